@@ -0,0 +1,147 @@
+use super::phases::{
+    self,
+    scope::{self, Occurrence},
+    types::{Loc, Located},
+};
+use super::source::{SourceLines, SourceLoader};
+use super::{Error, Options};
+use std::path::Path;
+
+/*
+    What an editor needs to know about an assembly (see `cli::lsp`): the errors raised when
+    assembling it, and the exact position of each appearance of each symbol (label or `!equ`
+    constant), so that one can jump between the definition of a symbol and its uses.
+
+    Symbols are found after macro expansion (so that labels private to a macro body are told
+    apart from those outside of it), and each is then placed at the text of its name in the
+    source. When that text is not at the location of the statement (as for a macro argument
+    substituted into the body of the macro) the macro call sites are tried in turn instead.
+    Symbols cannot be found in a source which fails to expand.
+*/
+
+pub struct Analysis {
+    error: Option<Error>,
+    symbols: Vec<Located<Occurrence>>,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// The byte offset of the first whole appearance of `name` in `line` at or after `from`.
+fn find_name(line: &str, from: usize, name: &str) -> Option<usize> {
+    let rest = line.get(from..)?;
+    rest.match_indices(name)
+        .map(|(idx, _)| from + idx)
+        .find(|&idx| {
+            let before = line[..idx].chars().next_back();
+            let after = line[idx + name.len()..].chars().next();
+            !before.into_iter().chain(after).any(is_name_char)
+        })
+}
+
+impl Analysis {
+    /// Assemble the file at `path` as directed by `opts`, recording any errors and the
+    /// appearances of each symbol.
+    pub fn of_path(path: &Path, loader: &dyn SourceLoader, opts: &Options) -> Self {
+        let error = super::assemble_path(path, loader, opts).err();
+
+        let expanded = phases::include_path(path, loader)
+            .and_then(|stmts| Ok(phases::expand(super::with_defines(opts, stmts))?.0));
+        let symbols = match expanded {
+            Ok(stmts) => Analysis::place_all(scope::occurrences(&stmts), loader),
+            Err(_) => Vec::new(),
+        };
+
+        Analysis { error, symbols }
+    }
+
+    fn place_all(
+        occs: Vec<Located<Occurrence>>,
+        loader: &dyn SourceLoader,
+    ) -> Vec<Located<Occurrence>> {
+        let mut sources = SourceLines::new(None, loader);
+        let mut placed: Vec<Located<Occurrence>> = Vec::new();
+        for occ in occs {
+            // NOTE: Each expansion of a macro gives the symbols in its body another appearance
+            // at the same place.
+            if let Some(occ) = Analysis::place(occ, &mut sources) {
+                if !placed.contains(&occ) {
+                    placed.push(occ);
+                }
+            }
+        }
+        placed
+    }
+
+    /// Move `occ` to the exact position of its name in the source, if it can be found.
+    fn place(occ: Located<Occurrence>, sources: &mut SourceLines) -> Option<Located<Occurrence>> {
+        let mut site = occ.loc();
+        while let Some(loc) = site {
+            let line = sources.line(loc).unwrap_or_default();
+            if let Some(idx) = find_name(line, loc.col().saturating_sub(1), &occ.value_ref().name) {
+                let exact = Loc::new(loc.line(), idx + 1).in_file(loc.file().cloned());
+                return Some(Located::with_loc(exact, occ.value()));
+            }
+            site = loc.call_site();
+        }
+        None
+    }
+
+    /// The errors raised while assembling, if any.
+    pub fn errors(&self) -> Vec<&Error> {
+        self.error.iter().flat_map(Error::errors).collect()
+    }
+
+    /// Every appearance of a symbol.
+    pub fn symbols(&self) -> &[Located<Occurrence>] {
+        &self.symbols
+    }
+
+    /// The global names of the symbols whose text covers the given (1-based) position, where
+    /// `file` is `None` for a root source which was not loaded from a file.
+    fn globals_at(&self, file: Option<&str>, line: usize, col: usize) -> Vec<&str> {
+        self.symbols
+            .iter()
+            .filter(|occ| {
+                let loc = occ.loc().unwrap();
+                let start = loc.col();
+                loc.file().map(|f| &**f) == file
+                    && loc.line() == line
+                    && start <= col
+                    && col <= start + occ.value_ref().name.len()
+            })
+            .map(|occ| occ.value_ref().global.as_str())
+            .collect()
+    }
+
+    /// The appearances of the symbols at the given position (as for `globals_at`), including
+    /// their definitions only if `include_defs`.
+    pub fn references_at(
+        &self,
+        file: Option<&str>,
+        line: usize,
+        col: usize,
+        include_defs: bool,
+    ) -> Vec<&Located<Occurrence>> {
+        let globals = self.globals_at(file, line, col);
+        self.symbols
+            .iter()
+            .filter(|occ| globals.contains(&occ.value_ref().global.as_str()))
+            .filter(|occ| include_defs || !occ.value_ref().is_def)
+            .collect()
+    }
+
+    /// The definitions of the symbols at the given position (as for `globals_at`).
+    pub fn definitions_at(
+        &self,
+        file: Option<&str>,
+        line: usize,
+        col: usize,
+    ) -> Vec<&Located<Occurrence>> {
+        self.references_at(file, line, col, true)
+            .into_iter()
+            .filter(|occ| occ.value_ref().is_def)
+            .collect()
+    }
+}
@@ -0,0 +1,84 @@
+use super::phases::expand::Warning;
+use super::phases::resolve::{self, Placement, Symbols};
+use super::phases::types::Located;
+use crate::spec::types::hw::{self, Byte, Word};
+
+/*
+    The result of assembling a program, for the tools which need more than the binary itself
+    (`kasm -l` and `kasm -g`, or a test which checks the memory at a label after a run):
+
+        -   The binary, as words or bytes.
+        -   The value of each symbol: every label (local labels under their full name, e.g.
+            `main.loop`), and every `!equ` constant which can be evaluated.
+        -   The placement of the output of each statement (see `resolve::Placement`), giving its
+            address and source location, and for an instruction the alias which was chosen to
+            encode it.
+        -   The warnings raised while assembling it.
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    words: Vec<Word>,
+    symbols: Symbols,
+    placements: Vec<Located<Placement>>,
+    warnings: Vec<Located<Warning>>,
+}
+
+impl Assembly {
+    pub fn new(placements: Vec<Located<Placement>>, symbols: Symbols) -> Self {
+        Assembly {
+            words: resolve::image(&placements),
+            symbols,
+            placements,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn with_warnings(self, warnings: Vec<Located<Warning>>) -> Self {
+        Assembly { warnings, ..self }
+    }
+
+    pub fn words(&self) -> &[Word] {
+        &self.words
+    }
+
+    pub fn into_words(self) -> Vec<Word> {
+        self.words
+    }
+
+    pub fn bytes(&self) -> Vec<Byte> {
+        hw::words_to_bytes(self.words.clone())
+    }
+
+    /// The value of the symbol `name`, if it is defined (and can be evaluated).
+    pub fn symbol(&self, name: &str) -> Option<Word> {
+        self.symbols.get(name).copied()
+    }
+
+    /// Each symbol with its value, sorted by name. This includes the names given to the labels
+    /// private to each macro expansion and to anonymous labels, which contain a `$`.
+    pub fn symbols(&self) -> impl Iterator<Item = (&str, Word)> {
+        self.symbols.iter().map(|(name, val)| (name.as_str(), *val))
+    }
+
+    /// The output of each statement (and each label), in source order.
+    pub fn placements(&self) -> &[Located<Placement>] {
+        &self.placements
+    }
+
+    pub fn into_placements(self) -> Vec<Located<Placement>> {
+        self.placements
+    }
+
+    /// The problems found in the source which did not stop it from being assembled.
+    pub fn warnings(&self) -> &[Located<Warning>] {
+        &self.warnings
+    }
+
+    /// The name of the alias chosen for each instruction, with the instruction's placement.
+    pub fn aliases(&self) -> impl Iterator<Item = (&Located<Placement>, &str)> {
+        self.placements
+            .iter()
+            .filter_map(|p| Some((p, p.value_ref().inst.as_ref()?.alias.as_str())))
+    }
+}
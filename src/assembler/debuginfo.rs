@@ -0,0 +1,181 @@
+use super::phases::resolve::Placement;
+use super::phases::types::Located;
+use crate::spec::types::hw::Word;
+use std::{convert::TryFrom, fmt::Display};
+
+/*
+    Debug info, written alongside an assembled binary by `kasm -g`, so that tools which only see
+    addresses (the debugger, the disassembler, a profiler) can relate them back to the source.
+
+    The format is line-oriented text, starting with the header line `KDBG 1`, followed by any
+    number of the records:
+
+        LABEL <name> <hex address>
+        LINE <hex address> <line> <col> [<file>]
+
+    There is a `LINE` record for the first word of each instruction, giving the location of the
+    statement which generated it. (The file is omitted if the root source was not loaded from a
+    file, and since it is the last field it may contain spaces.)
+*/
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    Malformed(usize, String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Malformed(line, msg) => {
+                write!(f, "Malformed debug info (line: {}): {}", line, msg)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourcePos {
+    pub file: Option<String>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Display for SourcePos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file)?;
+        }
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DebugInfo {
+    /// Each label with its address, sorted by address.
+    pub labels: Vec<(String, Word)>,
+    /// The source position of the first word of each instruction, sorted by address.
+    pub lines: Vec<(Word, SourcePos)>,
+}
+
+const HEADER: &str = "KDBG 1";
+
+impl DebugInfo {
+    pub fn new(placements: &[Located<Placement>]) -> Self {
+        let mut info = DebugInfo::default();
+
+        for p in placements {
+            let addr = Word::try_from(p.value_ref().addr).unwrap();
+
+            if let Some(label) = &p.value_ref().label {
+                info.labels.push((label.clone(), addr));
+            }
+
+            if let (Some(_), Some(loc)) = (&p.value_ref().inst, p.loc()) {
+                info.lines.push((
+                    addr,
+                    SourcePos {
+                        file: loc.file().map(|file| file.to_string()),
+                        line: loc.line(),
+                        col: loc.col(),
+                    },
+                ));
+            }
+        }
+
+        info.labels.sort_by_key(|(_, addr)| *addr);
+        info.lines.sort_by_key(|(addr, _)| *addr);
+        info
+    }
+
+    /// The label at or most closely preceding `addr`, together with the offset of `addr` from it.
+    pub fn symbolize(&self, addr: Word) -> Option<(&str, Word)> {
+        let idx = self.labels.partition_point(|(_, label)| *label <= addr);
+        let (name, label) = self.labels.get(idx.checked_sub(1)?)?;
+        Some((name, addr - label))
+    }
+
+    /// The source position of the instruction starting at `addr`, if any.
+    pub fn source_at(&self, addr: Word) -> Option<&SourcePos> {
+        let idx = self
+            .lines
+            .binary_search_by_key(&addr, |(addr, _)| *addr)
+            .ok()?;
+        Some(&self.lines[idx].1)
+    }
+
+    pub fn write(&self) -> String {
+        let mut lines = vec![HEADER.to_owned()];
+
+        for (name, addr) in self.labels.iter() {
+            lines.push(format!("LABEL {} {:X}", name, addr));
+        }
+
+        for (addr, pos) in self.lines.iter() {
+            let mut line = format!("LINE {:X} {} {}", addr, pos.line, pos.col);
+            if let Some(file) = &pos.file {
+                line.push(' ');
+                line.push_str(file);
+            }
+            lines.push(line);
+        }
+
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    pub fn read(src: &str) -> Result<DebugInfo, Error> {
+        let mut lines = src.lines().enumerate().map(|(idx, line)| (idx + 1, line));
+
+        match lines.next() {
+            Some((_, header)) if header.trim() == HEADER => (),
+            _ => return Err(Error::Malformed(1, format!("expected '{}'", HEADER))),
+        }
+
+        let mut info = DebugInfo::default();
+        for (line_no, line) in lines {
+            info.read_record(line)
+                .map_err(|msg| Error::Malformed(line_no, msg))?;
+        }
+
+        info.labels.sort_by_key(|(_, addr)| *addr);
+        info.lines.sort_by_key(|(addr, _)| *addr);
+        Ok(info)
+    }
+
+    fn read_record(&mut self, line: &str) -> Result<(), String> {
+        let mut fields = line.trim().splitn(5, ' ');
+
+        let addr = |field: Option<&str>| {
+            let field = field.ok_or("expected an address")?;
+            Word::from_str_radix(field, 16).map_err(|_| format!("bad address '{}'", field))
+        };
+
+        let num = |field: Option<&str>| {
+            let field = field.ok_or("expected a number")?;
+            field
+                .parse::<usize>()
+                .map_err(|_| format!("bad number '{}'", field))
+        };
+
+        match fields.next() {
+            None | Some("") => Ok(()),
+            Some("LABEL") => {
+                let name = fields.next().ok_or("expected a name")?.to_owned();
+                self.labels.push((name, addr(fields.next())?));
+                match fields.next() {
+                    None => Ok(()),
+                    Some(field) => Err(format!("unexpected trailing '{}'", field)),
+                }
+            }
+            Some("LINE") => {
+                let addr = addr(fields.next())?;
+                let line = num(fields.next())?;
+                let col = num(fields.next())?;
+                let file = fields.next().map(str::to_owned);
+                self.lines.push((addr, SourcePos { file, line, col }));
+                Ok(())
+            }
+            Some(record) => Err(format!("unknown record '{}'", record)),
+        }
+    }
+}
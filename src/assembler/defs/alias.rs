@@ -67,6 +67,8 @@ pub(crate) fn gen_alu(builder: &mut Builder) {
         Virtual::with_2(I_ADD2, Slot::with_wconst(0x0001), Slot::with_arg(0)),
     ));
 
+    // The comparison jumps which test only a single flag, for after a `CMP a b` (the others are
+    // instructions in their own right). NOTE: CARRY is set exactly when `a >= b` (unsigned).
     builder.register_alias(Alias::with_single(
         "JE",
         Virtual::with_1(I_JZ, Slot::with_arg(0)),
@@ -75,18 +77,13 @@ pub(crate) fn gen_alu(builder: &mut Builder) {
         "JNE",
         Virtual::with_1(I_JNZ, Slot::with_arg(0)),
     ));
-    // RUSTFIX test these three: (and commented ones at the bottom)
     builder.register_alias(Alias::with_single(
-        "JL",
-        Virtual::with_1(I_JNC, Slot::with_arg(0)),
-    ));
-    builder.register_alias(Alias::with_single(
-        "JNL",
+        "JAE",
         Virtual::with_1(I_JC, Slot::with_arg(0)),
     ));
     builder.register_alias(Alias::with_single(
-        "JGE",
-        Virtual::with_1(I_JC, Slot::with_arg(0)),
+        "JB",
+        Virtual::with_1(I_JNC, Slot::with_arg(0)),
     ));
 
     builder.register_alias(Alias::with_single(
@@ -98,21 +95,13 @@ pub(crate) fn gen_alu(builder: &mut Builder) {
         Virtual::with_1(I_JNZ.with_flag(ITFLAG_JMP_LD), Slot::with_arg(0)),
     ));
     builder.register_alias(Alias::with_single(
-        "LDJL",
-        Virtual::with_1(I_JNC.with_flag(ITFLAG_JMP_LD), Slot::with_arg(0)),
-    ));
-    builder.register_alias(Alias::with_single(
-        "LDJNL",
+        "LDJAE",
         Virtual::with_1(I_JC.with_flag(ITFLAG_JMP_LD), Slot::with_arg(0)),
     ));
     builder.register_alias(Alias::with_single(
-        "LDJGE",
-        Virtual::with_1(I_JC.with_flag(ITFLAG_JMP_LD), Slot::with_arg(0)),
+        "LDJB",
+        Virtual::with_1(I_JNC.with_flag(ITFLAG_JMP_LD), Slot::with_arg(0)),
     ));
-
-    // TODO check these, I think they are just wrong
-    // builder.register_alias(Alias::new("JLE", unbound_opcode(I_JS,  Slot::with_arg(0) )));
-    // builder.register_alias(Alias::new("JG" , unbound_opcode(I_JNS, Slot::with_arg(0) )));
 }
 
 pub(crate) fn register(builder: &mut Builder) {
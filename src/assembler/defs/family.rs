@@ -23,6 +23,7 @@ fn gen_mem(builder: &mut Builder) {
 fn gen_alu(builder: &mut Builder) {
     builder.register_family(Family::with("ADD", vec!["ADD2", "ADD3"]));
     builder.register_family(Family::with("ADDNF", vec!["ADD2NF", "ADD3NF"]));
+    builder.register_family(Family::with("ADC", vec!["ADC2", "ADC3"]));
 }
 
 pub(crate) fn register(builder: &mut Builder) {
@@ -0,0 +1,130 @@
+use super::phases::expand::Warning;
+use super::phases::types::{Error, Loc, Located};
+use super::source::{SourceLines, SourceLoader};
+
+/*
+    Renders assembly errors for a human, in the style of rustc:
+
+        error[Resolver]: Use of undefined label: 'lopo'
+         --> prog.ks:4:9
+          |
+        4 |     JMP lopo
+          |         ^
+          = note: in expansion of macro called at prog.ks:9:5
+
+    The position is `file:line:col` (the file is `<source>` if the root source was not loaded
+    from a file). If the line cannot be loaded, only the position is given. When there are
+    several errors, each is rendered in turn, followed by a count. Warnings are rendered in the
+    same way, as `warning[Expander]: ...`.
+*/
+
+/// Render `err` (and, if it is `Multiple`, each of the errors it consists of), loading the text
+/// of each included file with `loader`. `root` is the text of the root source, if it was not
+/// loaded from a file.
+pub fn render(err: &Error, root: Option<&str>, loader: &dyn SourceLoader) -> String {
+    let mut sources = SourceLines::new(root, loader);
+
+    let errs = err.errors();
+    let mut out = String::new();
+    for err in errs.iter() {
+        render_one(&mut out, err, &mut sources);
+        out.push('\n');
+    }
+
+    if errs.len() > 1 {
+        out.push_str(&format!(
+            "error: could not assemble due to {} previous errors\n",
+            errs.len()
+        ));
+    }
+    out
+}
+
+/// Render each of `warnings`, as `render` does for errors.
+pub fn render_warnings(
+    warnings: &[Located<Warning>],
+    root: Option<&str>,
+    loader: &dyn SourceLoader,
+) -> String {
+    let mut sources = SourceLines::new(root, loader);
+
+    let mut out = String::new();
+    for warning in warnings {
+        let message = warning.value_ref().to_string();
+        render_diagnostic(
+            &mut out,
+            "warning",
+            "Expander",
+            &message,
+            warning.loc(),
+            &mut sources,
+        );
+        out.push('\n');
+    }
+    out
+}
+
+fn position(loc: &Loc) -> String {
+    let file = loc.file().map(|file| file.to_string());
+    format!(
+        "{}:{}:{}",
+        file.as_deref().unwrap_or("<source>"),
+        loc.line(),
+        loc.col()
+    )
+}
+
+fn render_one(out: &mut String, err: &Error, sources: &mut SourceLines) {
+    render_diagnostic(
+        out,
+        "error",
+        err.phase().unwrap_or("Assembler"),
+        &err.message(),
+        err.loc(),
+        sources,
+    );
+}
+
+fn render_diagnostic(
+    out: &mut String,
+    severity: &str,
+    phase: &str,
+    message: &str,
+    loc: Option<&Loc>,
+    sources: &mut SourceLines,
+) {
+    out.push_str(&format!("{}[{}]: {}\n", severity, phase, message));
+
+    let loc = match loc {
+        None => return,
+        Some(loc) => loc,
+    };
+
+    let line_no = loc.line().to_string();
+    let gutter = " ".repeat(line_no.len());
+    out.push_str(&format!("{}--> {}\n", gutter, position(loc)));
+
+    if let Some(line) = sources.line(loc) {
+        // Keep any tabs before the column, so that the caret lines up however they are shown.
+        let prefix = line
+            .get(..loc.col().saturating_sub(1))
+            .unwrap_or(line)
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line_no, line));
+        out.push_str(&format!("{} | {}^\n", gutter, prefix));
+    }
+
+    let mut call_site = loc.call_site();
+    while let Some(site) = call_site {
+        out.push_str(&format!(
+            "{} = note: in expansion of macro called at {}\n",
+            gutter,
+            position(site)
+        ));
+        call_site = site.call_site();
+    }
+}
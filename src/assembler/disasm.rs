@@ -358,7 +358,13 @@ pub fn disassemble_alias<'a>(
         .collect::<Vec<_>>();
 
     loop {
-        let new_blob = it.next().ok_or(Error::UnexpectedEndOfStream)??;
+        let new_blob = match it.next() {
+            Some(Ok(blob)) => blob,
+            // The stream may end (or stop being code) just after an alias which is complete.
+            Some(Err(_)) | None if !matches.is_empty() => break,
+            Some(Err(err)) => return Err(err),
+            None => return Err(Error::UnexpectedEndOfStream),
+        };
 
         let any_remaining = resolve_partials_against_blob(
             &mut candidates,
@@ -438,16 +444,29 @@ pub fn disassemble_alias<'a>(
     Ok((disasm, blobs))
 }
 
-/// Try to find the "most general" `Family` which includes this `Alias`, which will obviously be imperfect.
-/// Since such a `Family` should always exist (a default one should be registered when the `Alias` was), we
-/// panic if there are no results.
-pub fn family_reverse_lookup(alias: &Alias) -> &Family {
-    // Find the family containing `alias` which has the largest number of members.
+/// Find the "most general" `Family` which may be written in place of `disasm`: the largest one whose
+/// variants include its alias, and which selects that alias given its arguments (so that assembling the
+/// family with them gives the same instructions). Every `Alias` is the only variant of the `Family` of the
+/// same name, so we panic if there are no results.
+pub fn family_reverse_lookup(disasm: &DisassembledAlias) -> &'static Family {
     // FIXME EVIL? encapsulation breaking (pass this data in, and all families and aliases have an attached marker to it?)
-    Lang::get()
-        .family_iter()
-        .filter(|fam| fam.variants.contains(&alias.name))
-        .max_by_key(|fam| fam.variants.len())
+    let lang = Lang::get();
+    lang.family_iter()
+        .filter(|fam| {
+            let mut selected = fam.variants.iter().filter(|variant| {
+                lang.lookup_alias(variant)
+                    .and_then(|alias| alias.instantiate(&disasm.args))
+                    .is_some()
+            });
+            selected.next() == Some(&disasm.alias.name) && selected.next().is_none()
+        })
+        // NOTE: Ties are broken by name, since the families are not kept in any particular order.
+        .max_by(|f1, f2| {
+            f1.variants
+                .len()
+                .cmp(&f2.variants.len())
+                .then_with(|| f2.name.cmp(&f1.name))
+        })
         .expect("Alias is not in any families!")
 }
 
@@ -483,15 +502,18 @@ impl<'a> Display for Context<'a> {
 }
 
 impl<'a> Context<'a> {
-    fn new(alias: DisassembledAlias<'a>, blobs: Vec<DisassembledBlob<'a>>) -> Self {
+    /// The context of the `idx`th instruction of `alias`, which was decoded from `blobs`.
+    pub fn new(alias: DisassembledAlias<'a>, blobs: Vec<DisassembledBlob<'a>>, idx: usize) -> Self {
         let mut ctx = Context {
-            family: &family_reverse_lookup(alias.alias).name,
+            family: &family_reverse_lookup(&alias).name,
             alias,
             inst_count: blobs.len(),
             blob_queue: VecDeque::from(blobs),
             current_blob: None,
         };
-        ctx.advance_blob_queue();
+        for _ in 0..=idx {
+            ctx.advance_blob_queue();
+        }
         ctx
     }
 
@@ -502,61 +524,67 @@ impl<'a> Context<'a> {
     fn advance_blob_queue(&mut self) {
         self.current_blob = self.blob_queue.pop_front();
     }
+
+    fn is_at(&self, blob: &DisassembledBlob) -> bool {
+        self.current_blob()
+            .map(|current| current.blob.to_words())
+            .as_ref()
+            == Some(&blob.blob.to_words())
+    }
 }
 
 /// Container for stateful version of `disassemble_alias`, which keeps track of when we are "inside"
 /// a multiple-instruction `Alias`, so that the computed disassembly doesn't change (and remains correct).
 /// The `DisassemblyContext` stores the current `Alias` and current instruction, so that when we are
 /// inside a multi-instruction alias we can show this in a pretty print.
+///
+/// Each instruction may be given the context in which it is `known` to lie, as found ahead of time by
+/// `recover::Program::context_at`, which is then preferred. Otherwise, we continue through the current
+/// `Alias` for as long as the instructions executed are those which it expects, and then (as after a jump
+/// into the middle of it) assume that a new `Alias` starts at each instruction.
 #[derive(Debug)]
 pub struct SteppingDisassembler<'a> {
     context: Context<'a>,
 }
 
 impl<'a> SteppingDisassembler<'a> {
-    pub fn new(it: impl Iterator<Item = Word>) -> Result<Self, Error> {
+    pub fn new(
+        known: Option<Context<'a>>,
+        mut it: impl Iterator<Item = Word>,
+    ) -> Result<Self, Error> {
+        let actual_blob = disassemble_blob(&mut it)?;
         Ok(Self {
-            context: Self::compute_context(None, it)?,
+            context: Self::compute_context(known, actual_blob, it)?,
         })
     }
 
     fn compute_context(
-        first_blob: Option<DisassembledBlob<'a>>,
+        known: Option<Context<'a>>,
+        actual_blob: DisassembledBlob<'a>,
         it: impl Iterator<Item = Word>,
     ) -> Result<Context<'a>, Error> {
-        // Recompute the current disassembly context, assuming that the first word from `it`
-        // is the beginning of an `Alias`.
-        let blobs_it = first_blob
-            .map(Ok)
-            .into_iter()
-            .chain(disassemble_blob_iter(it));
+        if let Some(known) = known.filter(|known| known.is_at(&actual_blob)) {
+            return Ok(known);
+        }
+
+        // Recompute the current disassembly context, assuming that `actual_blob` (followed by
+        // the rest of `it`) is the beginning of an `Alias`.
+        let blobs_it = std::iter::once(Ok(actual_blob)).chain(disassemble_blob_iter(it));
 
         let (alias, blobs) = disassemble_alias(blobs_it)?;
-        Ok(Context::new(alias, blobs))
+        Ok(Context::new(alias, blobs, 0))
     }
 
-    pub fn step(&mut self, mut it: impl Iterator<Item = Word>) -> Result<(), Error> {
+    pub fn step(
+        &mut self,
+        known: Option<Context<'a>>,
+        mut it: impl Iterator<Item = Word>,
+    ) -> Result<(), Error> {
         let actual_blob = disassemble_blob(&mut it)?;
 
         self.context.advance_blob_queue();
-        if self.context.current_blob().is_none() {
-            self.context = SteppingDisassembler::compute_context(Some(actual_blob.clone()), it)?;
-        }
-
-        // Check that the current blob matches the expected on from the top of the `blob_queue`.
-        // If not, if we haven't already, recompute everything (calling `compute_context`), but
-        // don't forget that we've already read a `ResolvedBlob` so we'll need to pass that to
-        // some version of `recompute_context` and consequently `disassemble_alias`.
-        let front_blob = self.context.current_blob().unwrap();
-        if actual_blob.blob.to_words() != front_blob.blob.to_words() {
-            // In principle (e.g. if the code itself was overwritten, or we jumped) this could happen and we would need
-            // to recalculate, but for now let's just panic to find likely bugs.
-            panic!(
-                "{}\n\tactual_blob was: {:#}\n\tfront_blob was: {:#}",
-                "The blob cache was invalidated, did we jump/or did the code itself change? (otherwise, this is a bug):",
-                actual_blob,
-                front_blob,
-            );
+        if known.is_some() || !self.context.is_at(&actual_blob) {
+            self.context = SteppingDisassembler::compute_context(known, actual_blob, it)?;
         }
 
         Ok(())
@@ -591,10 +619,33 @@ mod tests {
         .unwrap();
     }
 
-    // RUSTFIX How do we test `SteppingDisassembler` in particular? Some small inline binaries would actually be possible.
     #[test]
-    fn it_works() {
-        // RUSTFIX implement
-        // todo!();
+    fn collapses_into_families() {
+        let data = assembler::assemble("ADD %ra %rb %rc").unwrap();
+        let (alias, _) = disassemble_alias(disassemble_blob_iter(data.into_iter())).unwrap();
+
+        assert_eq!(alias.alias.name, "add3");
+        assert_eq!(family_reverse_lookup(&alias).name, "add");
+    }
+
+    #[test]
+    fn steps_into_the_middle_of_an_alias() {
+        let data = assembler::assemble("PUSHA\nHLT").unwrap();
+        let program = assembler::recover::Program::new(&data);
+
+        // The second instruction of `PUSHA`, as found ahead of time.
+        let stepper = SteppingDisassembler::new(program.context_at(2), data[1..].iter().copied());
+        assert!(stepper
+            .unwrap()
+            .context()
+            .to_string()
+            .starts_with("<PUSHA:2/3>"));
+
+        // Jumping from the first instruction of `PUSHA` straight to the `HLT`.
+        let mut stepper = SteppingDisassembler::new(None, data.iter().copied()).unwrap();
+        assert!(stepper.context().to_string().starts_with("<PUSHA:1/3>"));
+        stepper.step(None, data[3..].iter().copied()).unwrap();
+        assert_eq!(stepper.context().to_string(), "HLT");
+        assert!(program.context_at(6).is_some());
     }
 }
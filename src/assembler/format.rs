@@ -0,0 +1,162 @@
+use super::model;
+use super::phases::tokenize::{self, RawLine, Token};
+use super::Error;
+use itertools::Itertools;
+use std::iter;
+
+/*
+    The source formatter (`kasm fmt`), which rewrites a source in a uniform layout while keeping
+    each of its lines (and comments) in place:
+
+        -   Labels and directives (`!macro`, `!equ`, ...) start in column 0, and instructions
+            (and macro calls) are indented. An instruction following a label on the same line
+            starts in the same column, if the label leaves room for it.
+        -   Instruction names are written in canonical case (see `model::sanitize_name`), and
+            the operands of the instructions in each run of lines (separated by blank lines)
+            start in the same column.
+        -   The trailing comments in each run of lines start in the same column, just after the
+            longest line carrying one. A line consisting only of a comment is indented if and
+            only if it was before.
+        -   Otherwise each token is written as it was, separated from the next by a space.
+
+    Formatting a formatted source leaves it unchanged.
+*/
+
+const INDENT: usize = 4;
+
+enum Code<'a> {
+    Nothing,
+    /// A directive, or anything else which is not an instruction, given token by token.
+    Directive(Vec<&'a str>),
+    /// The (canonical) name and the operands of an instruction or macro call.
+    Inst(String, Vec<&'a str>),
+}
+
+struct Line<'a> {
+    labels: Vec<&'a str>,
+    code: Code<'a>,
+    comment: Option<&'a str>,
+    indented: bool,
+}
+
+impl<'a> Line<'a> {
+    fn new(raw: RawLine<'a>, text: &str) -> Self {
+        let mut tokens = raw.tokens.into_iter().peekable();
+        let labels = iter::from_fn(|| tokens.next_if(|(tk, _)| matches!(tk, Token::LabelDef(_))))
+            .map(|(_, text)| text)
+            .collect();
+
+        let code = match tokens.next() {
+            None => Code::Nothing,
+            Some((Token::Name(name), _)) => Code::Inst(
+                model::sanitize_name(&name),
+                tokens.map(|(_, text)| text).collect(),
+            ),
+            Some((_, first)) => Code::Directive(
+                iter::once(first)
+                    .chain(tokens.map(|(_, text)| text))
+                    .collect(),
+            ),
+        };
+
+        Line {
+            labels,
+            code,
+            comment: raw.comment,
+            indented: text.starts_with(char::is_whitespace),
+        }
+    }
+
+    fn is_blank(&self) -> bool {
+        self.labels.is_empty() && matches!(self.code, Code::Nothing) && self.comment.is_none()
+    }
+
+    fn mnemonic_width(&self) -> Option<usize> {
+        match &self.code {
+            Code::Inst(name, operands) if !operands.is_empty() => Some(name.chars().count()),
+            _ => None,
+        }
+    }
+
+    /// The line without its trailing comment, with the operands of an instruction starting
+    /// `mnemonic_width` characters after its name (if it has room).
+    fn render_code(&self, mnemonic_width: usize) -> String {
+        let mut out = self.labels.join(" ");
+        match &self.code {
+            Code::Nothing => (),
+            Code::Directive(tokens) => {
+                if !out.is_empty() {
+                    out.push(' ');
+                }
+                out.push_str(&tokens.join(" "));
+            }
+            Code::Inst(name, operands) => {
+                let col = match out.chars().count() {
+                    0 => INDENT,
+                    len => (len + 1).max(INDENT),
+                };
+                out = format!("{:<width$}{}", out, name, width = col);
+                if !operands.is_empty() {
+                    out = format!(
+                        "{:<width$} {}",
+                        out,
+                        operands.join(" "),
+                        width = col + mnemonic_width
+                    );
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Format a run of lines, containing no blank lines.
+fn format_run(out: &mut Vec<String>, run: &[Line]) {
+    let mnemonic_width = run.iter().filter_map(Line::mnemonic_width).max();
+    let codes = run
+        .iter()
+        .map(|line| line.render_code(mnemonic_width.unwrap_or(0)))
+        .collect::<Vec<_>>();
+
+    let comment_col = run
+        .iter()
+        .zip(codes.iter())
+        .filter(|(line, code)| line.comment.is_some() && !code.is_empty())
+        .map(|(_, code)| code.chars().count() + 1)
+        .max();
+
+    for (line, code) in run.iter().zip(codes) {
+        out.push(match line.comment {
+            None => code,
+            Some(comment) if !code.is_empty() => {
+                format!("{:<width$}{}", code, comment, width = comment_col.unwrap())
+            }
+            Some(comment) if line.indented => format!("{:<width$}{}", "", comment, width = INDENT),
+            Some(comment) => comment.to_owned(),
+        });
+    }
+}
+
+/// Format `source` as described above, which fails only if it cannot be tokenized.
+pub fn format(source: &str) -> Result<String, Error> {
+    let lines = tokenize::tokenize_lines(source)?
+        .into_iter()
+        .zip(source.lines())
+        .map(|(raw, text)| Line::new(raw, text))
+        .collect::<Vec<_>>();
+
+    let mut out = Vec::new();
+    for (blank, run) in &lines.into_iter().group_by(Line::is_blank) {
+        let run = run.collect::<Vec<_>>();
+        match blank {
+            true => out.extend(run.iter().map(|_| String::new())),
+            false => format_run(&mut out, &run),
+        }
+    }
+
+    while out.last().map(String::as_str) == Some("") {
+        out.pop();
+    }
+
+    Ok(out.into_iter().map(|line| line + "\n").collect())
+}
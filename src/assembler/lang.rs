@@ -6,10 +6,39 @@ use crate::common;
 use crate::spec::{types::schema::ArgKind, ucode::UCode};
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::fmt::Display;
 
 static STORAGE: Lazy<Lang> = Lazy::new(Lang::new);
 
+/// A problem with an alias or family which is being added to a `Lang`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    NameTaken(String),
+    UnknownAlias(String),
+    VariantsCollide(String, String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::NameTaken(name) => {
+                write!(f, "'{}' is already the name of an instruction", name)
+            }
+            Error::UnknownAlias(alias) => write!(f, "Unknown alias '{}'", alias),
+            Error::VariantsCollide(a, b) => write!(
+                f,
+                "Family variants '{}' and '{}' accept the same arguments",
+                a, b
+            ),
+        }
+    }
+}
+
+/// The aliases and families of the assembly language. The built-in language (`Lang::get`) is
+/// defined by `defs::alias` and `defs::family`, and a single assembly may extend it with its
+/// own (by `!alias` and `!family`), which are layered over the built-in ones.
 pub struct Lang {
+    base: Option<&'static Lang>,
     aliases: HashMap<String, Alias>,
     families: HashMap<String, Family>,
 }
@@ -26,20 +55,53 @@ impl Lang {
         Lazy::force(&STORAGE)
     }
 
+    /// Begin building a language which extends the built-in one.
+    pub fn extend() -> Builder {
+        Builder::with_lang(Lang {
+            base: Some(Lang::get()),
+            aliases: HashMap::new(),
+            families: HashMap::new(),
+        })
+    }
+
     pub fn lookup_alias(&self, name: &str) -> Option<&Alias> {
-        self.aliases.get(&model::sanitize_name(name))
+        self.aliases
+            .get(&model::sanitize_name(name))
+            .or_else(|| self.base?.lookup_alias(name))
     }
 
     pub fn alias_iter(&self) -> impl Iterator<Item = &Alias> {
-        self.aliases.values()
+        self.aliases
+            .values()
+            .chain(self.base.into_iter().flat_map(|base| base.aliases.values()))
     }
 
     pub fn lookup_family(&self, name: &str) -> Option<&Family> {
-        self.families.get(&model::sanitize_name(name))
+        self.families
+            .get(&model::sanitize_name(name))
+            .or_else(|| self.base?.lookup_family(name))
     }
 
     pub fn family_iter(&self) -> impl Iterator<Item = &Family> {
-        self.families.values()
+        self.families.values().chain(
+            self.base
+                .into_iter()
+                .flat_map(|base| base.families.values()),
+        )
+    }
+
+    /// The name and argument kinds of each of the variants of `family`.
+    pub fn candidates(&self, family: &Family) -> Vec<(String, Vec<ArgKind>)> {
+        family
+            .variants
+            .iter()
+            .map(|alias| {
+                (
+                    alias.clone(),
+                    self.lookup_alias(alias).unwrap().infer_type(),
+                )
+            })
+            .collect()
     }
 }
 
@@ -48,13 +110,16 @@ pub struct Builder {
 }
 
 impl Builder {
+    fn with_lang(lang: Lang) -> Self {
+        Builder { lang }
+    }
+
     fn new() -> Self {
-        let mut builder = Builder {
-            lang: Lang {
-                aliases: HashMap::new(),
-                families: HashMap::new(),
-            },
-        };
+        let mut builder = Builder::with_lang(Lang {
+            base: None,
+            aliases: HashMap::new(),
+            families: HashMap::new(),
+        });
 
         // RUSTFIX EVIL? breaking out of encapsulation
         for idef in UCode::get().inst_def_iter() {
@@ -64,15 +129,25 @@ impl Builder {
         builder
     }
 
-    fn build(self) -> Lang {
+    pub fn build(self) -> Lang {
         self.lang
     }
 
     pub(super) fn register_alias(&mut self, a: Alias) {
-        let name = a.name.clone();
-        assert!(self.lang.aliases.insert(a.name.clone(), a).is_none());
+        self.try_register_alias(a)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Add the alias `a`, together with a family of the same name consisting of just `a`.
+    pub fn try_register_alias(&mut self, a: Alias) -> Result<(), Error> {
+        // Every alias has a family of the same name, so checking the family names suffices.
+        if self.lang.lookup_family(&a.name).is_some() {
+            return Err(Error::NameTaken(a.name));
+        }
 
-        self.register_family(Family::new(name.clone(), vec![name]))
+        let name = a.name.clone();
+        self.lang.aliases.insert(a.name.clone(), a);
+        self.try_register_family(Family::new(name.clone(), vec![name]))
     }
 
     fn arg_kind_lists_collide(us: &[ArgKind], vs: &[ArgKind]) -> bool {
@@ -80,22 +155,35 @@ impl Builder {
     }
 
     pub(super) fn register_family(&mut self, f: Family) {
-        let arglists: Vec<_> = f
+        let name = f.name.clone();
+        self.try_register_family(f)
+            .unwrap_or_else(|err| panic!("Bad family '{}': {}", name, err))
+    }
+
+    /// Add the family `f`, checking that its variants exist and that no two of them could
+    /// accept the same arguments.
+    pub fn try_register_family(&mut self, f: Family) -> Result<(), Error> {
+        if self.lang.lookup_family(&f.name).is_some() {
+            return Err(Error::NameTaken(f.name));
+        }
+
+        let arglists = f
             .variants
             .iter()
-            .map(|v| {
-                self.lang
-                    .lookup_alias(v)
-                    .unwrap_or_else(|| panic!("Unknown alias: \"{}\"", v))
+            .map(|v| match self.lang.lookup_alias(v) {
+                Some(alias) => Ok((v, alias.infer_type())),
+                None => Err(Error::UnknownAlias(v.clone())),
             })
-            .map(Alias::infer_type)
-            .collect();
+            .collect::<Result<Vec<_>, _>>()?;
 
-        assert!(common::slice_pairwise_ordered(&arglists)
-            .all(|(a, b)| !Builder::arg_kind_lists_collide(a, b)));
+        if let Some(((a, _), (b, _))) = common::slice_pairwise_ordered(&arglists)
+            .find(|((_, a), (_, b))| Builder::arg_kind_lists_collide(a, b))
+        {
+            return Err(Error::VariantsCollide(a.to_string(), b.to_string()));
+        }
 
-        // RUSTFIX use expect_none once it stabilises
-        assert!(self.lang.families.insert(f.name.clone(), f).is_none());
+        self.lang.families.insert(f.name.clone(), f);
+        Ok(())
     }
 }
 
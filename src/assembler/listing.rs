@@ -0,0 +1,138 @@
+use super::disasm;
+use super::phases::resolve::Placement;
+use super::phases::types::{Loc, Located};
+use super::source::{SourceLines, SourceLoader};
+use crate::spec::types::hw::Word;
+
+/// A listing of an assembled program: for each source line which produced output (or defined a
+/// label), its address, the words it emitted and its text. For instruction family calls, the
+/// `Alias` variant chosen and the `InstDef`s it expanded to are shown beneath.
+pub struct Listing<'a> {
+    /// The text of the root source, if it was not loaded from a file.
+    root: Option<&'a str>,
+    placements: &'a [Located<Placement>],
+}
+
+/// The output of all of the statements on a single source line.
+struct Entry<'a> {
+    loc: Option<&'a Loc>,
+    addr: usize,
+    words: Vec<Word>,
+    expansions: Vec<String>,
+}
+
+const WORDS_PER_LINE: usize = 4;
+const WORDS_WIDTH: usize = 5 * WORDS_PER_LINE - 1;
+
+impl<'a> Entry<'a> {
+    fn same_line(loc: Option<&Loc>, other: Option<&Loc>) -> bool {
+        match (loc, other) {
+            (Some(loc), Some(other)) => {
+                loc.file() == other.file()
+                    && loc.line() == other.line()
+                    && loc.call_site() == other.call_site()
+            }
+            _ => false,
+        }
+    }
+
+    fn expansion(p: &Placement) -> Option<String> {
+        let inst = p.inst.as_ref()?;
+        let idefs = disasm::disassemble_blob_iter(p.words.iter().copied())
+            .map(|blob| match blob {
+                Ok(blob) => blob.to_string(),
+                Err(err) => err.to_string(),
+            })
+            .collect::<Vec<_>>();
+        Some(format!("{}: {}", inst.alias, idefs.join("; ")))
+    }
+}
+
+impl<'a> Listing<'a> {
+    pub fn new(root: Option<&'a str>, placements: &'a [Located<Placement>]) -> Self {
+        Listing { root, placements }
+    }
+
+    fn entries(&self) -> Vec<Entry<'_>> {
+        let mut entries: Vec<Entry> = Vec::new();
+        for p in self.placements.iter() {
+            let expansion = Entry::expansion(p.value_ref());
+            match entries.last_mut() {
+                Some(entry) if Entry::same_line(entry.loc, p.loc()) => {
+                    entry.words.extend(p.value_ref().words.iter());
+                    entry.expansions.extend(expansion);
+                }
+                _ => entries.push(Entry {
+                    loc: p.loc(),
+                    addr: p.value_ref().addr,
+                    words: p.value_ref().words.clone(),
+                    expansions: expansion.into_iter().collect(),
+                }),
+            }
+        }
+        entries
+    }
+
+    /// Render the listing, loading the text of each included file with `loader`.
+    pub fn render(&self, loader: &dyn SourceLoader) -> String {
+        let mut sources = SourceLines::new(self.root, loader);
+        let mut text = |loc: &Loc| {
+            sources
+                .line(loc)
+                .map(|line| line.trim().to_owned())
+                .unwrap_or_default()
+        };
+
+        let mut out = String::new();
+        for entry in self.entries() {
+            let (position, source) = match entry.loc {
+                None => (String::new(), String::new()),
+                Some(loc) => {
+                    let position = match loc.file() {
+                        None => loc.line().to_string(),
+                        Some(file) => format!("{}:{}", file, loc.line()),
+                    };
+                    let mut source = text(loc);
+                    if let Some(call_site) = loc.call_site() {
+                        source.push_str(&format!("  (expanded from {})", call_site));
+                    }
+                    (position, source)
+                }
+            };
+
+            let mut chunks = entry.words.chunks(WORDS_PER_LINE);
+            let words = |chunk: Option<&[Word]>| {
+                chunk
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|w| format!("{:04X}", w))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+
+            out.push_str(&format!(
+                "{:04X}  {:<width$}  {:>12}  {}\n",
+                entry.addr,
+                words(chunks.next()),
+                position,
+                source,
+                width = WORDS_WIDTH
+            ));
+            for (idx, chunk) in chunks.enumerate() {
+                let addr = entry.addr + 2 * WORDS_PER_LINE * (idx + 1);
+                out.push_str(&format!("{:04X}  {}\n", addr, words(Some(chunk))));
+            }
+            for expansion in entry.expansions {
+                out.push_str(&format!(
+                    "{:6}{:width$}  {:12}    ; {}\n",
+                    "",
+                    "",
+                    "",
+                    expansion,
+                    width = WORDS_WIDTH
+                ));
+            }
+        }
+        out
+    }
+}
@@ -1,27 +1,179 @@
+pub mod analysis;
+pub mod assembly;
+pub mod debuginfo;
+pub mod diagnostic;
 pub mod disasm;
+pub mod format;
 pub mod lang;
+pub mod listing;
 pub mod model;
+pub mod object;
 pub mod phases;
+pub mod recover;
+pub mod source;
 
 mod defs;
 
+pub use assembly::Assembly;
 pub use phases::types::Error;
 
-use crate::spec::types::hw::{self, Byte, Word};
+use crate::spec::types::hw::{Byte, Word};
+use object::Object;
+use phases::expand::Warning;
+use phases::expr::Expr;
+use phases::types::{BinaryElement, Located, Statement};
+use source::{FsLoader, SourceLoader};
+use std::path::Path;
+use std::str::FromStr;
 
 // RUSTFIX ERROR OVERHAUL:    Exception overhaul, just use `format!()` in-place to generate the messages,
 //                            since we are just doing `to_owned` spam everywhere now and the slices were
 //                            limiting in some places when I was originally writing the messages.
 
-pub fn assemble(source: &str) -> Result<Vec<Word>, Error> {
-    let tokens = phases::tokenize(source)?;
-    let statements = phases::parse(tokens)?;
+/// A constant defined from outside of the source (as by `kasm -D NAME=VALUE`), which behaves as
+/// if it were an `!equ` at the top of the root file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Define {
+    pub name: String,
+    pub value: Expr,
+}
+
+impl FromStr for Define {
+    type Err = String;
+
+    /// Parse `NAME=VALUE`, or just `NAME` (which is then defined to be `1`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.find('=') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => (s, "1"),
+        };
+
+        if name.is_empty() {
+            return Err(format!("no name given in definition '{}'", s));
+        }
+
+        let value = value.strip_prefix('$').unwrap_or(value);
+        let value = Expr::parse(value).map_err(|msg| format!("bad value '{}': {}", value, msg))?;
+        Ok(Define {
+            name: name.to_owned(),
+            value,
+        })
+    }
+}
+
+/// How to assemble a file.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Constants defined from outside of the source.
+    pub defines: Vec<Define>,
+    /// Whether to run the optimiser (`kasm -O`) over the generated code, see `phases::optimize`.
+    pub optimize: bool,
+}
+
+fn with_defines(opts: &Options, statements: Vec<Located<Statement>>) -> Vec<Located<Statement>> {
+    opts.defines
+        .iter()
+        .map(|def| Located::from(Statement::Equ(def.name.clone(), def.value.clone())))
+        .chain(statements)
+        .collect()
+}
+
+/// The generated code, together with the warnings raised along the way.
+type Generation = (Vec<Located<BinaryElement>>, Vec<Located<Warning>>);
+
+fn generate_statements(
+    statements: Vec<Located<Statement>>,
+    opts: &Options,
+) -> Result<Generation, Error> {
+    let (statements, warnings) = phases::expand(statements)?;
+    let statements = phases::scope(statements)?;
     let elems = phases::generate(statements)?;
-    let bins = phases::resolve(elems)?;
 
-    Ok(bins)
+    let elems = match opts.optimize {
+        true => phases::optimize(elems),
+        false => elems,
+    };
+    Ok((elems, warnings))
+}
+
+fn assemble_statements(
+    statements: Vec<Located<Statement>>,
+    opts: &Options,
+) -> Result<Assembly, Error> {
+    let (elems, warnings) = generate_statements(statements, opts)?;
+    let assembly = phases::resolve::resolve_assembly(elems)?;
+
+    Ok(assembly.with_warnings(warnings))
+}
+
+fn assemble_statements_relocatable(
+    statements: Vec<Located<Statement>>,
+    opts: &Options,
+) -> Result<(Object, Vec<Located<Warning>>), Error> {
+    let (elems, warnings) = generate_statements(statements, opts)?;
+    let obj = phases::resolve::resolve_relocatable(elems)?;
+
+    Ok((obj, warnings))
+}
+
+/// Assemble `source`, loading any `!include`d files (relative to the current directory)
+/// from the real filesystem.
+pub fn assemble(source: &str) -> Result<Vec<Word>, Error> {
+    assemble_with(source, &FsLoader)
+}
+
+pub fn assemble_with(source: &str, loader: &dyn SourceLoader) -> Result<Vec<Word>, Error> {
+    assemble_program_with(source, loader).map(Assembly::into_words)
+}
+
+/// Assemble the file at `path` as directed by `opts`.
+pub fn assemble_path(
+    path: &Path,
+    loader: &dyn SourceLoader,
+    opts: &Options,
+) -> Result<Vec<Word>, Error> {
+    assemble_program_path(path, loader, opts).map(Assembly::into_words)
+}
+
+/// Assemble `source` as `assemble_with` does, retaining everything known about the result
+/// (see `Assembly`).
+pub fn assemble_program_with(source: &str, loader: &dyn SourceLoader) -> Result<Assembly, Error> {
+    assemble_statements(phases::include_str(source, loader)?, &Options::default())
+}
+
+pub fn assemble_program_path(
+    path: &Path,
+    loader: &dyn SourceLoader,
+    opts: &Options,
+) -> Result<Assembly, Error> {
+    assemble_statements(
+        with_defines(opts, phases::include_path(path, loader)?),
+        opts,
+    )
+}
+
+/// Assemble `source` into a relocatable object, to be linked with `object::link`, returning it
+/// with the warnings raised along the way (which an `Object` has no room to keep).
+pub fn assemble_object_with(
+    source: &str,
+    loader: &dyn SourceLoader,
+) -> Result<(Object, Vec<Located<Warning>>), Error> {
+    assemble_statements_relocatable(phases::include_str(source, loader)?, &Options::default())
+}
+
+/// Assemble the file at `path` into a relocatable object as directed by `opts`, as
+/// `assemble_object_with` does.
+pub fn assemble_object_path(
+    path: &Path,
+    loader: &dyn SourceLoader,
+    opts: &Options,
+) -> Result<(Object, Vec<Located<Warning>>), Error> {
+    assemble_statements_relocatable(
+        with_defines(opts, phases::include_path(path, loader)?),
+        opts,
+    )
 }
 
 pub fn assemble_bytes(prog: &str) -> Result<Vec<Byte>, Error> {
-    Ok(hw::words_to_bytes(assemble(prog)?))
+    Ok(assemble_program_with(prog, &FsLoader)?.bytes())
 }
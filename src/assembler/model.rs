@@ -10,6 +10,7 @@ use derive_more::Constructor;
 use enum_map::EnumMap;
 use std::cmp;
 use std::collections::hash_map::Entry;
+use std::convert::TryFrom;
 use std::{collections::HashMap, fmt::Display};
 use strum::IntoEnumIterator;
 
@@ -65,8 +66,8 @@ use strum::IntoEnumIterator;
 
         More on `Blob`s: when assembling, we'd like a way to resolve a list of actual arguments
         against a `Family` (and consequently, the underlying `Alias`es and and `Virtual`s). However,
-        certain constants (coming from labels, but we implement a generic interface which could
-        support e.g. relocation/linking) must be resolved late and depends on the number of words our
+        certain constants (coming from labels, but we implement a generic interface which also
+        supports relocation/linking, see `assembler::object`) must be resolved late and depends on the number of words our
         big list of generated instructions take up. (e.g., instructions with constants cost double
         the number of words, etc. so this phase must be completred before the label positions
         can actually be determined---if we'd like labels to be able to be referenced before they
@@ -86,6 +87,17 @@ pub struct RegRef {
 pub enum Const {
     Byte(Byte, Half),
     Word(Word),
+    /// A word constant whose value fits in a byte, but which was written with more digits than
+    /// a byte needs (like `$0x0005`), and so is never coerced into a byte slot.
+    WideWord(Word),
+}
+
+/// Why a word constant could not be coerced into a byte slot (see `Const::coerce_to_byte`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteCoercionError {
+    DoesNotFit(Word),
+    WrittenAsWord(Word),
+    Unresolved,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -170,8 +182,26 @@ impl Display for RegRef {
 impl Display for Const {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Const::Word(w) => write!(f, "${:#06X}", w),
-            Const::Byte(b, half) => write!(f, "{}$0x{}", half, b),
+            Const::Word(w) | Const::WideWord(w) => write!(f, "${:#06X}", w),
+            Const::Byte(b, half) => write!(f, "{}${:#04X}", half, b),
+        }
+    }
+}
+
+impl Display for ByteCoercionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ByteCoercionError::DoesNotFit(val) => {
+                write!(f, "its value {:#06X} does not fit in a byte", val)
+            }
+            ByteCoercionError::WrittenAsWord(val) => write!(
+                f,
+                "it is written with more digits than a byte needs (write l${:#04X} or h${:#04X} to pass it as a byte)",
+                val, val
+            ),
+            ByteCoercionError::Unresolved => {
+                write!(f, "its value is not known until labels are placed")
+            }
         }
     }
 }
@@ -229,22 +259,46 @@ impl ConstPolicy {
             _ => true,
         }
     }
+
+    /// The policy accepting exactly the arguments which both `self` and `other` accept, if
+    /// there are any.
+    pub fn intersect(self, other: ConstPolicy) -> Option<ConstPolicy> {
+        // That is, the lesser of the two (since the policies are ordered by inclusion).
+        match self.partial_cmp(&other)? {
+            cmp::Ordering::Greater => Some(other),
+            _ => Some(self),
+        }
+    }
 }
 
 impl Const {
     pub fn to_width(self) -> Width {
         match self {
-            Const::Word(_) => Width::Word,
+            Const::Word(_) | Const::WideWord(_) => Width::Word,
             Const::Byte(_, half) => Width::Byte(half),
         }
     }
 
     pub fn encode(self) -> Word {
         match self {
-            Const::Word(val) => val,
+            Const::Word(val) | Const::WideWord(val) => val,
             Const::Byte(val, half) => (val as Word) << half.shift(),
         }
     }
+
+    /// The rule `ConstWordCoercedToByte`: a word constant may fill a byte slot (becoming a byte
+    /// constant in the given `half`) if its value fits in a byte, and it was not written with
+    /// more digits than a byte needs. Byte constants are never coerced, so an explicit `l$` or
+    /// `h$` always takes precedence.
+    pub fn coerce_to_byte(self, half: Half) -> Result<Const, ByteCoercionError> {
+        match self {
+            Const::Word(val) => Byte::try_from(val)
+                .map(|b| Const::Byte(b, half))
+                .map_err(|_| ByteCoercionError::DoesNotFit(val)),
+            Const::WideWord(val) => Err(ByteCoercionError::WrittenAsWord(val)),
+            Const::Byte(..) => Ok(self),
+        }
+    }
 }
 
 impl<Tag> ConstBinding<Tag> {
@@ -256,6 +310,15 @@ impl<Tag> ConstBinding<Tag> {
     }
 }
 
+impl<Tag> ConstBinding<Tag> {
+    pub fn coerce_to_byte(self, half: Half) -> Result<Self, ByteCoercionError> {
+        match self {
+            ConstBinding::Resolved(c) => Ok(ConstBinding::Resolved(c.coerce_to_byte(half)?)),
+            ConstBinding::Unresolved(_) => Err(ByteCoercionError::Unresolved),
+        }
+    }
+}
+
 impl ConstBinding<NoTag> {
     pub fn coerce<T>(self) -> ConstBinding<T> {
         match self {
@@ -277,6 +340,17 @@ impl ArgKind {
     pub fn matches<Tag>(self, arg: &Arg<Tag>) -> bool {
         self.policy.matches(arg) && self.width == arg.to_width()
     }
+
+    /// If this is a byte slot and `arg` a word constant, try to make `arg` match by coercing it
+    /// into a byte constant (see `Const::coerce_to_byte`). Any other `arg` is returned unchanged.
+    pub fn coerce<Tag>(self, arg: Arg<Tag>) -> Result<Arg<Tag>, ByteCoercionError> {
+        match (self.width, arg) {
+            (Width::Byte(half), Arg::Const(cb)) if cb.to_width() == Width::Word => {
+                Ok(Arg::Const(cb.coerce_to_byte(half)?))
+            }
+            (_, arg) => Ok(arg),
+        }
+    }
 }
 
 impl<Tag> Arg<Tag> {
@@ -343,19 +417,26 @@ impl Family {
 
 impl Alias {
     pub fn new(from_idef: bool, name: String, vinsts: Vec<Virtual>) -> Self {
+        Self::try_new(from_idef, name, vinsts)
+            .unwrap_or_else(|idx| panic!("argument {} of alias has no consistent kind", idx))
+    }
+
+    /// Like `new`, but if the type of the alias cannot be inferred, the index of the first
+    /// argument whose kind could not be determined.
+    pub fn try_new(from_idef: bool, name: String, vinsts: Vec<Virtual>) -> Result<Self, ArgIdx> {
         // Check that we can infer the type of `a`. This verifies
         // that the type of `a` "makes sense", in that the unbound
         // slots in the `Virtual` list are not contradictory in
         // type when referred to multiple times, and do not skip
         // indicies.
-        let typ = Self::infer_type_from_virtuals(&vinsts);
+        let typ = Self::try_infer_type_from_virtuals(&vinsts)?;
 
-        Self {
+        Ok(Self {
             from_idef,
             name: sanitize_name(&name),
             arg_count: typ.len(),
             vinsts,
-        }
+        })
     }
 
     pub fn with(name: &str, vinsts: Vec<Virtual>) -> Self {
@@ -376,6 +457,12 @@ impl Alias {
     }
 
     fn infer_type_from_virtuals(vinsts: &[Virtual]) -> Vec<ArgKind> {
+        Self::try_infer_type_from_virtuals(vinsts).unwrap()
+    }
+
+    /// The kinds of the arguments of an alias with body `vinsts`, or the index of the first
+    /// argument which is either never used, or used as arguments of incompatible kinds.
+    fn try_infer_type_from_virtuals(vinsts: &[Virtual]) -> Result<Vec<ArgKind>, ArgIdx> {
         let mut max_idx = None;
         let mut idxs = HashMap::new();
         for vi in vinsts {
@@ -402,24 +489,49 @@ impl Alias {
             }
         }
 
-        // In particular, the first `unwrap` makes sure that there are no "holes" in the unbound arg indexes.
+        // In particular, this makes sure that there are no "holes" in the unbound arg indexes.
         let mut kinds = Vec::new();
         if let Some(max_idx) = max_idx {
             for i in 0..max_idx + 1 {
-                let mut it = idxs.get(&i).unwrap().iter();
-                let first = it.next().unwrap();
-                assert!(it.all(|typ| typ == first));
-                kinds.push(*first);
+                // An argument used in several places must be acceptable in all of them.
+                let mut it = idxs.get(&i).ok_or(i)?.iter();
+                let first = *it.next().unwrap();
+                let kind = it
+                    .try_fold(first, |kind, other| match kind.width == other.width {
+                        true => kind
+                            .policy
+                            .intersect(other.policy)
+                            .map(|policy| ArgKind::new(kind.width, policy)),
+                        false => None,
+                    })
+                    .ok_or(i)?;
+                kinds.push(kind);
             }
         }
 
-        kinds
+        Ok(kinds)
     }
 
     pub fn infer_type(&self) -> Vec<ArgKind> {
         Self::infer_type_from_virtuals(&self.vinsts)
     }
 
+    /// Like `instantiate`, but first coercing each word constant passed for a byte argument into
+    /// a byte constant, where this is allowed (see `ArgKind::coerce`).
+    pub fn instantiate_coerced<Tag: Clone>(&self, args: &[Arg<Tag>]) -> Option<Vec<Blob<Tag>>> {
+        let kinds = self.infer_type();
+        if kinds.len() != args.len() {
+            return None;
+        }
+
+        let args = kinds
+            .into_iter()
+            .zip(args.iter().cloned())
+            .map(|(kind, arg)| kind.coerce(arg).ok())
+            .collect::<Option<Vec<_>>>()?;
+        self.instantiate(&args)
+    }
+
     pub fn instantiate<Tag: Clone>(&self, args: &[Arg<Tag>]) -> Option<Vec<Blob<Tag>>> {
         // We need to check the argument list length against our internally stored argument count,
         // since `Virtual::instantiate` panics when the argument list is too short, and we could
@@ -506,18 +618,29 @@ impl Virtual {
     }
 
     pub fn new(opclass: OpClass, args: EnumMap<IU, Option<Slot>>) -> Self {
-        assert!(Self::bound_slots_match(
-            UCode::get()
-                .inst_def_iter()
-                .find(|inst| inst.opclass == opclass)
-                .unwrap(),
-            args
-        ));
+        Self::try_new(opclass, args).unwrap()
+    }
+
+    /// Like `new`, but `None` if the slots do not fit the arguments of the instruction (or
+    /// bind more than one constant).
+    pub fn try_new(opclass: OpClass, args: EnumMap<IU, Option<Slot>>) -> Option<Self> {
+        let idef = UCode::get()
+            .inst_def_iter()
+            .find(|inst| inst.opclass == opclass)
+            .unwrap();
+
+        let consts = args
+            .values()
+            .filter(|slot| matches!(slot, Some(Slot::Const(_))))
+            .count();
+        if consts > 1 || !Self::bound_slots_match(idef, args) {
+            return None;
+        }
 
-        Virtual {
+        Some(Virtual {
             opclass,
             slots: args,
-        }
+        })
     }
 
     pub fn with_slots(
@@ -555,9 +678,9 @@ impl Virtual {
     /// if there is a type mismatch between the argument list and the instruction
     /// represented by this virtual instruction.
     ///
-    /// Panics if the passed argument list is invalid: currently either a) the
-    /// argument list is not long enough to resolve a bound variable, or includes
-    /// multiple constants.
+    /// Panics if the passed argument list is not long enough to resolve a bound variable.
+    /// Binding more than one constant (counting those already in this `Virtual`) also gives
+    /// `None`.
     pub fn instantiate<Tag: Clone>(&self, args: &[Arg<Tag>]) -> Option<Blob<Tag>> {
         // RUSTFIX we want to be performing runtime checks with `opclass.is_compatible` in this method
         //         oop, actually, if an opcode has a "bind" instruction where it binds an EnumMap of ius,
@@ -590,7 +713,9 @@ impl Virtual {
                     ius[iu] = Some(arg.to_preg());
 
                     if let Arg::Const(c) = arg {
-                        assert!(maybe_cb.is_none());
+                        if maybe_cb.is_some() {
+                            return None;
+                        }
                         maybe_cb = Some(c);
                     }
                 }
@@ -717,6 +842,26 @@ mod test {
     //
     // For example:
 
+    #[test]
+    fn const_word_coerced_to_byte() {
+        assert_eq!(
+            Const::Word(0x12).coerce_to_byte(Half::Hi),
+            Ok(Const::Byte(0x12, Half::Hi))
+        );
+        assert_eq!(
+            Const::Word(0x123).coerce_to_byte(Half::Lo),
+            Err(ByteCoercionError::DoesNotFit(0x123))
+        );
+        assert_eq!(
+            Const::WideWord(0x12).coerce_to_byte(Half::Lo),
+            Err(ByteCoercionError::WrittenAsWord(0x12))
+        );
+        assert_eq!(
+            Const::Byte(0x12, Half::Lo).coerce_to_byte(Half::Hi),
+            Ok(Const::Byte(0x12, Half::Lo))
+        );
+    }
+
     #[test]
     fn const_policy_partial_order() {
         assert!(ConstPolicy::Allow >= ConstPolicy::Allow);
@@ -726,4 +871,17 @@ mod test {
         assert!(ConstPolicy::Never >= ConstPolicy::Never);
         assert!(ConstPolicy::Never.partial_cmp(&ConstPolicy::Only) == None);
     }
+
+    #[test]
+    fn const_policy_intersect() {
+        assert_eq!(
+            ConstPolicy::Allow.intersect(ConstPolicy::Never),
+            Some(ConstPolicy::Never)
+        );
+        assert_eq!(
+            ConstPolicy::Only.intersect(ConstPolicy::Allow),
+            Some(ConstPolicy::Only)
+        );
+        assert_eq!(ConstPolicy::Only.intersect(ConstPolicy::Never), None);
+    }
 }
@@ -0,0 +1,425 @@
+use super::phases::expr::{BinaryOp, Expr, UnaryOp};
+use super::phases::resolve::{self, SymbolTable};
+use crate::spec::types::{
+    hw::{Byte, Word},
+    schema::Half,
+};
+use derive_more::Constructor;
+use std::collections::HashMap;
+use std::{convert::TryFrom, fmt::Display};
+
+/*
+    A relocatable object, as produced by `kasm -c` and consumed by `kcpu link`.
+
+    An object is a run of words which will be placed at some unknown (word-aligned) base address
+    in the final image. Any constant whose value could not be determined during assembly (i.e.
+    which refers to a label, or to a symbol imported from another object) is left as a
+    placeholder zero word, and a `Reloc` records the expression which must be evaluated and
+    patched into that word by the linker.
+
+    The on-disk format is line-oriented text, starting with the header line `KOBJ 1`, followed
+    by any number of the records:
+
+        DATA <hex word>...
+        LABEL <name> <hex byte offset>
+        EQU <name> <expr>
+        EXPORT <name>
+        IMPORT <name>
+        RELOC <hex word offset> W|L|H <expr>
+
+    `DATA` records are concatenated to form the words of the object, and must precede any
+    `RELOC` which refers to them. Expressions are written in
+    prefix notation, with numbers prefixed by `#` and names by `:` (e.g. `+ :buf #4`), since the
+    names generated by macro expansion cannot otherwise be written unambiguously.
+*/
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    DuplicateExport(String),
+    UnresolvedImport(String),
+    ImageTooLarge(usize),
+    Malformed(usize, String),
+    Resolve(resolve::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DuplicateExport(name) => {
+                write!(f, "Symbol '{}' is exported by more than one object", name)
+            }
+            Error::UnresolvedImport(name) => {
+                write!(
+                    f,
+                    "Symbol '{}' is imported but not exported by any object",
+                    name
+                )
+            }
+            Error::ImageTooLarge(len) => write!(
+                f,
+                "Linked image is {:#X} bytes long, which exceeds the address space",
+                len
+            ),
+            Error::Malformed(line, msg) => {
+                write!(f, "Malformed object file (line: {}): {}", line, msg)
+            }
+            Error::Resolve(err) => write!(f, "Linker error: {}", err),
+        }
+    }
+}
+
+impl From<resolve::Error> for Error {
+    fn from(err: resolve::Error) -> Self {
+        Error::Resolve(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocKind {
+    Word,
+    Byte(Half),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Constructor)]
+pub struct Reloc {
+    /// The index of the word to patch.
+    pub offset: usize,
+    pub kind: RelocKind,
+    pub expr: Expr,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub words: Vec<Word>,
+    pub relocs: Vec<Reloc>,
+    /// Each label, with its byte offset from the start of the object.
+    pub labels: Vec<(String, Word)>,
+    pub equs: Vec<(String, Expr)>,
+    pub exports: Vec<String>,
+    pub imports: Vec<String>,
+}
+
+const HEADER: &str = "KOBJ 1";
+const WORDS_PER_LINE: usize = 8;
+
+impl RelocKind {
+    fn to_str(self) -> &'static str {
+        match self {
+            RelocKind::Word => "W",
+            RelocKind::Byte(Half::Lo) => "L",
+            RelocKind::Byte(Half::Hi) => "H",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "W" => Some(RelocKind::Word),
+            "L" => Some(RelocKind::Byte(Half::Lo)),
+            "H" => Some(RelocKind::Byte(Half::Hi)),
+            _ => None,
+        }
+    }
+}
+
+fn write_expr(expr: &Expr, out: &mut Vec<String>) {
+    match expr {
+        Expr::Num(val) => out.push(format!("#{:X}", val)),
+        Expr::Name(name) => out.push(format!(":{}", name)),
+        Expr::Unary(op, e) => {
+            out.push(
+                match op {
+                    UnaryOp::Neg => "neg",
+                    UnaryOp::Not => "not",
+                    UnaryOp::Hi => "hi",
+                    UnaryOp::Lo => "lo",
+                }
+                .to_owned(),
+            );
+            write_expr(e, out);
+        }
+        Expr::Binary(op, l, r) => {
+            out.push(op.to_string());
+            write_expr(l, out);
+            write_expr(r, out);
+        }
+    }
+}
+
+fn read_expr<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<Expr, String> {
+    let token = tokens.next().ok_or("unexpected end of expression")?;
+
+    if let Some(hex) = token.strip_prefix('#') {
+        return Word::from_str_radix(hex, 16)
+            .map(Expr::Num)
+            .map_err(|_| format!("bad number '{}'", token));
+    }
+
+    if let Some(name) = token.strip_prefix(':') {
+        return Ok(Expr::Name(name.to_owned()));
+    }
+
+    let unary = match token {
+        "neg" => Some(UnaryOp::Neg),
+        "not" => Some(UnaryOp::Not),
+        "hi" => Some(UnaryOp::Hi),
+        "lo" => Some(UnaryOp::Lo),
+        _ => None,
+    };
+    if let Some(op) = unary {
+        return Ok(Expr::Unary(op, Box::new(read_expr(tokens)?)));
+    }
+
+    let op = BinaryOp::from_symbol(token).ok_or(format!("unknown operator '{}'", token))?;
+    let l = read_expr(tokens)?;
+    let r = read_expr(tokens)?;
+    Ok(Expr::Binary(op, Box::new(l), Box::new(r)))
+}
+
+impl Object {
+    pub fn write(&self) -> String {
+        let mut lines = vec![HEADER.to_owned()];
+
+        for chunk in self.words.chunks(WORDS_PER_LINE) {
+            let words: Vec<_> = chunk.iter().map(|w| format!("{:04X}", w)).collect();
+            lines.push(format!("DATA {}", words.join(" ")));
+        }
+
+        for (name, offset) in self.labels.iter() {
+            lines.push(format!("LABEL {} {:X}", name, offset));
+        }
+
+        for (name, expr) in self.equs.iter() {
+            let mut tokens = vec!["EQU".to_owned(), name.clone()];
+            write_expr(expr, &mut tokens);
+            lines.push(tokens.join(" "));
+        }
+
+        lines.extend(self.exports.iter().map(|name| format!("EXPORT {}", name)));
+        lines.extend(self.imports.iter().map(|name| format!("IMPORT {}", name)));
+
+        for reloc in self.relocs.iter() {
+            let mut tokens = vec![
+                "RELOC".to_owned(),
+                format!("{:X}", reloc.offset),
+                reloc.kind.to_str().to_owned(),
+            ];
+            write_expr(&reloc.expr, &mut tokens);
+            lines.push(tokens.join(" "));
+        }
+
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    pub fn read(src: &str) -> Result<Object, Error> {
+        let mut lines = src.lines().enumerate().map(|(idx, line)| (idx + 1, line));
+
+        match lines.next() {
+            Some((_, header)) if header.trim() == HEADER => (),
+            _ => return Err(Error::Malformed(1, format!("expected '{}'", HEADER))),
+        }
+
+        let mut obj = Object::default();
+        for (line_no, line) in lines {
+            obj.read_record(line)
+                .map_err(|msg| Error::Malformed(line_no, msg))?;
+        }
+
+        Ok(obj)
+    }
+
+    fn read_record(&mut self, line: &str) -> Result<(), String> {
+        let mut tokens = line.split_whitespace();
+
+        let name = |tokens: &mut std::str::SplitWhitespace| {
+            tokens
+                .next()
+                .map(str::to_owned)
+                .ok_or_else(|| "expected a name".to_owned())
+        };
+
+        let hex = |tokens: &mut std::str::SplitWhitespace| {
+            let token = tokens.next().ok_or("expected a number")?;
+            usize::from_str_radix(token, 16).map_err(|_| format!("bad number '{}'", token))
+        };
+
+        match tokens.next() {
+            None => (),
+            Some("DATA") => {
+                for token in tokens.by_ref() {
+                    let word = Word::from_str_radix(token, 16)
+                        .map_err(|_| format!("bad word '{}'", token))?;
+                    self.words.push(word);
+                }
+            }
+            Some("LABEL") => {
+                let label = name(&mut tokens)?;
+                let offset = Word::try_from(hex(&mut tokens)?)
+                    .map_err(|_| "label offset out of range".to_owned())?;
+                self.labels.push((label, offset));
+            }
+            Some("EQU") => {
+                let equ = name(&mut tokens)?;
+                self.equs.push((equ, read_expr(&mut tokens)?));
+            }
+            Some("EXPORT") => self.exports.push(name(&mut tokens)?),
+            Some("IMPORT") => self.imports.push(name(&mut tokens)?),
+            Some("RELOC") => {
+                let offset = hex(&mut tokens)?;
+                if offset >= self.words.len() {
+                    return Err(format!(
+                        "relocation at {:#X} is past the end of the data",
+                        offset
+                    ));
+                }
+                let kind = tokens
+                    .next()
+                    .and_then(RelocKind::from_str)
+                    .ok_or("expected a relocation kind")?;
+                let expr = read_expr(&mut tokens)?;
+                self.relocs.push(Reloc::new(offset, kind, expr));
+            }
+            Some(record) => return Err(format!("unknown record '{}'", record)),
+        }
+
+        match tokens.next() {
+            None => Ok(()),
+            Some(token) => Err(format!("unexpected trailing '{}'", token)),
+        }
+    }
+}
+
+/// Link `objects` into a single image, placing each object directly after the previous one,
+/// starting at address zero.
+pub fn link(objects: &[Object]) -> Result<Vec<Word>, Error> {
+    let mut exporters = HashMap::new();
+    for (idx, obj) in objects.iter().enumerate() {
+        for name in obj.exports.iter() {
+            if exporters.insert(name.as_str(), idx).is_some() {
+                return Err(Error::DuplicateExport(name.clone()));
+            }
+        }
+    }
+
+    for obj in objects {
+        if let Some(name) = obj
+            .imports
+            .iter()
+            .find(|name| !exporters.contains_key(name.as_str()))
+        {
+            return Err(Error::UnresolvedImport(name.clone()));
+        }
+    }
+
+    // Every object gets its own namespace: symbols which are not exported or imported are
+    // suffixed with the index of their object, so that they cannot collide.
+    let scoped = |idx: usize, obj: &Object, name: &str| {
+        if obj
+            .exports
+            .iter()
+            .chain(obj.imports.iter())
+            .any(|n| n == name)
+        {
+            name.to_owned()
+        } else {
+            format!("{}@{}", name, idx)
+        }
+    };
+    let scope_expr = |idx: usize, obj: &Object, expr: &Expr| {
+        expr.clone()
+            .map_names(&|name| Some(Expr::Name(scoped(idx, obj, name))))
+    };
+
+    let mut labels = HashMap::new();
+    let mut equs = HashMap::new();
+    let mut base = 0;
+    for (idx, obj) in objects.iter().enumerate() {
+        for (name, offset) in obj.labels.iter() {
+            let addr = base + usize::from(*offset);
+            let addr = Word::try_from(addr).map_err(|_| Error::ImageTooLarge(addr))?;
+            labels.insert(scoped(idx, obj, name), addr);
+        }
+
+        for (name, expr) in obj.equs.iter() {
+            equs.insert(scoped(idx, obj, name), scope_expr(idx, obj, expr));
+        }
+
+        base += 2 * obj.words.len();
+    }
+
+    if base > usize::from(Word::MAX) + 1 {
+        return Err(Error::ImageTooLarge(base));
+    }
+
+    let symbols = SymbolTable::new(labels, equs);
+
+    let mut image = Vec::new();
+    for (idx, obj) in objects.iter().enumerate() {
+        let mut words = obj.words.clone();
+        for reloc in obj.relocs.iter() {
+            let val = symbols.eval(&scope_expr(idx, obj, &reloc.expr), &[])?;
+            let word = &mut words[reloc.offset];
+            match reloc.kind {
+                RelocKind::Word => *word = val,
+                RelocKind::Byte(half) => {
+                    let byte = Byte::try_from(val).map_err(|_| {
+                        resolve::Error::LabelNotByteAddressable(reloc.expr.to_string(), val)
+                    })?;
+                    *word = match half {
+                        Half::Lo => (*word & 0xFF00) | Word::from(byte),
+                        Half::Hi => (*word & 0x00FF) | (Word::from(byte) << 8),
+                    };
+                }
+            }
+        }
+        image.extend(words);
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_read_roundtrip() {
+        let scoped = |name: &str| match name {
+            "buf" => Some(Expr::Name("buf$3".to_owned())),
+            _ => None,
+        };
+        let obj = Object {
+            words: (0..10).collect(),
+            relocs: vec![
+                Reloc::new(
+                    1,
+                    RelocKind::Word,
+                    Expr::parse("buf+hi(-COUNT)").unwrap().map_names(&scoped),
+                ),
+                Reloc::new(9, RelocKind::Byte(Half::Hi), Expr::parse("~lo(x)").unwrap()),
+            ],
+            labels: vec![("buf$3".to_owned(), 0x10)],
+            equs: vec![(
+                "COUNT".to_owned(),
+                Expr::parse("(buf-4)*2").unwrap().map_names(&scoped),
+            )],
+            exports: vec!["COUNT".to_owned()],
+            imports: vec!["x".to_owned()],
+        };
+
+        assert_eq!(Object::read(&obj.write()), Ok(obj));
+    }
+
+    #[test]
+    fn read_malformed() {
+        assert!(matches!(Object::read(""), Err(Error::Malformed(1, _))));
+        assert!(matches!(
+            Object::read("KOBJ 1\nDATA 0000\nRELOC 0 W + :a"),
+            Err(Error::Malformed(3, _))
+        ));
+        assert!(matches!(
+            Object::read("KOBJ 1\nDATA 0000\nRELOC 1 W :a"),
+            Err(Error::Malformed(3, _))
+        ));
+    }
+}
@@ -0,0 +1,835 @@
+use super::expr::Expr;
+use super::resolve::{self, SymbolTable};
+use super::scope;
+use super::types::{self, Field, FieldKind, Loc, Located, Statement};
+use crate::assembler::{
+    lang::Lang,
+    model::{self, Arg, Const, ConstBinding},
+};
+use crate::spec::types::hw::Word;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt::Display;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    UnterminatedBlock(&'static str),
+    UnmatchedBlockEnd(&'static str),
+    NestedMacroDef(String),
+    MacroNameCollidesWithInst(String),
+    MacroRedefined(String, Option<Box<Loc>>),
+    MacroDuplicateParam(String, String),
+    MacroArgCount(String, usize, usize, Option<Box<Loc>>),
+    MacroRecursionLimit(String),
+    BadCondition(String, resolve::Error),
+    AssertionFailed(String),
+    UserError(String),
+    StructBodyNotField(String),
+    StructDuplicateField(String, String),
+    StructTooLarge(String),
+    FieldOutsideStruct(String),
+    NestedProcDef(String),
+    ProcDuplicateName(String, String),
+    ProcFrameTooLarge(String),
+}
+
+fn fmt_opt_loc(loc: &Option<Box<Loc>>) -> String {
+    match loc {
+        None => String::from("<unknown location>"),
+        Some(loc) => loc.to_string(),
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnterminatedBlock(block) => write!(f, "Unterminated '!{}' block", block),
+            Error::UnmatchedBlockEnd(end) => {
+                write!(f, "Encountered '!{}' without a matching block start", end)
+            }
+            Error::NestedMacroDef(name) => write!(
+                f,
+                "Macro '{}' is defined inside another block, macro definitions may not be nested",
+                name
+            ),
+            Error::MacroNameCollidesWithInst(name) => write!(
+                f,
+                "Unacceptable macro name '{}', it collides with an instruction name",
+                name
+            ),
+            Error::MacroRedefined(name, prev) => write!(
+                f,
+                "Duplicate definition of macro '{}', previously defined at {}",
+                name,
+                fmt_opt_loc(prev)
+            ),
+            Error::MacroDuplicateParam(name, param) => write!(
+                f,
+                "Macro '{}' has more than one parameter named '{}'",
+                name, param
+            ),
+            Error::MacroArgCount(name, expected, given, def) => write!(
+                f,
+                "Macro '{}' (defined at {}) expects {} argument(s), but {} were given",
+                name,
+                fmt_opt_loc(def),
+                expected,
+                given
+            ),
+            Error::MacroRecursionLimit(name) => write!(
+                f,
+                "Macro expansion limit exceeded while expanding '{}', is it recursive?",
+                name
+            ),
+            Error::BadCondition(expr, err) => {
+                write!(f, "Could not evaluate '{}' during assembly: {}", expr, err)
+            }
+            Error::AssertionFailed(expr) => write!(f, "Assertion failed: {}", expr),
+            Error::UserError(msg) => write!(f, "{}", msg),
+            Error::StructBodyNotField(name) => write!(
+                f,
+                "The body of struct '{}' may only contain '!word' and '!byte' fields",
+                name
+            ),
+            Error::StructDuplicateField(name, field) => write!(
+                f,
+                "Struct '{}' has more than one field named '{}'",
+                name, field
+            ),
+            Error::StructTooLarge(name) => {
+                write!(f, "Struct '{}' does not fit in the address space", name)
+            }
+            Error::FieldOutsideStruct(field) => write!(
+                f,
+                "Field '{}' is declared outside of a '!struct' block",
+                field
+            ),
+            Error::NestedProcDef(name) => write!(
+                f,
+                "Procedure '{}' is defined inside another procedure, procedures may not be nested",
+                name
+            ),
+            Error::ProcDuplicateName(name, arg) => write!(
+                f,
+                "Procedure '{}' has more than one argument or local named '{}'",
+                name, arg
+            ),
+            Error::ProcFrameTooLarge(name) => write!(
+                f,
+                "The stack frame of procedure '{}' does not fit in the address space",
+                name
+            ),
+        }
+    }
+}
+
+/// A problem with a source which does not stop it from being assembled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    RetSkipsLeave(String),
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::RetSkipsLeave(name) => write!(
+                f,
+                "'RET' in procedure '{}' can be reached without passing through a 'LEAVE', \
+                 so would return without releasing the stack frame",
+                name
+            ),
+        }
+    }
+}
+
+struct Macro {
+    def: Option<Box<Loc>>,
+    params: Vec<String>,
+    body: Vec<Located<Statement>>,
+}
+
+#[derive(Clone, Copy)]
+enum BlockKind {
+    Macro,
+    Rept,
+    Alias,
+    Struct,
+    Proc,
+}
+
+impl BlockKind {
+    fn start_name(&self) -> &'static str {
+        match self {
+            BlockKind::Macro => "macro",
+            BlockKind::Rept => "rept",
+            BlockKind::Alias => "alias",
+            BlockKind::Struct => "struct",
+            BlockKind::Proc => "proc",
+        }
+    }
+
+    fn end_name(&self) -> &'static str {
+        match self {
+            BlockKind::Macro => "endm",
+            BlockKind::Rept => "endr",
+            BlockKind::Alias => "enda",
+            BlockKind::Struct => "ends",
+            BlockKind::Proc => "endp",
+        }
+    }
+}
+
+impl Statement {
+    fn block_delta(&self) -> Option<(BlockKind, bool)> {
+        match self {
+            Statement::MacroDef(..) => Some((BlockKind::Macro, true)),
+            Statement::MacroEnd => Some((BlockKind::Macro, false)),
+            Statement::Rept(_) => Some((BlockKind::Rept, true)),
+            Statement::ReptEnd => Some((BlockKind::Rept, false)),
+            Statement::AliasDef(..) => Some((BlockKind::Alias, true)),
+            Statement::AliasEnd => Some((BlockKind::Alias, false)),
+            Statement::StructDef(_) => Some((BlockKind::Struct, true)),
+            Statement::StructEnd => Some((BlockKind::Struct, false)),
+            Statement::ProcDef(..) => Some((BlockKind::Proc, true)),
+            Statement::ProcEnd => Some((BlockKind::Proc, false)),
+            _ => None,
+        }
+    }
+}
+
+/// Consume statements from `stmts` up to and including the end of the block
+/// which was begun by `start` (which has already been consumed), returning the
+/// statements strictly inside the block.
+fn take_block(
+    start: &Located<Statement>,
+    kind: BlockKind,
+    stmts: &mut impl Iterator<Item = Located<Statement>>,
+) -> Result<Vec<Located<Statement>>, Located<Error>> {
+    let mut body = Vec::new();
+    let mut open = vec![kind];
+    loop {
+        let stmt = match stmts.next() {
+            Some(stmt) => stmt,
+            None => {
+                return Err(start
+                    .clone()
+                    .transfer(Error::UnterminatedBlock(kind.start_name())))
+            }
+        };
+
+        match stmt.value_ref().block_delta() {
+            Some((inner, true)) => open.push(inner),
+            Some((inner, false)) => {
+                let outer = open.pop().unwrap();
+                if outer.end_name() != inner.end_name() {
+                    return Err(stmt.transfer(Error::UnmatchedBlockEnd(inner.end_name())));
+                }
+
+                if open.is_empty() {
+                    return Ok(body);
+                }
+            }
+            None => (),
+        }
+
+        body.push(stmt);
+    }
+}
+
+/// Lay out the fields `body` of the `!struct` called `name` (begun by `start`), each word field
+/// at the next even offset and each byte field at the next offset, returning the fields and
+/// the (even) size of the record.
+fn layout_struct(
+    start: &Located<Statement>,
+    name: &str,
+    body: Vec<Located<Statement>>,
+) -> Result<(Vec<Located<Field>>, Word), Located<Error>> {
+    let too_large = || Error::StructTooLarge(name.to_owned());
+
+    let mut fields: Vec<Located<Field>> = Vec::new();
+    let mut offset: Word = 0;
+    for stmt in body {
+        let (kind, field, count) = match stmt.value_ref() {
+            Statement::FieldDef(kind, field, count) => (*kind, field.clone(), *count),
+            _ => return Err(stmt.transfer(Error::StructBodyNotField(name.to_owned()))),
+        };
+
+        let full = format!("{}.{}", name, field);
+        if fields.iter().any(|prev| prev.value_ref().name == full) {
+            return Err(stmt.transfer(Error::StructDuplicateField(name.to_owned(), field)));
+        }
+
+        if kind == FieldKind::Word {
+            offset = offset
+                .checked_add(offset % 2)
+                .ok_or_else(|| stmt.locate(too_large()))?;
+        }
+        fields.push(stmt.locate(Field {
+            kind,
+            name: full,
+            offset,
+        }));
+        offset = kind
+            .size()
+            .checked_mul(count)
+            .and_then(|size| offset.checked_add(size))
+            .ok_or_else(|| stmt.locate(too_large()))?;
+    }
+
+    let size = offset
+        .checked_add(offset % 2)
+        .ok_or_else(|| start.locate(too_large()))?;
+    Ok((fields, size))
+}
+
+/// The argument bound to each macro parameter, or the offset bound to each `!proc` argument
+/// and local.
+type Bindings = HashMap<String, Located<Arg<Expr>>>;
+
+/// The offset from `%rbp` of each argument and local of the `!proc` called `name` (begun by
+/// `start`), bound as a constant, and the number of bytes taken by the locals. Once the frame
+/// has been entered, `%rbp` points at the saved `%rbp`, above which lie the return address and
+/// then the arguments (the last pushed lowest), while the locals lie below it (the first
+/// highest).
+fn layout_frame(
+    start: &Located<Statement>,
+    name: &str,
+    args: &[String],
+    locals: &[String],
+) -> Result<(Bindings, Word), Located<Error>> {
+    let too_large = || start.locate(Error::ProcFrameTooLarge(name.to_owned()));
+    let word = |bytes: usize| Word::try_from(bytes).map_err(|_| too_large());
+
+    let mut offsets = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        offsets.push((arg, word(4 + 2 * (args.len() - 1 - i))?));
+    }
+    let size = word(2 * locals.len())?;
+    for (i, local) in locals.iter().enumerate() {
+        offsets.push((local, word(2 * (i + 1))?.wrapping_neg()));
+    }
+
+    let mut bindings = HashMap::new();
+    for (var, offset) in offsets {
+        let offset = Arg::Const(ConstBinding::Resolved(Const::Word(offset)));
+        if bindings.insert(var.clone(), start.locate(offset)).is_some() {
+            let dup = Error::ProcDuplicateName(name.to_owned(), var.clone());
+            return Err(start.locate(dup));
+        }
+    }
+    Ok((bindings, size))
+}
+
+/// Whether the instruction called `name` is `family`, or one of its variants.
+fn is_in_family(name: &str, family: &str) -> bool {
+    let name = model::sanitize_name(name);
+    name == model::sanitize_name(family)
+        || matches!(
+            Lang::get().lookup_family(family),
+            Some(family) if family.variants.contains(&name)
+        )
+}
+
+/// An open `!if` or `!ifdef` block.
+struct Cond {
+    start: Located<()>,
+    holds: bool,
+    in_else: bool,
+}
+
+struct Expander {
+    macros: HashMap<String, Macro>,
+    next_id: usize,
+    /// The `!equ` constants seen so far while evaluating conditional blocks.
+    symbols: SymbolTable,
+    /// Whether the body of a `!proc` is being expanded.
+    in_proc: bool,
+    warnings: Vec<Located<Warning>>,
+}
+
+impl Expander {
+    const MAX_DEPTH: usize = 64;
+
+    fn new() -> Self {
+        Expander {
+            macros: HashMap::new(),
+            next_id: 0,
+            symbols: SymbolTable::new(HashMap::new(), HashMap::new()),
+            in_proc: false,
+            warnings: Vec::new(),
+        }
+    }
+
+    fn eval(&self, stmt: &Located<Statement>, expr: &Expr) -> Result<Word, Located<Error>> {
+        self.symbols
+            .eval(expr, &[])
+            .map_err(|err| stmt.locate(Error::BadCondition(expr.to_string(), err)))
+    }
+
+    /// Evaluate the conditional blocks in `stmts` (other than those inside a macro definition),
+    /// keeping only the statements of the branches which are taken, and checking each `!error`
+    /// and `!assert` among them. Conditions may only refer to the `!equ` constants (and
+    /// `!struct` offsets) defined before them, since no label has an address yet. Each
+    /// `!struct` block is also laid out here, so that its offsets are available.
+    fn select(
+        &mut self,
+        stmts: Vec<Located<Statement>>,
+    ) -> Result<Vec<Located<Statement>>, Located<Error>> {
+        let mut out = Vec::new();
+        let mut conds: Vec<Cond> = Vec::new();
+        let mut in_macro = false;
+        let mut it = stmts.into_iter();
+        while let Some(stmt) = it.next() {
+            let live = conds.iter().all(|cond| cond.holds != cond.in_else);
+
+            if in_macro {
+                in_macro = !matches!(stmt.value_ref(), Statement::MacroEnd);
+                if live {
+                    out.push(stmt);
+                }
+                continue;
+            }
+
+            match stmt.value_ref() {
+                Statement::If(expr) => {
+                    let holds = live && self.eval(&stmt, expr)? != 0;
+                    conds.push(Cond {
+                        start: stmt.locate(()),
+                        holds,
+                        in_else: false,
+                    });
+                }
+                Statement::IfDef(name) => {
+                    let holds = live && self.symbols.defines(name);
+                    conds.push(Cond {
+                        start: stmt.locate(()),
+                        holds,
+                        in_else: false,
+                    });
+                }
+                Statement::Else => match conds.last_mut() {
+                    Some(cond) if !cond.in_else => cond.in_else = true,
+                    _ => return Err(stmt.transfer(Error::UnmatchedBlockEnd("else"))),
+                },
+                Statement::EndIf => {
+                    if conds.pop().is_none() {
+                        return Err(stmt.transfer(Error::UnmatchedBlockEnd("endif")));
+                    }
+                }
+                Statement::MacroDef(..) => {
+                    in_macro = true;
+                    if live {
+                        out.push(stmt);
+                    }
+                }
+                _ if !live => (),
+                Statement::UserError(msg) => {
+                    let msg = msg.clone();
+                    return Err(stmt.transfer(Error::UserError(msg)));
+                }
+                Statement::Assert(expr) => {
+                    if self.eval(&stmt, expr)? == 0 {
+                        let expr = expr.to_string();
+                        return Err(stmt.transfer(Error::AssertionFailed(expr)));
+                    }
+                }
+                Statement::Equ(name, expr) => {
+                    self.symbols.insert_equ(name.clone(), expr.clone());
+                    out.push(stmt);
+                }
+                Statement::StructDef(name) => {
+                    let name = name.clone();
+                    let body = take_block(&stmt, BlockKind::Struct, &mut it)?;
+                    let (fields, size) = layout_struct(&stmt, &name, body)?;
+                    for field in fields.iter().map(Located::value_ref) {
+                        let offset = Expr::Num(field.offset);
+                        self.symbols.insert_equ(field.name.clone(), offset);
+                    }
+                    let size_name = types::struct_size_name(&name);
+                    self.symbols.insert_equ(size_name, Expr::Num(size));
+                    out.push(stmt.transfer(Statement::Struct(name, fields, size)));
+                }
+                Statement::StructEnd => {
+                    let end = BlockKind::Struct.end_name();
+                    return Err(stmt.transfer(Error::UnmatchedBlockEnd(end)));
+                }
+                Statement::FieldDef(_, field, _) => {
+                    let field = field.clone();
+                    return Err(stmt.transfer(Error::FieldOutsideStruct(field)));
+                }
+                _ => out.push(stmt),
+            }
+        }
+
+        match conds.pop() {
+            Some(cond) => Err(cond.start.transfer(Error::UnterminatedBlock("if"))),
+            None => Ok(out),
+        }
+    }
+
+    /// Remove all of the macro definitions from `stmts`, recording them. Afterwards
+    /// every remaining block in `stmts` is known to be well-formed.
+    fn extract_macros(
+        &mut self,
+        stmts: Vec<Located<Statement>>,
+    ) -> Result<Vec<Located<Statement>>, Located<Error>> {
+        let mut rest = Vec::new();
+        let mut open_repts = Vec::new();
+        let mut it = stmts.into_iter();
+        while let Some(stmt) = it.next() {
+            match stmt.value_ref() {
+                Statement::MacroDef(name, params) => {
+                    if !open_repts.is_empty() {
+                        let name = name.clone();
+                        return Err(stmt.transfer(Error::NestedMacroDef(name)));
+                    }
+
+                    let (name, params) = (name.clone(), params.clone());
+                    let body = take_block(&stmt, BlockKind::Macro, &mut it)?;
+                    self.define(stmt.transfer(name), params, body)?;
+                }
+                Statement::MacroEnd => {
+                    return Err(stmt.transfer(Error::UnmatchedBlockEnd(BlockKind::Macro.end_name())))
+                }
+                Statement::Rept(_) => {
+                    open_repts.push(stmt.clone().transfer(()));
+                    rest.push(stmt);
+                }
+                Statement::ReptEnd => {
+                    if open_repts.pop().is_none() {
+                        return Err(
+                            stmt.transfer(Error::UnmatchedBlockEnd(BlockKind::Rept.end_name()))
+                        );
+                    }
+                    rest.push(stmt);
+                }
+                _ => rest.push(stmt),
+            }
+        }
+
+        if let Some(start) = open_repts.pop() {
+            return Err(start.transfer(Error::UnterminatedBlock(BlockKind::Rept.start_name())));
+        }
+
+        Ok(rest)
+    }
+
+    fn define(
+        &mut self,
+        name: Located<String>,
+        params: Vec<String>,
+        body: Vec<Located<Statement>>,
+    ) -> Result<(), Located<Error>> {
+        let def = name.loc().cloned().map(Box::new);
+        let name = name.try_map(|name| {
+            if Lang::get().lookup_family(&name).is_some() {
+                return Err(Error::MacroNameCollidesWithInst(name));
+            }
+
+            for (i, param) in params.iter().enumerate() {
+                if params[..i].contains(param) {
+                    return Err(Error::MacroDuplicateParam(name, param.clone()));
+                }
+            }
+
+            Ok(model::sanitize_name(&name))
+        })?;
+
+        for stmt in body.iter() {
+            if let Statement::MacroDef(inner, _) = stmt.value_ref() {
+                return Err(stmt.clone().transfer(Error::NestedMacroDef(inner.clone())));
+            }
+        }
+
+        let key = name.value_ref().clone();
+        if let Some(prev) = self.macros.get(&key) {
+            return Err(name.map(|name| Error::MacroRedefined(name, prev.def.clone())));
+        }
+
+        self.macros.insert(key, Macro { def, params, body });
+        Ok(())
+    }
+
+    fn expand_all(
+        &mut self,
+        stmts: Vec<Located<Statement>>,
+        depth: usize,
+    ) -> Result<Vec<Located<Statement>>, Located<Error>> {
+        let mut out = Vec::new();
+        let mut it = stmts.into_iter();
+        while let Some(stmt) = it.next() {
+            match stmt.value_ref() {
+                Statement::Rept(count) => {
+                    let count = *count;
+                    let body = take_block(&stmt, BlockKind::Rept, &mut it)?;
+                    out.extend(self.expand_rept(count, body, depth)?);
+                }
+                Statement::Inst(name, _)
+                    if self.macros.contains_key(&model::sanitize_name(name)) =>
+                {
+                    out.extend(self.expand_call(stmt, depth)?);
+                }
+                Statement::AliasDef(name, params) => {
+                    let (name, params) = (name.clone(), params.clone());
+                    let body = take_block(&stmt, BlockKind::Alias, &mut it)?;
+                    let body = self.expand_all(body, depth)?;
+                    out.push(stmt.transfer(Statement::Alias(name, params, body)));
+                }
+                Statement::AliasEnd => {
+                    return Err(stmt.transfer(Error::UnmatchedBlockEnd(BlockKind::Alias.end_name())))
+                }
+                Statement::ProcDef(..) => {
+                    let body = take_block(&stmt, BlockKind::Proc, &mut it)?;
+                    out.extend(self.expand_proc(stmt, body, depth)?);
+                }
+                Statement::ProcEnd => {
+                    return Err(stmt.transfer(Error::UnmatchedBlockEnd(BlockKind::Proc.end_name())))
+                }
+                _ => out.push(stmt),
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Expand the `!proc` block begun by `start`, with the statements `body`: the body (with its
+    /// macros expanded, and each argument and local replaced by its offset) is labelled by the
+    /// name of the procedure and wrapped in a prologue entering its frame, and an epilogue
+    /// leaving it again and returning.
+    fn expand_proc(
+        &mut self,
+        start: Located<Statement>,
+        body: Vec<Located<Statement>>,
+        depth: usize,
+    ) -> Result<Vec<Located<Statement>>, Located<Error>> {
+        let (name, args, locals) = match start.value_ref() {
+            Statement::ProcDef(name, args, locals) => (name.clone(), args, locals),
+            _ => unreachable!(),
+        };
+
+        if self.in_proc {
+            return Err(start.transfer(Error::NestedProcDef(name)));
+        }
+        let (bindings, size) = layout_frame(&start, &name, args, locals)?;
+
+        self.in_proc = true;
+        let body = self.expand_all(body, depth);
+        self.in_proc = false;
+        let body = body?;
+        self.check_rets(&name, &body);
+
+        let inst = |inst: &str, args| start.locate(Statement::Inst(inst.to_owned(), args));
+        let prologue = match size {
+            0 => inst("ENTER", Vec::new()),
+            size => {
+                let size = Arg::Const(ConstBinding::Resolved(Const::Word(size)));
+                inst("ENTERFR", vec![start.locate(size)])
+            }
+        };
+
+        let mut out = vec![start.locate(Statement::LabelDef(name)), prologue];
+        out.extend(
+            body.into_iter()
+                .map(|stmt| stmt.map(|stmt| substitute(stmt, &bindings, &|_| None))),
+        );
+        out.push(inst("LEAVE", Vec::new()));
+        out.push(inst("RET", Vec::new()));
+        Ok(out)
+    }
+
+    /// Warn about each `RET` in the `body` of the procedure `name` which is not directly
+    /// preceded by a `LEAVE`, and so may be reached with the frame still entered. A label in
+    /// between counts, since it may be jumped to from anywhere.
+    fn check_rets(&mut self, name: &str, body: &[Located<Statement>]) {
+        let mut left = false;
+        for stmt in body {
+            match stmt.value_ref() {
+                Statement::Inst(inst, _) => {
+                    if is_in_family(inst, "RET") && !left {
+                        let warning = Warning::RetSkipsLeave(name.to_owned());
+                        self.warnings.push(stmt.locate(warning));
+                    }
+                    left = is_in_family(inst, "LEAVE");
+                }
+                Statement::Equ(..) => (),
+                _ => left = false,
+            }
+        }
+    }
+
+    fn expand_rept(
+        &mut self,
+        count: Word,
+        body: Vec<Located<Statement>>,
+        depth: usize,
+    ) -> Result<Vec<Located<Statement>>, Located<Error>> {
+        let mut out = Vec::new();
+        for _ in 0..count {
+            let copy = self.instantiate(&body, &HashMap::new(), None);
+            out.extend(self.expand_all(copy, depth)?);
+        }
+        Ok(out)
+    }
+
+    fn expand_call(
+        &mut self,
+        call: Located<Statement>,
+        depth: usize,
+    ) -> Result<Vec<Located<Statement>>, Located<Error>> {
+        let call_site = call.loc().cloned();
+        let (name, args) = match call.value_ref() {
+            Statement::Inst(name, args) => (name.clone(), args.clone()),
+            _ => unreachable!(),
+        };
+
+        if depth >= Expander::MAX_DEPTH {
+            return Err(call.transfer(Error::MacroRecursionLimit(name)));
+        }
+
+        let mac = &self.macros[&model::sanitize_name(&name)];
+        if mac.params.len() != args.len() {
+            return Err(call.transfer(Error::MacroArgCount(
+                name,
+                mac.params.len(),
+                args.len(),
+                mac.def.clone(),
+            )));
+        }
+
+        let bindings = mac
+            .params
+            .iter()
+            .cloned()
+            .zip(args)
+            .collect::<HashMap<_, _>>();
+        let body = mac.body.clone();
+
+        let copy = self.instantiate(&body, &bindings, call_site);
+        let copy = self.select(copy)?;
+        self.expand_all(copy, depth + 1)
+    }
+
+    /// Produce a fresh copy of `body`, with each parameter reference substituted
+    /// by its binding and each label (or constant) defined in the body renamed uniquely.
+    /// (Anonymous labels are left alone, since each reference to one is already relative to
+    /// its own position.)
+    fn instantiate(
+        &mut self,
+        body: &[Located<Statement>],
+        bindings: &Bindings,
+        call_site: Option<Loc>,
+    ) -> Vec<Located<Statement>> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let locals = body
+            .iter()
+            .filter_map(|stmt| match stmt.value_ref() {
+                Statement::LabelDef(name) | Statement::Equ(name, _)
+                    if !scope::is_anonymous_label(name) =>
+                {
+                    Some(name.clone())
+                }
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        // NOTE: '$' can never appear in a user-specified name, so these are unique.
+        let rename = |name: &str| match locals.contains(name) {
+            true => Some(format!("{}${}", name, id)),
+            false => None,
+        };
+
+        body.iter()
+            .cloned()
+            .map(|stmt| {
+                let stmt = stmt.map(|stmt| substitute(stmt, bindings, &rename));
+                match &call_site {
+                    None => stmt,
+                    Some(call_site) => stmt.map_loc(|loc| loc.expanded_from(call_site.clone())),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Substitute each parameter reference in `stmt` by its binding in `bindings`, and replace each
+/// other name (both where it is defined and where it is referred to) by its new name under
+/// `rename`, if it has one.
+fn substitute(
+    stmt: Statement,
+    bindings: &Bindings,
+    rename: &dyn Fn(&str) -> Option<String>,
+) -> Statement {
+    // Parameters bound to registers cannot appear inside an expression, so are left alone.
+    let substitute_name = |name: &str| match bindings.get(name).map(Located::value_ref) {
+        Some(Arg::Const(ConstBinding::Resolved(c))) => Some(Expr::Num(c.encode())),
+        Some(Arg::Const(ConstBinding::Unresolved(expr))) => Some(expr.clone()),
+        Some(Arg::Reg(_)) => None,
+        None => rename(name).map(Expr::Name),
+    };
+
+    let substitute = |cb: ConstBinding<Expr>| match cb {
+        ConstBinding::Unresolved(expr) => {
+            let expr = expr.map_names(&substitute_name);
+            match expr.fold() {
+                Some(val) => ConstBinding::Resolved(Const::Word(val)),
+                None => ConstBinding::Unresolved(expr),
+            }
+        }
+        cb => cb,
+    };
+
+    match stmt {
+        Statement::LabelDef(label) => Statement::LabelDef(rename(&label).unwrap_or(label)),
+        Statement::Equ(name, expr) => Statement::Equ(
+            rename(&name).unwrap_or(name),
+            expr.map_names(&substitute_name),
+        ),
+        Statement::Inst(name, args) => Statement::Inst(
+            name,
+            args.into_iter()
+                .map(|arg| match arg.value_ref() {
+                    // A parameter standing alone is replaced by exactly what it was bound to,
+                    // which may be a register or a byte constant.
+                    Arg::Const(ConstBinding::Unresolved(Expr::Name(param)))
+                        if bindings.contains_key(param) =>
+                    {
+                        bindings[param].clone()
+                    }
+                    _ => arg.map(|arg| match arg {
+                        Arg::Const(cb) => Arg::Const(substitute(cb)),
+                        arg => arg,
+                    }),
+                })
+                .collect(),
+        ),
+        Statement::RawWords(words) => {
+            Statement::RawWords(words.into_iter().map(substitute).collect())
+        }
+        Statement::RawBytes(bytes) => {
+            Statement::RawBytes(bytes.into_iter().map(substitute).collect())
+        }
+        Statement::Org(addr) => Statement::Org(addr.map_names(&substitute_name)),
+        Statement::Align(align) => Statement::Align(align.map_names(&substitute_name)),
+        Statement::If(cond) => Statement::If(cond.map_names(&substitute_name)),
+        Statement::Assert(cond) => Statement::Assert(cond.map_names(&substitute_name)),
+        Statement::Fill(count, value) => {
+            Statement::Fill(count.map_names(&substitute_name), substitute(value))
+        }
+        stmt => stmt,
+    }
+}
+
+/// The expanded statements, together with a warning for each problem found which does not stop
+/// them from being assembled.
+pub type Expansion = (Vec<Located<Statement>>, Vec<Located<Warning>>);
+
+/// Expand `stmts` as described in `types`.
+pub fn expand(stmts: Vec<Located<Statement>>) -> Result<Expansion, Located<Error>> {
+    let mut expander = Expander::new();
+    let stmts = expander.select(stmts)?;
+    let stmts = expander.extract_macros(stmts)?;
+    let stmts = expander.expand_all(stmts, 0)?;
+    Ok((stmts, expander.warnings))
+}
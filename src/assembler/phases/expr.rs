@@ -0,0 +1,431 @@
+use super::scope;
+use super::tokenize;
+use crate::spec::types::hw::Word;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// A constant expression, as may appear as an instruction or data operand, or in an `!equ`.
+/// Any `Name`s are symbols (labels or `!equ` constants), which are only known once all of the
+/// label addresses have been determined; so evaluation happens in `phases::resolve`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Num(Word),
+    Name(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    Hi,
+    Lo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EvalError<E> {
+    Symbol(E),
+    DivideByZero,
+}
+
+impl Display for UnaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnaryOp::Neg => write!(f, "-"),
+            UnaryOp::Not => write!(f, "~"),
+            UnaryOp::Hi => write!(f, "hi"),
+            UnaryOp::Lo => write!(f, "lo"),
+        }
+    }
+}
+
+impl Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::And => "&",
+            BinaryOp::Or => "|",
+            BinaryOp::Xor => "^",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Num(val) => write!(f, "{:#X}", val),
+            Expr::Name(name) => write!(f, "{}", name),
+            Expr::Unary(op @ UnaryOp::Hi, e) | Expr::Unary(op @ UnaryOp::Lo, e) => {
+                write!(f, "{}({})", op, e)
+            }
+            Expr::Unary(op, e) => write!(f, "{}{}", op, e),
+            Expr::Binary(op, l, r) => write!(f, "({}{}{})", l, op, r),
+        }
+    }
+}
+
+impl UnaryOp {
+    fn apply(self, val: Word) -> Word {
+        match self {
+            UnaryOp::Neg => val.wrapping_neg(),
+            UnaryOp::Not => !val,
+            UnaryOp::Hi => val >> 8,
+            UnaryOp::Lo => val & 0xFF,
+        }
+    }
+
+    fn from_function_name(name: &str) -> Option<Self> {
+        match name {
+            "hi" => Some(UnaryOp::Hi),
+            "lo" => Some(UnaryOp::Lo),
+            _ => None,
+        }
+    }
+}
+
+impl BinaryOp {
+    fn apply(self, l: Word, r: Word) -> Option<Word> {
+        Some(match self {
+            BinaryOp::Add => l.wrapping_add(r),
+            BinaryOp::Sub => l.wrapping_sub(r),
+            BinaryOp::Mul => l.wrapping_mul(r),
+            BinaryOp::Div => l.checked_div(r)?,
+            BinaryOp::Rem => l.checked_rem(r)?,
+            BinaryOp::Shl => l.checked_shl(r.into()).unwrap_or(0),
+            BinaryOp::Shr => l.checked_shr(r.into()).unwrap_or(0),
+            BinaryOp::And => l & r,
+            BinaryOp::Or => l | r,
+            BinaryOp::Xor => l ^ r,
+            BinaryOp::Eq => Word::from(l == r),
+            BinaryOp::Ne => Word::from(l != r),
+            BinaryOp::Lt => Word::from(l < r),
+            BinaryOp::Le => Word::from(l <= r),
+            BinaryOp::Gt => Word::from(l > r),
+            BinaryOp::Ge => Word::from(l >= r),
+        })
+    }
+
+    pub fn from_symbol(sym: &str) -> Option<Self> {
+        BinaryOp::PRECEDENCE
+            .iter()
+            .flat_map(|level| level.iter())
+            .find(|(s, _)| *s == sym)
+            .map(|(_, op)| *op)
+    }
+
+    /// The operators at each precedence level, from loosest to tightest binding. (Where one
+    /// operator is a prefix of another, the longer must come first.) Comparisons evaluate to
+    /// `1` if they hold and `0` otherwise.
+    const PRECEDENCE: [&'static [(&'static str, BinaryOp)]; 8] = [
+        &[("|", BinaryOp::Or)],
+        &[("^", BinaryOp::Xor)],
+        &[("&", BinaryOp::And)],
+        &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+        &[
+            ("<=", BinaryOp::Le),
+            (">=", BinaryOp::Ge),
+            ("<", BinaryOp::Lt),
+            (">", BinaryOp::Gt),
+        ],
+        &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+        &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+        &[
+            ("*", BinaryOp::Mul),
+            ("/", BinaryOp::Div),
+            ("%", BinaryOp::Rem),
+        ],
+    ];
+}
+
+impl Expr {
+    pub fn parse(raw: &str) -> Result<Expr, &'static str> {
+        let mut parser = Parser {
+            raw,
+            chars: raw.char_indices().peekable(),
+        };
+
+        let expr = parser.parse_level(0)?;
+        match parser.chars.peek().map(|(_, c)| *c) {
+            None => Ok(expr),
+            Some(')') => Err("unbalanced ')'"),
+            Some(_) => Err("expected an operator"),
+        }
+    }
+
+    pub fn eval<E, F>(&self, lookup: &F) -> Result<Word, EvalError<E>>
+    where
+        F: Fn(&str) -> Result<Word, E>,
+    {
+        match self {
+            Expr::Num(val) => Ok(*val),
+            Expr::Name(name) => lookup(name).map_err(EvalError::Symbol),
+            Expr::Unary(op, e) => Ok(op.apply(e.eval(lookup)?)),
+            Expr::Binary(op, l, r) => op
+                .apply(l.eval(lookup)?, r.eval(lookup)?)
+                .ok_or(EvalError::DivideByZero),
+        }
+    }
+
+    /// Evaluate this expression if it does not depend on the value of any symbol.
+    pub fn fold(&self) -> Option<Word> {
+        self.eval(&|_| Err(())).ok()
+    }
+
+    /// The symbols named in this expression, in order of appearance.
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Expr::Num(_) => vec![],
+            Expr::Name(name) => vec![name],
+            Expr::Unary(_, e) => e.names(),
+            Expr::Binary(_, l, r) => [l.names(), r.names()].concat(),
+        }
+    }
+
+    /// Substitute each `Name` in this expression with the result of `f`, or leave it
+    /// unchanged if `f` returns `None`.
+    pub fn map_names<F>(self, f: &F) -> Expr
+    where
+        F: Fn(&str) -> Option<Expr>,
+    {
+        match self {
+            Expr::Num(val) => Expr::Num(val),
+            Expr::Name(name) => f(&name).unwrap_or(Expr::Name(name)),
+            Expr::Unary(op, e) => Expr::Unary(op, Box::new(e.map_names(f))),
+            Expr::Binary(op, l, r) => {
+                Expr::Binary(op, Box::new(l.map_names(f)), Box::new(r.map_names(f)))
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    raw: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn is_name_char(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '_' || c == '.'
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        let idx = match self.chars.peek() {
+            Some((idx, _)) => *idx,
+            None => return false,
+        };
+
+        if !self.raw[idx..].starts_with(op) {
+            return false;
+        }
+
+        for _ in op.chars() {
+            self.chars.next();
+        }
+        true
+    }
+
+    fn parse_level(&mut self, level: usize) -> Result<Expr, &'static str> {
+        if level == BinaryOp::PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+
+        let mut lhs = self.parse_level(level + 1)?;
+        'outer: loop {
+            for (sym, op) in BinaryOp::PRECEDENCE[level].iter() {
+                if self.eat(sym) {
+                    let rhs = self.parse_level(level + 1)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, &'static str> {
+        if self.eat("-") {
+            Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.parse_unary()?)))
+        } else if self.eat("~") {
+            Ok(Expr::Unary(UnaryOp::Not, Box::new(self.parse_unary()?)))
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    fn parse_parenthesized(&mut self) -> Result<Expr, &'static str> {
+        if !self.eat("(") {
+            return Err("expected '('");
+        }
+
+        let expr = self.parse_level(0)?;
+        if !self.eat(")") {
+            return Err("expected ')'");
+        }
+        Ok(expr)
+    }
+
+    fn parse_char_literal(&mut self, start: usize) -> Result<Expr, &'static str> {
+        self.chars.next();
+
+        let mut escaped = false;
+        loop {
+            match self.chars.next() {
+                None => return Err("unterminated character literal"),
+                Some(_) if escaped => escaped = false,
+                Some((_, '\\')) => escaped = true,
+                Some((idx, '\'')) => {
+                    return tokenize::parse_char_literal(&self.raw[start + 1..idx]).map(Expr::Num)
+                }
+                Some(_) => (),
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, &'static str> {
+        let (start, first) = match self.chars.peek() {
+            None => return Err("unexpected end of expression"),
+            Some((idx, c)) => (*idx, *c),
+        };
+
+        if first == '(' {
+            return self.parse_parenthesized();
+        }
+
+        if first == '\'' {
+            return self.parse_char_literal(start);
+        }
+
+        if !Parser::is_name_char(first) {
+            return Err("expected a number, name, or '('");
+        }
+
+        while let Some((_, c)) = self.chars.peek() {
+            if !Parser::is_name_char(*c) {
+                break;
+            }
+            self.chars.next();
+        }
+
+        let end = self.chars.peek().map_or(self.raw.len(), |(idx, _)| *idx);
+        let atom = &self.raw[start..end];
+
+        if first.is_ascii_digit() && scope::anonymous_ref(atom).is_none() {
+            return Parser::parse_number(atom).map(Expr::Num);
+        }
+
+        match UnaryOp::from_function_name(atom) {
+            Some(op) if self.chars.peek().map(|(_, c)| *c) == Some('(') => {
+                Ok(Expr::Unary(op, Box::new(self.parse_parenthesized()?)))
+            }
+            _ => Ok(Expr::Name(atom.to_owned())),
+        }
+    }
+
+    fn parse_number(atom: &str) -> Result<Word, &'static str> {
+        let val = if let Some(digits) = atom.strip_prefix("0x") {
+            u64::from_str_radix(digits, 16)
+        } else if let Some(digits) = atom.strip_prefix("0o") {
+            u64::from_str_radix(digits, 8)
+        } else if let Some(digits) = atom.strip_prefix("0b") {
+            u64::from_str_radix(digits, 2)
+        } else {
+            atom.parse::<u64>()
+        }
+        .map_err(|_| "could not parse numeric")?;
+
+        Word::try_from(val).map_err(|_| "integral value out of bounds")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BinaryOp, EvalError, Expr, UnaryOp};
+
+    fn eval(raw: &str) -> Result<u16, EvalError<()>> {
+        Expr::parse(raw).unwrap().eval(&|name| match name {
+            "buf" => Ok(0x100),
+            "COLS" => Ok(80),
+            _ => Err(()),
+        })
+    }
+
+    #[test]
+    fn parse_precedence() {
+        assert_eq!(
+            Expr::parse("buf+4*2").unwrap(),
+            Expr::Binary(
+                BinaryOp::Add,
+                Box::new(Expr::Name("buf".to_owned())),
+                Box::new(Expr::Binary(
+                    BinaryOp::Mul,
+                    Box::new(Expr::Num(4)),
+                    Box::new(Expr::Num(2))
+                ))
+            )
+        );
+        assert_eq!(
+            Expr::parse("hi(buf)").unwrap(),
+            Expr::Unary(UnaryOp::Hi, Box::new(Expr::Name("buf".to_owned())))
+        );
+    }
+
+    #[test]
+    fn parse_malformed() {
+        assert!(Expr::parse("(buf+4").is_err());
+        assert!(Expr::parse("buf+4)").is_err());
+        assert!(Expr::parse("buf+").is_err());
+        assert!(Expr::parse("0x10000").is_err());
+        assert!(Expr::parse("0xZZ").is_err());
+    }
+
+    #[test]
+    fn evaluate() {
+        assert_eq!(eval("buf+4"), Ok(0x104));
+        assert_eq!(eval("(COLS*2)-1"), Ok(159));
+        assert_eq!(eval("hi(buf)|lo(0x1234)"), Ok(0x35));
+        assert_eq!(eval("1<<4>>2"), Ok(4));
+        assert_eq!(eval("~0&0xF0^0x0F"), Ok(0xFF));
+        assert_eq!(eval("-1"), Ok(0xFFFF));
+        assert_eq!(eval("0b101%0o3"), Ok(2));
+        assert_eq!(eval("buf/(COLS-80)"), Err(EvalError::DivideByZero));
+        assert_eq!(eval("nope+1"), Err(EvalError::Symbol(())));
+        assert_eq!(eval("COLS==80&buf>=0x100"), Ok(1));
+        assert_eq!(eval("1<<2<4|COLS!=80"), Ok(0));
+    }
+}
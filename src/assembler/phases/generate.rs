@@ -1,28 +1,51 @@
-use super::types::{BinaryElement, LabelName, Located, Statement};
-use crate::assembler::{lang::Lang, model::Arg};
+use super::expr::Expr;
+use super::types::{
+    self, BinaryElement, Field, FieldKind, InstSource, LabelName, Located, Statement, MAX_ERRORS,
+};
+use crate::assembler::{
+    lang::{self, Lang},
+    model::{self, Alias, Arg, ByteCoercionError, Const, ConstBinding, Slot, Virtual},
+};
 use crate::common;
-use crate::spec::types::{
-    hw::{self, Byte, Word},
-    schema::ArgKind,
+use crate::spec::{
+    types::{
+        hw::{self, Byte, Word, IU},
+        schema::{ArgKind, Half, Width},
+    },
+    ucode::UCode,
 };
 use ansi_term::Color::{Green, Red, Yellow};
+use enum_map::EnumMap;
 use itertools::{EitherOrBoth, Itertools};
-use std::{fmt::Display, iter};
+use std::{collections::HashSet, convert::TryFrom, fmt::Display, iter};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     BadDataParity,
+    DataByteOutOfRange(Word),
+    StringCharOutOfRange(char),
     LabelNameCollidesWithInst(LabelName),
     InstUnknown(String),
-    InstMultipleConstArgs(String, Vec<Arg<LabelName>>),
-    InstUnacceptableArgKinds(String, Vec<Arg<LabelName>>),
+    InstMultipleConstArgs(String, Vec<Arg<Expr>>),
+    /// The name and arguments of the instruction, and the name and argument kinds of each of
+    /// the variants of its family.
+    InstUnacceptableArgKinds(String, Vec<Arg<Expr>>, Box<[(String, Vec<ArgKind>)]>),
+    InstConstNotByte(String, Arg<Expr>, ByteCoercionError),
+    InstAmbiguousCoercion(String, Vec<Arg<Expr>>),
+    InstByteFieldNotByteAccess(String, String),
+    AliasBodyNotInst,
+    AliasBodyUnknownInst(String),
+    AliasBodyBadArgs(String, Vec<Arg<Expr>>),
+    AliasArgNotLiteral(Expr),
+    AliasParamKind(String, String),
+    Lang(lang::Error),
 }
 
 impl Error {
     fn fmt_arg_kinds_given_args(
         f: &mut std::fmt::Formatter<'_>,
         kinds: &[ArgKind],
-        args: &[Arg<LabelName>],
+        args: &[Arg<Expr>],
     ) -> std::fmt::Result {
         let fmted = kinds
             .iter()
@@ -49,6 +72,16 @@ impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::BadDataParity => write!(f, "Bad data parity"),
+            Error::StringCharOutOfRange(c) => {
+                write!(f, "Character '{}' in string does not fit in a byte", c)
+            }
+            Error::DataByteOutOfRange(val) => {
+                write!(
+                    f,
+                    "Constant {:#06X} in byte array does not fit in a byte",
+                    val
+                )
+            }
             Error::LabelNameCollidesWithInst(ln) => write!(
                 f,
                 "Unacceptable label name '{}', it collides with an instruction name",
@@ -64,7 +97,54 @@ impl Display for Error {
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
-            Error::InstUnacceptableArgKinds(name, args) => {
+            Error::InstConstNotByte(name, arg, err) => write!(
+                f,
+                "Constant {} passed to instruction '{}' cannot be used as a byte: {}",
+                arg, name, err
+            ),
+            Error::InstAmbiguousCoercion(name, args) => write!(
+                f,
+                "Constant arguments to instruction '{}' could be used as either byte, write l$ or h$ to choose one; arguments were: {}",
+                name,
+                args.iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Error::InstByteFieldNotByteAccess(name, field) => write!(
+                f,
+                "Byte field '{}' is accessed by '{}', which is not a byte load or store",
+                field, name
+            ),
+            Error::AliasBodyNotInst => {
+                write!(f, "The body of an alias may only contain instructions")
+            }
+            Error::AliasBodyUnknownInst(inst) => write!(
+                f,
+                "Unknown instruction '{}' in the body of an alias (only true instructions, not aliases, may be used)",
+                inst
+            ),
+            Error::AliasBodyBadArgs(inst, args) => write!(
+                f,
+                "Invalid arguments passed to instruction '{}' in the body of an alias, arguments were: {}",
+                inst,
+                args.iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Error::AliasArgNotLiteral(expr) => write!(
+                f,
+                "Argument '{}' in the body of an alias is neither a parameter nor a literal constant",
+                expr
+            ),
+            Error::AliasParamKind(alias, param) => write!(
+                f,
+                "Parameter '{}' of alias '{}' is either unused, or used as arguments of incompatible kinds",
+                param, alias
+            ),
+            Error::Lang(err) => write!(f, "{}", err),
+            Error::InstUnacceptableArgKinds(name, args, candidates) => {
                 writeln!(
                     f,
                     "Invalid arguments passed to instruction '{}', arguments were:",
@@ -79,14 +159,9 @@ impl Display for Error {
                         .join(", ")
                 )?;
                 writeln!(f, "candidate argument lists were:")?;
-                let family = Lang::get().lookup_family(&name).unwrap();
-                for alias in family
-                    .variants
-                    .iter()
-                    .map(|alias| Lang::get().lookup_alias(alias).unwrap())
-                {
-                    write!(f, "\t{: <3}: ", alias.name)?;
-                    Error::fmt_arg_kinds_given_args(f, &alias.infer_type(), args)?;
+                for (alias, kinds) in candidates.iter() {
+                    write!(f, "\t{: <3}: ", alias)?;
+                    Error::fmt_arg_kinds_given_args(f, kinds, args)?;
                     writeln!(f)?;
                 }
                 Ok(())
@@ -95,72 +170,263 @@ impl Display for Error {
     }
 }
 
+/// The families of loads and stores. Only their variants which access a single byte may name a
+/// byte field of a `!struct`.
+const MEM_FAMILIES: [&str; 3] = ["LD", "LDZ", "ST"];
+
 impl Statement {
-    pub(super) fn generate(self) -> Result<Vec<BinaryElement>, Error> {
+    /// Generate this statement, where `byte_fields` holds the name of each byte field of every
+    /// `!struct` in the assembly.
+    pub(super) fn generate(
+        self,
+        lang: &Lang,
+        byte_fields: &HashSet<String>,
+    ) -> Result<Vec<BinaryElement>, Error> {
         match self {
-            Statement::LabelDef(label) => Statement::generate_label_def(label),
+            Statement::LabelDef(label) => Statement::generate_label_def(lang, label),
+            Statement::Equ(name, expr) => Statement::generate_equ(lang, name, expr),
+            Statement::Export(names) => Ok(vec![BinaryElement::Export(names)]),
+            Statement::Import(names) => Ok(vec![BinaryElement::Import(names)]),
+            Statement::Org(addr) => Ok(vec![BinaryElement::Org(addr)]),
+            Statement::Align(align) => Ok(vec![BinaryElement::Align(align)]),
+            Statement::Fill(count, value) => Ok(vec![BinaryElement::Fill(count, value)]),
             Statement::RawWords(words) => Statement::generate_raw_words(words),
             Statement::RawBytes(bytes) => Statement::generate_raw_bytes(bytes),
-            Statement::RawString(string) => Statement::generate_raw_string(string),
-            Statement::Inst(inst, args) => Statement::generate_inst(inst, args),
+            Statement::RawString(string) => Statement::generate_raw_string(string, true),
+            Statement::RawBString(string) => Statement::generate_raw_string(string, false),
+            Statement::Inst(inst, args) => Statement::generate_inst(lang, byte_fields, inst, args),
+            Statement::Struct(name, fields, size) => Statement::generate_struct(name, fields, size),
+            Statement::Include(_) => unreachable!("includes are removed during inclusion"),
+            Statement::Alias(..) | Statement::Family(..) => {
+                unreachable!("language definitions are removed before generation")
+            }
+            Statement::MacroDef(..)
+            | Statement::MacroEnd
+            | Statement::AliasDef(..)
+            | Statement::AliasEnd
+            | Statement::StructDef(_)
+            | Statement::StructEnd
+            | Statement::FieldDef(..)
+            | Statement::ProcDef(..)
+            | Statement::ProcEnd
+            | Statement::Rept(_)
+            | Statement::ReptEnd
+            | Statement::If(_)
+            | Statement::IfDef(_)
+            | Statement::Else
+            | Statement::EndIf
+            | Statement::UserError(_)
+            | Statement::Assert(_) => unreachable!("blocks are removed by macro expansion"),
         }
     }
 
-    fn generate_label_def(label: String) -> Result<Vec<BinaryElement>, Error> {
-        if Lang::get().lookup_family(&label).is_some() {
+    fn generate_label_def(lang: &Lang, label: String) -> Result<Vec<BinaryElement>, Error> {
+        if lang.lookup_family(&label).is_some() {
             return Err(Error::LabelNameCollidesWithInst(label));
         }
 
         Ok(vec![BinaryElement::LabelDef(label)])
     }
 
-    fn generate_raw_words(words: Vec<Word>) -> Result<Vec<BinaryElement>, Error> {
+    fn generate_equ(lang: &Lang, name: String, expr: Expr) -> Result<Vec<BinaryElement>, Error> {
+        if lang.lookup_family(&name).is_some() {
+            return Err(Error::LabelNameCollidesWithInst(name));
+        }
+
+        Ok(vec![BinaryElement::Equ(name, expr)])
+    }
+
+    fn generate_struct(
+        name: String,
+        fields: Vec<Located<Field>>,
+        size: Word,
+    ) -> Result<Vec<BinaryElement>, Error> {
+        Ok(fields
+            .into_iter()
+            .map(Located::value)
+            .map(|field| BinaryElement::Equ(field.name, Expr::Num(field.offset)))
+            .chain(iter::once(BinaryElement::Equ(
+                types::struct_size_name(&name),
+                Expr::Num(size),
+            )))
+            .collect())
+    }
+
+    fn generate_raw_words(words: Vec<ConstBinding<Expr>>) -> Result<Vec<BinaryElement>, Error> {
         Ok(vec![BinaryElement::Data(words)])
     }
 
-    fn generate_raw_bytes(bytes: Vec<Byte>) -> Result<Vec<BinaryElement>, Error> {
-        Statement::generate_raw_words(hw::bytes_to_words(&bytes).ok_or(Error::BadDataParity)?)
+    fn generate_raw_bytes(bytes: Vec<ConstBinding<Expr>>) -> Result<Vec<BinaryElement>, Error> {
+        if bytes.len() % 2 != 0 {
+            return Err(Error::BadDataParity);
+        }
+
+        let bytes = bytes
+            .into_iter()
+            .map(|cb| match cb {
+                ConstBinding::Resolved(Const::Word(val))
+                | ConstBinding::Resolved(Const::WideWord(val)) => Byte::try_from(val)
+                    .map(|b| ConstBinding::Resolved(Const::Byte(b, Half::Lo)))
+                    .map_err(|_| Error::DataByteOutOfRange(val)),
+                cb => Ok(cb),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(vec![BinaryElement::ByteData(bytes)])
     }
 
-    fn generate_raw_string(string: String) -> Result<Vec<BinaryElement>, Error> {
-        Statement::generate_raw_bytes(
-            string
-                .bytes()
-                .chain(iter::repeat(b'\0').take(if string.len() % 2 == 0 { 2 } else { 1 }))
+    /// Each character of a string is emitted as a single byte (so only characters up to U+00FF
+    /// may appear), followed (if `terminate`) by a NUL and, if necessary, a second NUL padding
+    /// the string to a whole number of words.
+    fn generate_raw_string(string: String, terminate: bool) -> Result<Vec<BinaryElement>, Error> {
+        let mut bytes = string
+            .chars()
+            .map(|c| u8::try_from(c).map_err(|_| Error::StringCharOutOfRange(c)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if terminate {
+            let padding = if bytes.len() % 2 == 0 { 2 } else { 1 };
+            bytes.extend(iter::repeat(b'\0').take(padding));
+        }
+
+        Statement::generate_raw_words(
+            hw::bytes_to_words(&bytes)
+                .ok_or(Error::BadDataParity)?
+                .into_iter()
+                .map(|w| ConstBinding::Resolved(Const::Word(w)))
                 .collect(),
         )
     }
 
+    /// If some alias of `inst` would have accepted `args` had one of the word constants among
+    /// them been coerced into a byte, an error explaining why it was not.
+    fn explain_rejected_const(lang: &Lang, inst: &str, args: &[Arg<Expr>]) -> Option<Error> {
+        let family = lang.lookup_family(inst)?;
+        for alias in family.variants.iter() {
+            let alias = lang.lookup_alias(alias).unwrap();
+            for (idx, (kind, arg)) in alias.infer_type().into_iter().zip(args).enumerate() {
+                let (half, err) = match (kind.width, kind.coerce(arg.clone())) {
+                    (Width::Byte(half), Err(err)) => (half, err),
+                    _ => continue,
+                };
+
+                let mut forced = args.to_vec();
+                forced[idx] = Arg::Const(ConstBinding::Resolved(Const::Byte(0, half)));
+                if alias.instantiate(&forced).is_some() {
+                    return Some(Error::InstConstNotByte(inst.to_owned(), arg.clone(), err));
+                }
+            }
+        }
+        None
+    }
+
+    /// Check that no byte field in `byte_fields` is named by `args`, if `alias` is a word load
+    /// or store.
+    fn check_byte_fields(
+        lang: &Lang,
+        byte_fields: &HashSet<String>,
+        alias: &str,
+        args: &[Arg<Expr>],
+    ) -> Result<(), Error> {
+        let is_mem = MEM_FAMILIES
+            .iter()
+            .filter_map(|name| lang.lookup_family(name))
+            .flat_map(|family| family.variants.iter())
+            .any(|variant| model::sanitize_name(variant) == model::sanitize_name(alias));
+        let is_byte = lang
+            .lookup_alias(alias)
+            .unwrap()
+            .infer_type()
+            .iter()
+            .any(|kind| matches!(kind.width, Width::Byte(_)));
+        if !is_mem || is_byte {
+            return Ok(());
+        }
+
+        let field = args
+            .iter()
+            .filter_map(|arg| match arg {
+                Arg::Const(ConstBinding::Unresolved(expr)) => Some(expr.names()),
+                _ => None,
+            })
+            .flatten()
+            .find(|name| byte_fields.contains(*name));
+        match field {
+            Some(field) => Err(Error::InstByteFieldNotByteAccess(
+                alias.to_uppercase(),
+                field.to_owned(),
+            )),
+            None => Ok(()),
+        }
+    }
+
     fn generate_inst(
+        lang: &Lang,
+        byte_fields: &HashSet<String>,
         inst: String,
-        args: Vec<Located<Arg<LabelName>>>,
+        args: Vec<Located<Arg<Expr>>>,
     ) -> Result<Vec<BinaryElement>, Error> {
-        // RUSTFIX at the moment, we drop argument location information, since we don't have anything to do with it
-        // (and, even if we could think of something(?), we'd have to peer into `Alias::instantiate` to assign it).
-        // This latter thing could be fine, but we don't want to do it yet.
-        let args: Vec<Arg<LabelName>> = args.into_iter().map(Located::value).collect();
+        let arg_vals: Vec<Arg<Expr>> = args.iter().map(|arg| arg.value_ref().clone()).collect();
 
         // RUSTFIX this condition is too harsh: have aliases generate a named error for this (during instantiate) when they do
         // Don't throw away failures though: consider this a match, error on more then one, and only then open up the error message.
-        if args.iter().filter(|arg| arg.is_const()).count() > 1 {
-            return Err(Error::InstMultipleConstArgs(inst, args));
+        if arg_vals.iter().filter(|arg| arg.is_const()).count() > 1 {
+            return Err(Error::InstMultipleConstArgs(inst, arg_vals));
         }
 
-        let family = Lang::get()
+        let family = lang
             .lookup_family(&inst)
             .ok_or_else(|| Error::InstUnknown(inst.clone()))?;
 
-        let matches = family
-            .variants
-            .iter()
-            .filter_map(|alias| Lang::get().lookup_alias(alias).unwrap().instantiate(&args));
+        let matches = family.variants.iter().filter_map(|alias| {
+            lang.lookup_alias(alias)
+                .unwrap()
+                .instantiate(&arg_vals)
+                .map(|blobs| (alias, blobs))
+        });
 
-        let blobs = common::unwrap_at_most_one(matches);
+        let matched = match common::unwrap_at_most_one(matches) {
+            Some(matched) => Some(matched),
+            None => {
+                // Only if there is no exact match do we allow word constants to fill byte slots.
+                let mut coerced = family
+                    .variants
+                    .iter()
+                    .filter_map(|alias| {
+                        lang.lookup_alias(alias)
+                            .unwrap()
+                            .instantiate_coerced(&arg_vals)
+                            .map(|blobs| (alias, blobs))
+                    })
+                    .collect::<Vec<_>>();
+
+                if coerced.len() > 1 {
+                    return Err(Error::InstAmbiguousCoercion(inst, arg_vals));
+                }
+                coerced.pop()
+            }
+        };
 
         // RUSTFIX list candiates when there is no match.
-        let blobs = blobs.ok_or_else(|| Error::InstUnacceptableArgKinds(inst, args))?;
+        let (alias, blobs) = match matched {
+            Some(matched) => matched,
+            None => {
+                let candidates = lang.candidates(family).into_boxed_slice();
+                return Err(Statement::explain_rejected_const(lang, &inst, &arg_vals)
+                    .unwrap_or(Error::InstUnacceptableArgKinds(inst, arg_vals, candidates)));
+            }
+        };
 
-        Ok(blobs.into_iter().map(BinaryElement::Inst).collect())
+        Statement::check_byte_fields(lang, byte_fields, alias, &arg_vals)?;
+
+        let source = InstSource {
+            alias: alias.clone(),
+            args,
+        };
+        Ok(blobs
+            .into_iter()
+            .map(|blob| BinaryElement::Inst(blob, source.clone()))
+            .collect())
 
         // RUSTFIX, (DUPLICATED IN `inst.rs`) Split the `inst.rs` file in half, with all of the basic notions like `RegRef`s,
         // `Half`, `Width`, `Const`, even `ConstBinding`s put somewhere and the stuff only needed to specify
@@ -170,10 +436,123 @@ impl Statement {
     }
 }
 
-pub fn generate(stmts: Vec<Located<Statement>>) -> Result<Vec<BinaryElement>, Located<Error>> {
-    common::accumulate_vecs(
-        stmts
-            .into_iter()
-            .map(|stmt| Ok(stmt.try_map_err(Statement::generate)?)),
-    )
+/// The slot filled by `arg` in the body of an alias with parameters `params`.
+fn alias_slot(params: &[String], arg: Arg<Expr>) -> Result<Slot, Error> {
+    match arg {
+        Arg::Reg(r) => Ok(Slot::Reg(r)),
+        Arg::Const(ConstBinding::Resolved(c)) => Ok(Slot::Const(c)),
+        Arg::Const(ConstBinding::Unresolved(Expr::Name(name))) if params.contains(&name) => {
+            Ok(Slot::Arg(params.iter().position(|p| *p == name).unwrap()))
+        }
+        Arg::Const(ConstBinding::Unresolved(expr)) => Err(Error::AliasArgNotLiteral(expr)),
+    }
+}
+
+/// The `Virtual` instruction given by `stmt` in the body of an alias with parameters `params`.
+fn alias_virtual(params: &[String], stmt: Statement) -> Result<Virtual, Error> {
+    let (inst, args) = match stmt {
+        Statement::Inst(inst, args) => (inst, args),
+        _ => return Err(Error::AliasBodyNotInst),
+    };
+    let args: Vec<Arg<Expr>> = args.into_iter().map(Located::value).collect();
+
+    let idef = UCode::get()
+        .inst_def_iter()
+        .find(|idef| model::sanitize_name(&idef.name) == model::sanitize_name(&inst))
+        .ok_or_else(|| Error::AliasBodyUnknownInst(inst.clone()))?;
+
+    let ius = idef
+        .args
+        .iter()
+        .filter_map(|(iu, kind)| kind.map(|_| iu))
+        .collect::<Vec<_>>();
+    if ius.len() != args.len() {
+        return Err(Error::AliasBodyBadArgs(inst, args));
+    }
+
+    let mut slots: EnumMap<IU, Option<Slot>> = EnumMap::new();
+    for (iu, arg) in ius.into_iter().zip(args.iter().cloned()) {
+        slots[iu] = Some(alias_slot(params, arg)?);
+    }
+
+    Virtual::try_new(idef.opclass.clone(), slots).ok_or(Error::AliasBodyBadArgs(inst, args))
+}
+
+fn define_alias(
+    builder: &mut lang::Builder,
+    name: String,
+    params: Vec<String>,
+    body: Vec<Located<Statement>>,
+) -> Result<(), Located<Error>> {
+    let vinsts = body
+        .into_iter()
+        .map(|stmt| stmt.try_map_err(|stmt| alias_virtual(&params, stmt)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let alias = Alias::try_new(false, name.clone(), vinsts)
+        .and_then(|alias| match alias.arg_count == params.len() {
+            true => Ok(alias),
+            false => Err(alias.arg_count),
+        })
+        .map_err(|idx| Error::AliasParamKind(name, params[idx].clone()))?;
+    Ok(builder.try_register_alias(alias).map_err(Error::Lang)?)
+}
+
+/// The language defined by the `!alias` and `!family` statements `defs`, extending the
+/// built-in one.
+fn define_lang(defs: Vec<Located<Statement>>) -> Result<Lang, Vec<Located<Error>>> {
+    let mut builder = Lang::extend();
+    common::collect_all(
+        defs.into_iter().map(|def| {
+            let here = def.locate(());
+            let res = match def.value() {
+                Statement::Alias(name, params, body) => {
+                    define_alias(&mut builder, name, params, body)
+                }
+                Statement::Family(name, variants) => builder
+                    .try_register_family(model::Family::new(name, variants))
+                    .map_err(|err| here.locate(Error::Lang(err))),
+                _ => unreachable!(),
+            };
+            res.map_err(|err| err.proximate_to_option_loc(here.loc().cloned()))
+        }),
+        MAX_ERRORS,
+    )?;
+    Ok(builder.build())
+}
+
+pub fn generate(
+    stmts: Vec<Located<Statement>>,
+) -> Result<Vec<Located<BinaryElement>>, Vec<Located<Error>>> {
+    // The definitions apply to the whole assembly, so are dealt with first.
+    let (defs, stmts): (Vec<_>, Vec<_>) = stmts.into_iter().partition(|stmt| {
+        matches!(
+            stmt.value_ref(),
+            Statement::Alias(..) | Statement::Family(..)
+        )
+    });
+    let lang = define_lang(defs)?;
+
+    // Likewise a byte field may be named before its `!struct`.
+    let byte_fields = stmts
+        .iter()
+        .filter_map(|stmt| match stmt.value_ref() {
+            Statement::Struct(_, fields, _) => Some(fields),
+            _ => None,
+        })
+        .flatten()
+        .map(Located::value_ref)
+        .filter(|field| field.kind == FieldKind::Byte)
+        .map(|field| field.name.clone())
+        .collect::<HashSet<_>>();
+
+    let elems = common::collect_all(
+        stmts.into_iter().map(|stmt| {
+            Ok(stmt
+                .try_map(|stmt| stmt.generate(&lang, &byte_fields))?
+                .distribute())
+        }),
+        MAX_ERRORS,
+    )?;
+    Ok(elems.into_iter().flatten().collect())
 }
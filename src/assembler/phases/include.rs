@@ -0,0 +1,138 @@
+use super::types::{FileId, Loc, Located, Statement};
+use super::{parse, tokenize};
+use crate::assembler::{
+    source::{self, SourceLoader},
+    Error as AsmError,
+};
+use crate::assets;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    LoadFailed(String, String),
+    IncludeCycle(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::LoadFailed(path, msg) => write!(f, "Could not load '{}': {}", path, msg),
+            Error::IncludeCycle(path) => write!(f, "File '{}' includes itself", path),
+        }
+    }
+}
+
+fn file_id(path: &Path) -> FileId {
+    FileId::from(path.display().to_string())
+}
+
+struct Includer<'a> {
+    loader: &'a dyn SourceLoader,
+    stack: Vec<PathBuf>,
+}
+
+impl<'a> Includer<'a> {
+    fn tokenize_and_parse(
+        source: &str,
+        file: Option<FileId>,
+    ) -> Result<Vec<Located<Statement>>, AsmError> {
+        let in_file = |loc: Loc| loc.in_file(file.clone());
+
+        let tokens = tokenize::tokenize(source).map_err(|errs| {
+            errs.into_iter()
+                .map(|err| err.map_loc(in_file))
+                .collect::<Vec<_>>()
+        })?;
+        let tokens = tokens
+            .into_iter()
+            .map(|line| line.into_iter().map(|tk| tk.map_loc(in_file)).collect())
+            .collect();
+
+        Ok(parse::parse(tokens)?)
+    }
+
+    fn include_all(
+        &mut self,
+        stmts: Vec<Located<Statement>>,
+        dir: &Path,
+    ) -> Result<Vec<Located<Statement>>, AsmError> {
+        let mut out = Vec::new();
+        for stmt in stmts {
+            match stmt.value_ref() {
+                Statement::Include(target) => {
+                    let path = match assets::std_module_name(target) {
+                        Some(_) => PathBuf::from(target),
+                        None => dir.join(target),
+                    };
+                    out.extend(self.include_file(stmt.transfer(path))?);
+                }
+                _ => out.push(stmt),
+            }
+        }
+        Ok(out)
+    }
+
+    fn include_file(
+        &mut self,
+        path: Located<PathBuf>,
+    ) -> Result<Vec<Located<Statement>>, AsmError> {
+        // NOTE: The same file may be named by many paths (e.g. `a.ks` and `./a.ks`), which
+        // must all be recognised as the same by the cycle check.
+        let path = path.map(|path| source::normalize(&path));
+        if self.stack.contains(path.value_ref()) {
+            let name = path.value_ref().display().to_string();
+            return Err(path.transfer(Error::IncludeCycle(name)).into());
+        }
+
+        let source = match source::load(self.loader, path.value_ref()) {
+            Ok(source) => source,
+            Err(err) => {
+                let name = path.value_ref().display().to_string();
+                return Err(path
+                    .transfer(Error::LoadFailed(name, err.to_string()))
+                    .into());
+            }
+        };
+
+        let path = path.value();
+        let stmts = Includer::tokenize_and_parse(&source, Some(file_id(&path)))?;
+        let dir = path.parent().map(Path::to_owned).unwrap_or_default();
+
+        self.stack.push(path);
+        let stmts = self.include_all(stmts, &dir)?;
+        self.stack.pop();
+
+        Ok(stmts)
+    }
+}
+
+/// Tokenize and parse `source` (which is not associated with any path), replacing each
+/// `!include` by the contents of the named file, relative to the current directory (or by the
+/// named module of the standard library, for a name starting with `std:`).
+pub fn include_str(
+    source: &str,
+    loader: &dyn SourceLoader,
+) -> Result<Vec<Located<Statement>>, AsmError> {
+    let mut includer = Includer {
+        loader,
+        stack: Vec::new(),
+    };
+
+    let stmts = Includer::tokenize_and_parse(source, None)?;
+    includer.include_all(stmts, Path::new(""))
+}
+
+/// Load, tokenize and parse the file at `path`, replacing each `!include` by the contents of
+/// the named file, relative to the directory of the including file.
+pub fn include_path(
+    path: &Path,
+    loader: &dyn SourceLoader,
+) -> Result<Vec<Located<Statement>>, AsmError> {
+    let mut includer = Includer {
+        loader,
+        stack: Vec::new(),
+    };
+
+    includer.include_file(Located::from(path.to_owned()))
+}
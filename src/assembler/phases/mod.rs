@@ -1,11 +1,20 @@
 pub mod types;
 
+pub mod expand;
+pub mod expr;
 pub mod generate;
+pub mod include;
+pub mod optimize;
 pub mod parse;
 pub mod resolve;
+pub mod scope;
 pub mod tokenize;
 
+pub use expand::expand;
 pub use generate::generate;
+pub use include::{include_path, include_str};
+pub use optimize::optimize;
 pub use parse::parse;
 pub use resolve::resolve;
+pub use scope::scope;
 pub use tokenize::tokenize;
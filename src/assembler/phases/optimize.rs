@@ -0,0 +1,302 @@
+use super::expr::Expr;
+use super::types::{BinaryElement, Located};
+use crate::assembler::{
+    lang::Lang,
+    model::{Arg, Blob, ConstBinding},
+};
+use crate::common;
+use crate::spec::{
+    defs::usig,
+    types::{
+        hw::{PReg, Word},
+        schema::{InstDef, Width},
+    },
+    ucode::UCode,
+};
+
+/*
+    The optimiser (`kasm -O`): rewrites the generated instructions into equivalent ones which
+    are shorter or cheaper. It runs before any label is placed, so however much the code shrinks
+    every label still ends up at the address of the instruction it precedes.
+
+    There are two passes:
+
+        1.  Flag liveness: an ALU instruction whose flags are always overwritten before they are
+            read is replaced by its no-flags (`NF`) variant. The analysis scans backwards without
+            following jumps, so the flags are taken to be live after any instruction which may
+            jump (or call, or halt), and before any element which is neither an instruction nor
+            a label (e.g. data, or an `!org`).
+
+        2.  Peephole rewrites, each replacing one or two adjacent instructions (never with a label
+            in between) by a shorter sequence which has the same effect on the registers,
+            memory and flags. See `PEEPHOLES`.
+*/
+
+/// An instruction, as the `InstDef` it is an instance of, and its arguments.
+#[derive(Clone)]
+struct Decoded {
+    idef: &'static InstDef,
+    args: Vec<Arg<Expr>>,
+}
+
+impl Decoded {
+    fn new(blob: &Blob<Expr>) -> Self {
+        let idef = UCode::get()
+            .inst_def_iter()
+            .find(|idef| idef.opclass.to_opcodes().any(|oc| oc == blob.inst.opcode))
+            .unwrap();
+
+        let args = idef
+            .args
+            .iter()
+            .filter_map(|(iu, kind)| kind.map(|kind| Arg::disassemble(blob, iu, kind)))
+            .collect();
+
+        Decoded { idef, args }
+    }
+
+    fn with(name: &str, args: Vec<Arg<Expr>>) -> Self {
+        let idef = UCode::get()
+            .inst_def_iter()
+            .find(|idef| idef.name == name)
+            .unwrap();
+        Decoded { idef, args }
+    }
+
+    fn is(&self, name: &str) -> bool {
+        self.idef.name == name
+    }
+
+    fn reads_flags(&self) -> bool {
+        self.idef.uis.iter().any(|ui| usig::does_read_flags(*ui))
+    }
+
+    fn writes_flags(&self) -> bool {
+        self.idef.uis.iter().any(|ui| usig::does_write_flags(*ui))
+    }
+
+    fn may_jump(&self) -> bool {
+        self.idef.uis.iter().any(|ui| usig::does_jump(*ui))
+    }
+
+    /// The variant of this instruction which does not set the flags, if there is one.
+    fn without_flags(&self) -> Option<Decoded> {
+        let name = format!("{}NF", self.idef.name);
+        let idef = UCode::get()
+            .inst_def_iter()
+            .find(|idef| idef.name == name)?;
+        Some(Decoded {
+            idef,
+            args: self.args.clone(),
+        })
+    }
+
+    fn encode(self) -> Vec<Blob<Expr>> {
+        Lang::get()
+            .lookup_alias(&self.idef.name)
+            .and_then(|alias| alias.instantiate(&self.args))
+            .unwrap()
+    }
+}
+
+fn is_const(arg: &Arg<Expr>, val: Word) -> bool {
+    match arg {
+        Arg::Const(ConstBinding::Resolved(c)) => c.to_width() == Width::Word && c.encode() == val,
+        _ => false,
+    }
+}
+
+fn reg(arg: &Arg<Expr>) -> Option<PReg> {
+    match arg {
+        Arg::Reg(_) if arg.to_width() == Width::Word => Some(arg.to_preg()),
+        _ => None,
+    }
+}
+
+fn is_reg(arg: &Arg<Expr>, preg: PReg) -> bool {
+    reg(arg) == Some(preg)
+}
+
+/// A peephole rewrite: given a window of adjacent instructions, the number of them it replaces
+/// and what they are replaced by.
+type Peephole = fn(&[Decoded]) -> Option<(usize, Vec<Decoded>)>;
+
+const PEEPHOLES: [Peephole; 7] = [
+    // `MOV %rx %rx` does nothing.
+    |insts| match insts {
+        [mov, ..] if mov.is("MOV") && reg(&mov.args[0]).is_some() => {
+            match reg(&mov.args[0]) == reg(&mov.args[1]) {
+                true => Some((1, vec![])),
+                false => None,
+            }
+        }
+        _ => None,
+    },
+    // After `MOV %ra %rb`, `MOV %rb %ra` does nothing.
+    |insts| match insts {
+        [first, second, ..] if first.is("MOV") && second.is("MOV") => {
+            match (reg(&first.args[0]), reg(&first.args[1])) {
+                (Some(a), Some(b)) if is_reg(&second.args[0], b) && is_reg(&second.args[1], a) => {
+                    Some((2, vec![first.clone()]))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    },
+    // `MOV $0 %rx` is one word longer than `XORNF %rx %rx`.
+    |insts| match insts {
+        [mov, ..] if mov.is("MOV") && is_const(&mov.args[0], 0) => {
+            let dst = mov.args[1].clone();
+            Some((1, vec![Decoded::with("XORNF", vec![dst.clone(), dst])]))
+        }
+        _ => None,
+    },
+    // Adding (etc.) zero, or masking with all ones, does nothing if the flags are not set.
+    |insts| match insts {
+        [op, ..]
+            if (op.is("ADD2NF") || op.is("SUBNF") || op.is("ORNF") || op.is("XORNF"))
+                && is_const(&op.args[0], 0) =>
+        {
+            Some((1, vec![]))
+        }
+        [op, ..] if op.is("ANDNF") && is_const(&op.args[0], 0xFFFF) => Some((1, vec![])),
+        _ => None,
+    },
+    // `ADD3NF $0 %ra %rb` is just a (shorter) `MOV`.
+    |insts| match insts {
+        [add, ..] if add.is("ADD3NF") => {
+            let src = match (is_const(&add.args[0], 0), is_const(&add.args[1], 0)) {
+                (true, _) => add.args[1].clone(),
+                (_, true) => add.args[0].clone(),
+                _ => return None,
+            };
+            Some((
+                1,
+                vec![Decoded::with("MOV", vec![src, add.args[2].clone()])],
+            ))
+        }
+        _ => None,
+    },
+    // Two `PUSH`es can be done at once (so long as at most one of them pushes a constant).
+    |insts| match insts {
+        [first, second, ..] if first.is("PUSH") && second.is("PUSH") => {
+            let (a, b) = (&first.args[0], &second.args[0]);
+            if (a.is_const() && b.is_const()) || is_reg(a, PReg::SP) || is_reg(b, PReg::SP) {
+                return None;
+            }
+            Some((2, vec![Decoded::with("PUSHx2", vec![a.clone(), b.clone()])]))
+        }
+        _ => None,
+    },
+    // Likewise two `POP`s (into distinct registers).
+    |insts| match insts {
+        [first, second, ..] if first.is("POP") && second.is("POP") => {
+            let (a, b) = (&first.args[0], &second.args[0]);
+            if a == b || is_reg(a, PReg::SP) || is_reg(b, PReg::SP) {
+                return None;
+            }
+            Some((2, vec![Decoded::with("POPx2", vec![a.clone(), b.clone()])]))
+        }
+        _ => None,
+    },
+];
+
+/// Replace each ALU instruction whose flags are dead by its no-flags variant.
+fn select_no_flags(elems: Vec<Located<BinaryElement>>) -> Vec<Located<BinaryElement>> {
+    // Whether the flags may be read after the current element.
+    let mut live = true;
+    let mut out = elems
+        .into_iter()
+        .rev()
+        .map(|elem| {
+            elem.map(|elem| match elem {
+                BinaryElement::Inst(blob, source) => {
+                    let inst = Decoded::new(&blob);
+                    live |= inst.may_jump();
+
+                    let blob = match inst.without_flags() {
+                        Some(nf) if !live && !inst.reads_flags() => {
+                            // Every `InstDef` generates exactly one blob.
+                            common::unwrap_singleton(nf.encode().into_iter())
+                        }
+                        _ => blob,
+                    };
+
+                    live = inst.reads_flags() || (live && !inst.writes_flags());
+                    BinaryElement::Inst(blob, source)
+                }
+                elem @ BinaryElement::LabelDef(_)
+                | elem @ BinaryElement::Equ(..)
+                | elem @ BinaryElement::Export(_)
+                | elem @ BinaryElement::Import(_) => elem,
+                elem => {
+                    live = true;
+                    elem
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    out.reverse();
+    out
+}
+
+/// Apply the first peephole rewrite which matches at the start of `elems` (which must begin with
+/// an instruction), returning the number of elements replaced and their replacements.
+fn rewrite_one(elems: &[Located<BinaryElement>]) -> Option<(usize, Vec<Located<BinaryElement>>)> {
+    let window = elems
+        .iter()
+        .take(2)
+        .map_while(|elem| match elem.value_ref() {
+            BinaryElement::Inst(blob, _) => Some(Decoded::new(blob)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let (count, replacements) = PEEPHOLES.iter().find_map(|peephole| peephole(&window))?;
+
+    // The replacements keep the location (and source) of the first instruction they replace.
+    let first = &elems[0];
+    let source = match first.value_ref() {
+        BinaryElement::Inst(_, source) => source.clone(),
+        _ => unreachable!(),
+    };
+    let replacements = replacements
+        .into_iter()
+        .flat_map(Decoded::encode)
+        .map(|blob| first.locate(BinaryElement::Inst(blob, source.clone())))
+        .collect();
+    Some((count, replacements))
+}
+
+fn apply_peepholes(elems: Vec<Located<BinaryElement>>) -> (bool, Vec<Located<BinaryElement>>) {
+    let mut changed = false;
+    let mut out = Vec::with_capacity(elems.len());
+    let mut idx = 0;
+    while idx < elems.len() {
+        match rewrite_one(&elems[idx..]) {
+            Some((count, replacements)) => {
+                changed = true;
+                out.extend(replacements);
+                idx += count;
+            }
+            None => {
+                out.push(elems[idx].clone());
+                idx += 1;
+            }
+        }
+    }
+    (changed, out)
+}
+
+pub fn optimize(elems: Vec<Located<BinaryElement>>) -> Vec<Located<BinaryElement>> {
+    let mut elems = select_no_flags(elems);
+
+    loop {
+        let (changed, next) = apply_peepholes(elems);
+        elems = next;
+        if !changed {
+            return elems;
+        }
+    }
+}
@@ -1,7 +1,12 @@
-use super::types::Located;
-use super::{tokenize::Token, types::Statement};
-use crate::assembler::model::{Arg, ConstBinding};
+use super::types::{Located, MAX_ERRORS};
+use super::{
+    expr::Expr,
+    tokenize::Token,
+    types::{FieldKind, Statement},
+};
+use crate::assembler::model::{Arg, Const, ConstBinding};
 use crate::common;
+use crate::spec::types::hw::Word;
 use std::fmt::Display;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -9,6 +14,7 @@ pub enum Error {
     UnknownSpecialCommandName(String),
     UnexpectedToken(Token, &'static str),
     UnexpectedEndOfStream(&'static str),
+    MalformedExpression(String, &'static str),
 }
 
 impl Display for Error {
@@ -21,6 +27,9 @@ impl Display for Error {
             Error::UnexpectedEndOfStream(msg) => {
                 write!(f, "Unexpectedly encountered end of stream: {}", msg)
             }
+            Error::MalformedExpression(raw, msg) => {
+                write!(f, "Malformed expression '{}': {}", raw, msg)
+            }
         }
     }
 }
@@ -33,11 +42,50 @@ impl Token {
         }
     }
 
-    pub fn into_arg(self) -> Result<Arg<String>, Error> {
+    pub fn into_name(self) -> Result<String, Error> {
+        match self {
+            Token::Name(n) => Ok(n),
+            tk => Err(Error::UnexpectedToken(tk, "name")),
+        }
+    }
+
+    pub fn into_word(self) -> Result<Word, Error> {
+        match self {
+            Token::Const(Const::Word(w)) | Token::Const(Const::WideWord(w)) => Ok(w),
+            tk => Err(Error::UnexpectedToken(tk, "word constant")),
+        }
+    }
+
+    pub fn into_expr(self) -> Result<Expr, Error> {
+        match self {
+            Token::Const(c) => Ok(Expr::Num(c.encode())),
+            Token::Name(raw) | Token::Expr(raw) => {
+                Expr::parse(&raw).map_err(|msg| Error::MalformedExpression(raw, msg))
+            }
+            tk => Err(Error::UnexpectedToken(tk, "constant expression")),
+        }
+    }
+
+    pub fn into_const_binding(self) -> Result<ConstBinding<Expr>, Error> {
+        match self {
+            Token::Const(c) => Ok(ConstBinding::Resolved(c)),
+            tk @ Token::Name(_) | tk @ Token::Expr(_) => {
+                let expr = tk.into_expr()?;
+                Ok(match expr.fold() {
+                    Some(val) => ConstBinding::Resolved(Const::Word(val)),
+                    None => ConstBinding::Unresolved(expr),
+                })
+            }
+            tk => Err(Error::UnexpectedToken(tk, "constant or label name")),
+        }
+    }
+
+    pub fn into_arg(self) -> Result<Arg<Expr>, Error> {
         match self {
             Token::RegRef(r) => Ok(Arg::Reg(r)),
-            Token::Const(c) => Ok(Arg::Const(ConstBinding::Resolved(c))),
-            Token::Name(n) => Ok(Arg::Const(ConstBinding::Unresolved(n))),
+            Token::Const(_) | Token::Name(_) | Token::Expr(_) => {
+                Ok(Arg::Const(self.into_const_binding()?))
+            }
             tk => Err(Error::UnexpectedToken(tk, "argument")),
         }
     }
@@ -106,14 +154,14 @@ impl Statement {
         tokens: &mut impl Iterator<Item = Located<Token>>,
     ) -> Result<Statement, Located<Error>> {
         match name.as_str() {
-            "warray" => {
-                // RUSTFIX implement, consume numeric tokens which we expect to be word-size
-                Ok(Statement::RawWords(vec![]))
-            }
+            "warray" => Ok(Statement::RawWords(Statement::parse_const_bindings(
+                tokens,
+            )?)),
             "barray" => {
-                // RUSTFIX implement, consume numeric tokens which we expect to be word-size
-                // NOTE we don't have to do parity checking here, just parsing :) That is the job of the generator
-                Ok(Statement::RawBytes(vec![]))
+                // NOTE we don't have to do parity or range checking here, just parsing :) That is the job of the generator
+                Ok(Statement::RawBytes(Statement::parse_const_bindings(
+                    tokens,
+                )?))
             }
             "string" => Ok(Statement::RawString(
                 tokens
@@ -121,17 +169,180 @@ impl Statement {
                     .ok_or(Error::UnexpectedEndOfStream("string literal"))?
                     .try_map_err(|tk| tk.into_string())?,
             )),
+            "bstring" => Ok(Statement::RawBString(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("string literal"))?
+                    .try_map_err(|tk| tk.into_string())?,
+            )),
+            "export" => Ok(Statement::Export(Statement::parse_names(tokens)?)),
+            "import" => Ok(Statement::Import(Statement::parse_names(tokens)?)),
+            "org" => Ok(Statement::Org(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("origin address"))?
+                    .try_map_err(Token::into_expr)?,
+            )),
+            "align" => Ok(Statement::Align(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("alignment"))?
+                    .try_map_err(Token::into_expr)?,
+            )),
+            "fill" => Ok(Statement::Fill(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("fill count"))?
+                    .try_map_err(Token::into_expr)?,
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("fill value"))?
+                    .try_map_err(Token::into_const_binding)?,
+            )),
+            "include" => Ok(Statement::Include(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("include path"))?
+                    .try_map_err(Token::into_string)?,
+            )),
+            "macro" => Ok(Statement::MacroDef(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("macro name"))?
+                    .try_map_err(Token::into_name)?,
+                Statement::parse_names(tokens)?,
+            )),
+            "endm" => Ok(Statement::MacroEnd),
+            "alias" => Ok(Statement::AliasDef(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("alias name"))?
+                    .try_map_err(Token::into_name)?,
+                Statement::parse_names(tokens)?,
+            )),
+            "enda" => Ok(Statement::AliasEnd),
+            "family" => Ok(Statement::Family(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("family name"))?
+                    .try_map_err(Token::into_name)?,
+                Statement::parse_names(tokens)?,
+            )),
+            "struct" => Ok(Statement::StructDef(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("struct name"))?
+                    .try_map_err(Token::into_name)?,
+            )),
+            "ends" => Ok(Statement::StructEnd),
+            "word" => Statement::parse_field(FieldKind::Word, tokens),
+            "byte" => Statement::parse_field(FieldKind::Byte, tokens),
+            "proc" => Statement::parse_proc(tokens),
+            "endp" => Ok(Statement::ProcEnd),
+            "rept" => Ok(Statement::Rept(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("repeat count"))?
+                    .try_map_err(Token::into_word)?,
+            )),
+            "endr" => Ok(Statement::ReptEnd),
+            "if" => Ok(Statement::If(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("condition"))?
+                    .try_map_err(Token::into_expr)?,
+            )),
+            "ifdef" => Ok(Statement::IfDef(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("constant name"))?
+                    .try_map_err(Token::into_name)?,
+            )),
+            "else" => Ok(Statement::Else),
+            "endif" => Ok(Statement::EndIf),
+            "error" => Ok(Statement::UserError(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("error message"))?
+                    .try_map_err(Token::into_string)?,
+            )),
+            "assert" => Ok(Statement::Assert(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("assertion"))?
+                    .try_map_err(Token::into_expr)?,
+            )),
+            "equ" => Ok(Statement::Equ(
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("constant name"))?
+                    .try_map_err(Token::into_name)?,
+                tokens
+                    .next()
+                    .ok_or(Error::UnexpectedEndOfStream("constant expression"))?
+                    .try_map_err(Token::into_expr)?,
+            )),
             _ => Err(Located::from(Error::UnknownSpecialCommandName(
                 name.to_owned(),
             ))),
         }
     }
+
+    /// Parse `NAME [COUNT]`, the rest of a `!word` or `!byte` field.
+    fn parse_field(
+        kind: FieldKind,
+        tokens: &mut impl Iterator<Item = Located<Token>>,
+    ) -> Result<Statement, Located<Error>> {
+        let name = tokens
+            .next()
+            .ok_or(Error::UnexpectedEndOfStream("field name"))?
+            .try_map_err(Token::into_name)?;
+        let count = match tokens.next() {
+            Some(tk) => tk.try_map_err(Token::into_word)?,
+            None => 1,
+        };
+        Ok(Statement::FieldDef(kind, name, count))
+    }
+
+    /// Parse `NAME ARG... [locals LOCAL...]`, the rest of a `!proc`.
+    fn parse_proc(
+        tokens: &mut impl Iterator<Item = Located<Token>>,
+    ) -> Result<Statement, Located<Error>> {
+        let name = tokens
+            .next()
+            .ok_or(Error::UnexpectedEndOfStream("procedure name"))?
+            .try_map_err(Token::into_name)?;
+        let mut args = Statement::parse_names(tokens)?;
+        let locals = match args.iter().position(|arg| arg == "locals") {
+            Some(idx) => args.split_off(idx).into_iter().skip(1).collect(),
+            None => Vec::new(),
+        };
+        Ok(Statement::ProcDef(name, args, locals))
+    }
+
+    fn parse_names(
+        tokens: &mut impl Iterator<Item = Located<Token>>,
+    ) -> Result<Vec<String>, Located<Error>> {
+        tokens.map(|tk| tk.try_map_err(Token::into_name)).collect()
+    }
+
+    fn parse_const_bindings(
+        tokens: &mut impl Iterator<Item = Located<Token>>,
+    ) -> Result<Vec<ConstBinding<Expr>>, Located<Error>> {
+        tokens
+            .map(|tk| tk.try_map_err(Token::into_const_binding))
+            .collect()
+    }
 }
 
-pub fn parse(tokens: Vec<Vec<Located<Token>>>) -> Result<Vec<Located<Statement>>, Located<Error>> {
-    common::accumulate_vecs(
+pub fn parse(
+    tokens: Vec<Vec<Located<Token>>>,
+) -> Result<Vec<Located<Statement>>, Vec<Located<Error>>> {
+    let lines = common::collect_all(
         tokens
             .into_iter()
             .map(|line| Statement::parse_iter(line.into_iter())),
-    )
+        MAX_ERRORS,
+    )?;
+    Ok(lines.into_iter().flatten().collect())
 }
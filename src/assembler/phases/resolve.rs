@@ -1,13 +1,25 @@
-use super::types::BinaryElement;
+use super::expr::{EvalError, Expr};
+use super::types::{BinaryElement, InstSource, LabelName, Located, MAX_ERRORS};
+use crate::assembler::assembly::Assembly;
+use crate::assembler::model::{Const, ConstBinding};
+use crate::assembler::object::{Object, Reloc, RelocKind};
 use crate::common;
-use crate::spec::types::hw::*;
-use std::collections::HashMap;
+use crate::spec::types::{hw::*, schema::Half};
+use std::collections::{BTreeMap, HashMap};
 use std::{convert::TryFrom, fmt::Display};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     DuplicateLabel(String),
     UnknownLabel(String),
+    LabelNotByteAddressable(String, Word),
+    CircularDefinition(String),
+    DivideByZero(String),
+    MisalignedOrigin(Word),
+    BadAlignment(Word),
+    OverlappingRegions(Word, Word),
+    ImageTooLarge(usize),
+    NotRelocatable(&'static str),
 }
 
 impl Display for Error {
@@ -15,6 +27,38 @@ impl Display for Error {
         match self {
             Error::DuplicateLabel(label) => write!(f, "Duplicate definition of label: '{}'", label),
             Error::UnknownLabel(label) => write!(f, "Use of undefined label: '{}'", label),
+            Error::LabelNotByteAddressable(label, addr) => write!(
+                f,
+                "Label '{}' used in a byte array, but its value {:#06X} does not fit in a byte",
+                label, addr
+            ),
+            Error::CircularDefinition(name) => {
+                write!(f, "Constant '{}' is defined in terms of itself", name)
+            }
+            Error::DivideByZero(expr) => {
+                write!(f, "Division by zero while evaluating '{}'", expr)
+            }
+            Error::MisalignedOrigin(addr) => {
+                write!(f, "Origin {:#06X} is not word-aligned", addr)
+            }
+            Error::BadAlignment(align) => {
+                write!(f, "Alignment {} is not a power of two", align)
+            }
+            Error::OverlappingRegions(addr, prev) => write!(
+                f,
+                "Output placed at {:#06X} overlaps output placed at {:#06X}",
+                addr, prev
+            ),
+            Error::ImageTooLarge(end) => write!(
+                f,
+                "Output extends to {:#X}, past the end of the address space",
+                end
+            ),
+            Error::NotRelocatable(directive) => write!(
+                f,
+                "'!{}' cannot be used in a relocatable object, since its base address is unknown",
+                directive
+            ),
         }
     }
 }
@@ -22,47 +66,421 @@ impl Display for Error {
 impl BinaryElement {
     fn words(&self) -> usize {
         match self {
-            BinaryElement::LabelDef(_) => 0,
+            BinaryElement::LabelDef(_)
+            | BinaryElement::Equ(..)
+            | BinaryElement::Export(_)
+            | BinaryElement::Import(_)
+            | BinaryElement::Org(_)
+            | BinaryElement::Align(_) => 0,
+            BinaryElement::Fill(..) => unreachable!("fills are expanded during placement"),
             BinaryElement::Data(raw) => raw.len(),
-            BinaryElement::Inst(blob) => blob.words(),
+            BinaryElement::ByteData(raw) => raw.len() / 2,
+            BinaryElement::Inst(blob, _) => blob.words(),
         }
     }
 
     fn resolve<F>(self, resolver: F) -> Result<Vec<Word>, Error>
     where
-        F: Fn(String) -> Result<Word, Error>,
+        F: Fn(Expr) -> Result<Word, Error>,
     {
         match self {
-            BinaryElement::LabelDef(_) => Ok(vec![]),
-            BinaryElement::Data(raw) => Ok(raw),
-            BinaryElement::Inst(blob) => blob.resolve(resolver),
+            BinaryElement::LabelDef(_)
+            | BinaryElement::Equ(..)
+            | BinaryElement::Export(_)
+            | BinaryElement::Import(_)
+            | BinaryElement::Org(_)
+            | BinaryElement::Align(_) => Ok(vec![]),
+            BinaryElement::Fill(..) => unreachable!("fills are expanded during placement"),
+            BinaryElement::Data(raw) => raw
+                .into_iter()
+                .map(|cb| match cb {
+                    ConstBinding::Resolved(c) => Ok(c.encode()),
+                    ConstBinding::Unresolved(tag) => resolver(tag),
+                })
+                .collect(),
+            BinaryElement::ByteData(raw) => {
+                let bytes = raw
+                    .into_iter()
+                    .map(|cb| BinaryElement::resolve_byte(cb, &resolver))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(bytes_to_words(&bytes).unwrap())
+            }
+            BinaryElement::Inst(blob, _) => blob.resolve(resolver),
         }
     }
+
+    fn resolve_byte<F>(cb: ConstBinding<Expr>, resolver: F) -> Result<Byte, Error>
+    where
+        F: Fn(Expr) -> Result<Word, Error>,
+    {
+        match cb {
+            ConstBinding::Resolved(Const::Byte(b, _)) => Ok(b),
+            ConstBinding::Resolved(Const::Word(_)) | ConstBinding::Resolved(Const::WideWord(_)) => {
+                unreachable!("word constants are narrowed during generation")
+            }
+            ConstBinding::Unresolved(tag) => {
+                let addr = resolver(tag.clone())?;
+                Byte::try_from(addr)
+                    .map_err(|_| Error::LabelNotByteAddressable(tag.to_string(), addr))
+            }
+        }
+    }
+
+    /// The relocations required by the unresolved constants in this element, which is to be
+    /// placed at word `offset` of an object.
+    fn relocs(&self, offset: usize) -> Vec<Reloc> {
+        match self {
+            BinaryElement::Inst(blob, _) => match &blob.binding {
+                Some(ConstBinding::Unresolved(expr)) => {
+                    vec![Reloc::new(offset + 1, RelocKind::Word, expr.clone())]
+                }
+                _ => vec![],
+            },
+            BinaryElement::Data(raw) => raw
+                .iter()
+                .enumerate()
+                .filter_map(|(i, cb)| match cb {
+                    ConstBinding::Unresolved(expr) => {
+                        Some(Reloc::new(offset + i, RelocKind::Word, expr.clone()))
+                    }
+                    ConstBinding::Resolved(_) => None,
+                })
+                .collect(),
+            BinaryElement::ByteData(raw) => raw
+                .iter()
+                .enumerate()
+                .filter_map(|(i, cb)| match cb {
+                    ConstBinding::Unresolved(expr) => {
+                        let half = if i % 2 == 0 { Half::Lo } else { Half::Hi };
+                        Some(Reloc::new(
+                            offset + i / 2,
+                            RelocKind::Byte(half),
+                            expr.clone(),
+                        ))
+                    }
+                    ConstBinding::Resolved(_) => None,
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+pub(crate) struct SymbolTable {
+    labels: HashMap<String, Word>,
+    equs: HashMap<String, Expr>,
+}
+
+/// The value of each symbol, by name.
+pub type Symbols = BTreeMap<String, Word>;
+
+/// An element which occupies space in the output (or is a label), together with its address.
+type Placed = (usize, Located<BinaryElement>);
+
+/// The output of a single element, placed at byte address `addr`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    pub addr: usize,
+    pub words: Vec<Word>,
+    /// If the element was a label, its name.
+    pub label: Option<LabelName>,
+    /// If the element was an instruction, how it was generated.
+    pub inst: Option<InstSource>,
 }
 
-fn build_label_map(elems: &[BinaryElement]) -> Result<HashMap<String, Word>, Error> {
-    let mut label_map = HashMap::new();
-
-    let mut bs = 0;
-    for e in elems {
-        if let BinaryElement::LabelDef(label) = e {
-            if label_map
-                .insert(label.clone(), Word::try_from(bs).unwrap())
-                .is_some()
-            {
-                return Err(Error::DuplicateLabel(label.clone()));
+const ADDRESS_SPACE: usize = Word::MAX as usize + 1;
+
+impl SymbolTable {
+    /// Place each of `elems`, recording the address of each label. Elements which occupy no
+    /// space (other than labels) are dropped, and each `Fill` is expanded into `Data`.
+    fn build(elems: Vec<Located<BinaryElement>>) -> Result<(Self, Vec<Placed>), Located<Error>> {
+        let mut symbols = SymbolTable {
+            labels: HashMap::new(),
+            equs: HashMap::new(),
+        };
+
+        // Equs are collected up front, so that they may be used by any placement directive.
+        for e in elems.iter() {
+            if let BinaryElement::Equ(name, expr) = e.value_ref() {
+                if symbols.equs.insert(name.clone(), expr.clone()).is_some() {
+                    return Err(e.locate(Error::DuplicateLabel(name.clone())));
+                }
+            }
+        }
+
+        let mut placed = Vec::new();
+        let mut addr = 0;
+        for e in elems {
+            let here = e.locate(());
+            match e.value_ref() {
+                BinaryElement::LabelDef(label) => {
+                    if symbols.defines(label) {
+                        return Err(e.locate(Error::DuplicateLabel(label.clone())));
+                    }
+                    let val =
+                        Word::try_from(addr).map_err(|_| e.locate(Error::ImageTooLarge(addr)))?;
+                    symbols.labels.insert(label.clone(), val);
+                    placed.push((addr, e));
+                }
+                BinaryElement::Org(expr) => {
+                    let org = symbols.eval(expr, &[]).map_err(|err| e.locate(err))?;
+                    if org % 2 != 0 {
+                        return Err(e.locate(Error::MisalignedOrigin(org)));
+                    }
+                    addr = usize::from(org);
+                }
+                BinaryElement::Align(expr) => {
+                    let align = symbols.eval(expr, &[]).map_err(|err| e.locate(err))?;
+                    if !align.is_power_of_two() {
+                        return Err(e.locate(Error::BadAlignment(align)));
+                    }
+                    let align = usize::from(align);
+                    addr = (addr + align - 1) & !(align - 1);
+                }
+                BinaryElement::Fill(count, value) => {
+                    let count = symbols.eval(count, &[]).map_err(|err| e.locate(err))?;
+                    let count = usize::from(count);
+                    if count != 0 {
+                        let data = BinaryElement::Data(vec![value.clone(); count]);
+                        placed.push((addr, e.transfer(data)));
+                        addr += 2 * count;
+                    }
+                }
+                elem if elem.words() != 0 => {
+                    let words = elem.words();
+                    placed.push((addr, e));
+                    addr += 2 * words;
+                }
+                _ => (),
             }
+
+            if addr > ADDRESS_SPACE {
+                return Err(here.transfer(Error::ImageTooLarge(addr)));
+            }
+        }
+
+        Ok((symbols, placed))
+    }
+
+    pub(crate) fn new(labels: HashMap<String, Word>, equs: HashMap<String, Expr>) -> Self {
+        SymbolTable { labels, equs }
+    }
+
+    pub(crate) fn insert_equ(&mut self, name: String, expr: Expr) {
+        self.equs.insert(name, expr);
+    }
+
+    pub(crate) fn defines(&self, name: &str) -> bool {
+        self.labels.contains_key(name) || self.equs.contains_key(name)
+    }
+
+    fn lookup(&self, name: &str, visiting: &[&str]) -> Result<Word, Error> {
+        if let Some(addr) = self.labels.get(name) {
+            return Ok(*addr);
+        }
+
+        let expr = self
+            .equs
+            .get(name)
+            .ok_or_else(|| Error::UnknownLabel(name.to_owned()))?;
+
+        if visiting.contains(&name) {
+            return Err(Error::CircularDefinition(name.to_owned()));
         }
 
-        bs += 2 * e.words();
+        let visiting = [visiting, &[name]].concat();
+        self.eval(expr, &visiting)
     }
 
-    Ok(label_map)
+    /// The value of every label, and of every `!equ` constant which can be evaluated.
+    fn values(&self) -> Symbols {
+        let labels = self.labels.iter().map(|(name, addr)| (name.clone(), *addr));
+        let equs = self
+            .equs
+            .keys()
+            .filter_map(|name| Some((name.clone(), self.lookup(name, &[]).ok()?)));
+        labels.chain(equs).collect()
+    }
+
+    pub(crate) fn eval(&self, expr: &Expr, visiting: &[&str]) -> Result<Word, Error> {
+        expr.eval(&|name| self.lookup(name, visiting))
+            .map_err(|err| match err {
+                EvalError::Symbol(err) => err,
+                EvalError::DivideByZero => Error::DivideByZero(expr.to_string()),
+            })
+    }
+}
+
+/// Check that no two of the `placed` elements overlap, reporting an overlap at the location
+/// of the element which starts later.
+fn check_overlaps(placed: &[Placed]) -> Result<(), Located<Error>> {
+    let mut regions = placed
+        .iter()
+        .map(|(addr, e)| (*addr, addr + 2 * e.value_ref().words(), e))
+        .filter(|(start, end, _)| start != end)
+        .collect::<Vec<_>>();
+    regions.sort_unstable_by_key(|(start, end, _)| (*start, *end));
+
+    for pair in regions.windows(2) {
+        let ((prev, prev_end, _), (addr, _, e)) = (pair[0], pair[1]);
+        if addr < prev_end {
+            return Err(e.locate(Error::OverlappingRegions(
+                Word::try_from(addr).unwrap(),
+                Word::try_from(prev).unwrap(),
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve `elems`, retaining the address and source location of the output of each element
+/// (and of each label), in source order. Once every element has been placed, each which refers
+/// to an unknown label (or otherwise fails to resolve) is reported.
+pub fn place(
+    elems: Vec<Located<BinaryElement>>,
+) -> Result<Vec<Located<Placement>>, Vec<Located<Error>>> {
+    Ok(resolve_assembly(elems)?.into_placements())
+}
+
+/// Resolve `elems` as `place` does, also recording the value of each symbol (as for
+/// `SymbolTable::values`).
+pub fn resolve_assembly(
+    elems: Vec<Located<BinaryElement>>,
+) -> Result<Assembly, Vec<Located<Error>>> {
+    let (symbols, placed) = SymbolTable::build(elems).map_err(|err| vec![err])?;
+    check_overlaps(&placed).map_err(|err| vec![err])?;
+
+    let resolver = |expr: Expr| symbols.eval(&expr, &[]);
+    let placements = placed.into_iter().map(|(addr, e)| {
+        let (label, inst) = match e.value_ref() {
+            BinaryElement::LabelDef(label) => (Some(label.clone()), None),
+            BinaryElement::Inst(_, source) => (None, Some(source.clone())),
+            _ => (None, None),
+        };
+        let words = e
+            .value_ref()
+            .clone()
+            .resolve(resolver)
+            .map_err(|err| e.locate(err))?;
+        Ok(e.transfer(Placement {
+            addr,
+            words,
+            label,
+            inst,
+        }))
+    });
+    let placements = common::collect_all(placements, MAX_ERRORS)?;
+    Ok(Assembly::new(placements, symbols.values()))
+}
+
+/// Write each placement at its address, filling any gaps with zeros.
+pub fn image(placements: &[Located<Placement>]) -> Vec<Word> {
+    let end = placements
+        .iter()
+        .map(|p| p.value_ref().addr / 2 + p.value_ref().words.len())
+        .max()
+        .unwrap_or(0);
+    let mut image = vec![0; end];
+
+    for p in placements.iter().map(Located::value_ref) {
+        image[p.addr / 2..][..p.words.len()].copy_from_slice(&p.words);
+    }
+
+    image
+}
+
+pub fn resolve(elems: Vec<Located<BinaryElement>>) -> Result<Vec<Word>, Vec<Located<Error>>> {
+    Ok(image(&place(elems)?))
 }
 
-pub fn resolve(elems: Vec<BinaryElement>) -> Result<Vec<Word>, Error> {
-    let label_map = build_label_map(&elems)?;
-    let label_resolver = |tag| label_map.get(&tag).copied().ok_or(Error::UnknownLabel(tag));
+/// Resolve `elems` into a relocatable object, rather than a flat binary. Every constant which
+/// is not already resolved becomes a relocation, since its value may depend on the base address
+/// of the object, and so is only known once the object has been linked.
+pub fn resolve_relocatable(elems: Vec<Located<BinaryElement>>) -> Result<Object, Located<Error>> {
+    let mut obj = Object::default();
+    let mut exports = Vec::new();
+    let mut imports = Vec::new();
+    let mut equs = Vec::new();
+    for e in elems.iter() {
+        match e.value_ref() {
+            BinaryElement::Export(names) => {
+                exports.extend(names.iter().map(|name| e.locate(name.clone())))
+            }
+            BinaryElement::Import(names) => {
+                imports.extend(names.iter().map(|name| e.locate(name.clone())))
+            }
+            BinaryElement::Equ(name, expr) => {
+                obj.equs.push((name.clone(), expr.clone()));
+                equs.push(e.locate(expr.clone()));
+            }
+            BinaryElement::Org(_) => return Err(e.locate(Error::NotRelocatable("org"))),
+            BinaryElement::Align(_) => return Err(e.locate(Error::NotRelocatable("align"))),
+            _ => (),
+        }
+    }
+    obj.exports = exports
+        .iter()
+        .map(|name| name.value_ref().clone())
+        .collect();
+    obj.imports = imports
+        .iter()
+        .map(|name| name.value_ref().clone())
+        .collect();
+
+    // NOTE: Without `!org` or `!align` every element is placed directly after the previous one,
+    // so the object is contiguous.
+    let (symbols, placed) = SymbolTable::build(elems)?;
+
+    if let Some(name) = imports
+        .iter()
+        .find(|name| symbols.defines(name.value_ref()))
+    {
+        return Err(name.locate(Error::DuplicateLabel(name.value_ref().clone())));
+    }
+
+    if let Some(name) = exports
+        .iter()
+        .find(|name| !symbols.defines(name.value_ref()))
+    {
+        return Err(name.locate(Error::UnknownLabel(name.value_ref().clone())));
+    }
+
+    let check_names = |expr: &Expr| match expr
+        .names()
+        .into_iter()
+        .find(|name| !symbols.defines(name) && !obj.imports.iter().any(|imp| imp == name))
+    {
+        Some(name) => Err(Error::UnknownLabel(name.to_owned())),
+        None => Ok(()),
+    };
+
+    for equ in equs.iter() {
+        check_names(equ.value_ref()).map_err(|err| equ.locate(err))?;
+    }
+
+    let mut relocs = Vec::new();
+    let mut words = Vec::new();
+    for (_, e) in placed {
+        let e_relocs = e.value_ref().relocs(words.len());
+        for reloc in e_relocs.iter() {
+            check_names(&reloc.expr).map_err(|err| e.locate(err))?;
+        }
+        relocs.extend(e_relocs);
+
+        let here = e.locate(());
+        words.extend(
+            e.value()
+                .resolve(|_| Ok(0))
+                .map_err(|err| here.transfer(err))?,
+        );
+    }
+
+    let mut labels = symbols.labels.into_iter().collect::<Vec<_>>();
+    labels.sort_by_key(|(name, offset)| (*offset, name.clone()));
 
-    common::accumulate_vecs(elems.into_iter().map(|be| be.resolve(label_resolver)))
+    obj.words = words;
+    obj.labels = labels;
+    obj.relocs = relocs;
+    Ok(obj)
 }
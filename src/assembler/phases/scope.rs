@@ -0,0 +1,313 @@
+use super::expr::Expr;
+use super::types::{self, Located, Statement, MAX_ERRORS};
+use crate::assembler::model::{Arg, ConstBinding};
+use crate::common;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::iter;
+
+/*
+    Label scoping: gives every local and anonymous label a unique global name, so that
+    resolution need only deal with a single flat namespace.
+
+        -   A local label (or `!equ` constant) is one whose name begins with a `.`, and belongs to
+            the nearest preceding global label: `.loop` following `main:` is `main.loop`, and
+            can also be referred to by that name from anywhere else.
+
+        -   An anonymous label is one whose name is a decimal number, such as `1:`. It may be
+            defined any number of times, and is referred to as `1f` (the next definition of `1`
+            after the reference) or `1b` (the closest definition at or before the reference).
+*/
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    LocalLabelOutsideScope(String),
+    NoSuchAnonymousLabel(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::LocalLabelOutsideScope(name) => write!(
+                f,
+                "Local label '{}' is used before any global label is defined",
+                name
+            ),
+            Error::NoSuchAnonymousLabel(name) => {
+                write!(f, "No anonymous label found for reference '{}'", name)
+            }
+        }
+    }
+}
+
+/// Is `name` the name of an anonymous label, that is, a decimal number?
+pub fn is_anonymous_label(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_digit())
+}
+
+/// If `name` is a reference to an anonymous label (like `1f` or `1b`), the label's name and
+/// whether the reference is forward.
+pub fn anonymous_ref(name: &str) -> Option<(&str, bool)> {
+    let (label, dir) = name.split_at(name.len().checked_sub(1)?);
+    if !is_anonymous_label(label) {
+        return None;
+    }
+
+    match dir {
+        "f" => Some((label, true)),
+        "b" => Some((label, false)),
+        _ => None,
+    }
+}
+
+/// An appearance of a symbol in the source: its name as written, the global name which it
+/// refers to, and whether it is being defined there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    pub name: String,
+    pub global: String,
+    pub is_def: bool,
+}
+
+impl Occurrence {
+    fn new(name: &str, global: String, is_def: bool) -> Self {
+        Occurrence {
+            // NOTE: Strip the suffix given to the labels private to a macro by the expander.
+            name: name.split('$').next().unwrap().to_owned(),
+            global,
+            is_def,
+        }
+    }
+}
+
+fn is_local(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+struct Scoper {
+    /// The number of times each anonymous label is defined in total.
+    totals: HashMap<String, usize>,
+    /// The number of times each anonymous label has been defined so far.
+    seen: HashMap<String, usize>,
+    global: Option<String>,
+}
+
+impl Scoper {
+    fn new(stmts: &[Located<Statement>]) -> Self {
+        let mut totals = HashMap::new();
+        for stmt in stmts {
+            if let Statement::LabelDef(label) = stmt.value_ref() {
+                if is_anonymous_label(label) {
+                    *totals.entry(label.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        Scoper {
+            totals,
+            seen: HashMap::new(),
+            global: None,
+        }
+    }
+
+    fn anonymous_name(label: &str, idx: usize) -> String {
+        // NOTE: '$' can never appear in a user-specified name, so these are unique.
+        format!("{}${}", label, idx)
+    }
+
+    /// Enter the definition of `label`, returning its global name.
+    fn define(&mut self, label: String) -> Result<String, Error> {
+        if is_anonymous_label(&label) {
+            let seen = self.seen.entry(label.clone()).or_insert(0);
+            *seen += 1;
+            return Ok(Scoper::anonymous_name(&label, *seen - 1));
+        }
+
+        // NOTE: Labels which are private to a macro or `!rept` body (those renamed by the
+        // expander) do not start a new scope, so that using a macro does not change the meaning
+        // of the local labels around it.
+        if !is_local(&label) && !label.contains('$') {
+            self.global = Some(label.clone());
+        }
+
+        self.rename(&label)
+    }
+
+    /// The global name of the symbol referred to by `name` at this point.
+    fn rename(&self, name: &str) -> Result<String, Error> {
+        if let Some((label, forward)) = anonymous_ref(name) {
+            let seen = self.seen.get(label).copied().unwrap_or(0);
+            let total = self.totals.get(label).copied().unwrap_or(0);
+            let idx = match forward {
+                true if seen < total => Some(seen),
+                false => seen.checked_sub(1),
+                _ => None,
+            };
+
+            return idx
+                .map(|idx| Scoper::anonymous_name(label, idx))
+                .ok_or_else(|| Error::NoSuchAnonymousLabel(name.to_owned()));
+        }
+
+        if !is_local(name) {
+            return Ok(name.to_owned());
+        }
+
+        match &self.global {
+            Some(global) => Ok(format!("{}{}", global, name)),
+            None => Err(Error::LocalLabelOutsideScope(name.to_owned())),
+        }
+    }
+
+    fn rename_expr(&self, expr: Expr) -> Result<Expr, Error> {
+        for name in expr.names() {
+            self.rename(name)?;
+        }
+        Ok(expr.map_names(&|name| self.rename(name).ok().map(Expr::Name)))
+    }
+
+    fn rename_binding(&self, cb: ConstBinding<Expr>) -> Result<ConstBinding<Expr>, Error> {
+        match cb {
+            ConstBinding::Unresolved(expr) => Ok(ConstBinding::Unresolved(self.rename_expr(expr)?)),
+            cb => Ok(cb),
+        }
+    }
+
+    fn rename_all<T, F>(&self, ts: Vec<T>, f: F) -> Result<Vec<T>, Error>
+    where
+        F: Fn(&Self, T) -> Result<T, Error>,
+    {
+        ts.into_iter().map(|t| f(self, t)).collect()
+    }
+
+    fn binding_names(cb: &ConstBinding<Expr>) -> Vec<&str> {
+        match cb {
+            ConstBinding::Unresolved(expr) => expr.names(),
+            ConstBinding::Resolved(_) => vec![],
+        }
+    }
+
+    /// The symbols named by `stmt` (entering the label it defines, if any), ignoring any
+    /// which cannot be scoped.
+    fn occurrences(&mut self, stmt: &Located<Statement>) -> Vec<Located<Occurrence>> {
+        // Each field of a `!struct` is defined on its own line.
+        if let Statement::Struct(name, fields, _) = stmt.value_ref() {
+            let size = types::struct_size_name(name);
+            return iter::once(stmt.locate(Occurrence::new(&size, size.clone(), true)))
+                .chain(fields.iter().map(|field| {
+                    let name = &field.value_ref().name;
+                    field.locate(Occurrence::new(name, name.clone(), true))
+                }))
+                .collect();
+        }
+
+        let def = match stmt.value_ref() {
+            Statement::LabelDef(label) => Some((label, self.define(label.clone()))),
+            Statement::Equ(name, _) => Some((name, self.rename(name))),
+            _ => None,
+        };
+
+        let mut occs = Vec::new();
+        if let Some((name, Ok(global))) = def {
+            occs.push(stmt.locate(Occurrence::new(name, global, true)));
+        }
+
+        // Only the arguments of an instruction have locations of their own.
+        let refs = match stmt.value_ref() {
+            Statement::Equ(_, expr) | Statement::Org(expr) | Statement::Align(expr) => {
+                vec![stmt.locate(expr.names())]
+            }
+            Statement::Export(names) | Statement::Import(names) => {
+                vec![stmt.locate(names.iter().map(String::as_str).collect())]
+            }
+            Statement::Inst(_, args) => args
+                .iter()
+                .map(|arg| match arg.value_ref() {
+                    Arg::Const(cb) => arg.locate(Scoper::binding_names(cb)),
+                    Arg::Reg(_) => arg.locate(vec![]),
+                })
+                .collect(),
+            Statement::RawWords(cbs) | Statement::RawBytes(cbs) => {
+                vec![stmt.locate(cbs.iter().flat_map(Scoper::binding_names).collect())]
+            }
+            Statement::Fill(count, value) => {
+                vec![stmt.locate([count.names(), Scoper::binding_names(value)].concat())]
+            }
+            _ => vec![],
+        };
+
+        for names in refs {
+            for name in names.value_ref() {
+                if let Ok(global) = self.rename(name) {
+                    occs.push(names.locate(Occurrence::new(name, global, false)));
+                }
+            }
+        }
+        occs
+    }
+
+    fn scope(&mut self, stmt: Statement) -> Result<Statement, Error> {
+        Ok(match stmt {
+            Statement::LabelDef(label) => Statement::LabelDef(self.define(label)?),
+            Statement::Equ(name, expr) => {
+                Statement::Equ(self.rename(&name)?, self.rename_expr(expr)?)
+            }
+            Statement::Export(names) => {
+                Statement::Export(self.rename_all(names, |s, name| s.rename(&name))?)
+            }
+            Statement::Import(names) => {
+                Statement::Import(self.rename_all(names, |s, name| s.rename(&name))?)
+            }
+            Statement::Inst(name, args) => Statement::Inst(
+                name,
+                args.into_iter()
+                    .map(|arg| {
+                        let here = arg.locate(());
+                        match arg.value() {
+                            Arg::Const(cb) => {
+                                Ok(here.transfer(Arg::Const(self.rename_binding(cb)?)))
+                            }
+                            arg => Ok(here.transfer(arg)),
+                        }
+                    })
+                    .collect::<Result<_, Error>>()?,
+            ),
+            Statement::RawWords(words) => {
+                Statement::RawWords(self.rename_all(words, Scoper::rename_binding)?)
+            }
+            Statement::RawBytes(bytes) => {
+                Statement::RawBytes(self.rename_all(bytes, Scoper::rename_binding)?)
+            }
+            Statement::Org(addr) => Statement::Org(self.rename_expr(addr)?),
+            Statement::Align(align) => Statement::Align(self.rename_expr(align)?),
+            Statement::Fill(count, value) => {
+                Statement::Fill(self.rename_expr(count)?, self.rename_binding(value)?)
+            }
+            stmt => stmt,
+        })
+    }
+}
+
+/// Replace the name of each local or anonymous label (and each reference to one) by a unique
+/// global name.
+pub fn scope(
+    stmts: Vec<Located<Statement>>,
+) -> Result<Vec<Located<Statement>>, Vec<Located<Error>>> {
+    let mut scoper = Scoper::new(&stmts);
+    common::collect_all(
+        stmts
+            .into_iter()
+            .map(|stmt| stmt.try_map(|stmt| scoper.scope(stmt))),
+        MAX_ERRORS,
+    )
+}
+
+/// Each appearance of a symbol in `stmts` (which have been expanded, but not yet scoped), at the
+/// location of the statement (or instruction argument) in which it appears.
+pub fn occurrences(stmts: &[Located<Statement>]) -> Vec<Located<Occurrence>> {
+    let mut scoper = Scoper::new(stmts);
+    stmts
+        .iter()
+        .flat_map(|stmt| scoper.occurrences(stmt))
+        .collect()
+}
@@ -1,8 +1,9 @@
-use super::types::{Loc, Located};
+use super::scope;
+use super::types::{Loc, Located, MAX_ERRORS};
 use crate::assembler::model::{Const, RegRef};
 use crate::common;
 use crate::spec::types::{
-    hw::{word_from_i64_wrapping, Byte, PReg},
+    hw::{word_from_i64_wrapping, Byte, PReg, Word},
     schema::{Half, Width},
 };
 use std::convert::TryFrom;
@@ -16,6 +17,7 @@ use strum::IntoEnumIterator;
 pub enum Error {
     MalformedToken(String, &'static str),
     UnterminatedStringLiteral,
+    UnterminatedCharLiteral,
 }
 
 impl From<ParseIntError> for Error {
@@ -38,6 +40,9 @@ impl Display for Error {
             Error::UnterminatedStringLiteral => {
                 write!(f, "Encountered unterminated string literal")
             }
+            Error::UnterminatedCharLiteral => {
+                write!(f, "Encountered unterminated character literal")
+            }
         }
     }
 }
@@ -56,6 +61,7 @@ pub enum Token {
 
     String(String),
     Name(String),
+    Expr(String),
 }
 
 impl Display for Token {
@@ -69,6 +75,7 @@ impl Display for Token {
 
             Token::String(s) => write!(f, "String({})", s),
             Token::Name(s) => write!(f, "Name({})", s),
+            Token::Expr(s) => write!(f, "Expr({})", s),
         }
     }
 }
@@ -128,22 +135,22 @@ impl<'a> CommandChar<'a> {
 type CommandCharHandler = (CommandChar<'static>, fn(&str) -> Result<Token, Error>);
 
 impl Token {
-    const COMMAND_CHARS: [CommandCharHandler; 9] = [
+    // NOTE: String literals are matched first, since they may contain any other character.
+    const COMMAND_CHARS: [CommandCharHandler; 10] = [
+        (CommandChar::Starting("\""), Token::parse_string),
+        (CommandChar::Starting("'"), Token::parse_char),
         (CommandChar::Containing(" "), Token::parse_error),
         (CommandChar::Containing("#"), Token::parse_error),
         (CommandChar::Ending(":"), Token::parse_label_def),
         (CommandChar::Starting("!"), Token::parse_special),
         (CommandChar::Starting("%"), Token::parse_reg_ref),
-        (CommandChar::Starting("$"), |s| {
-            Token::parse_numeric(s, Width::Word)
-        }),
+        (CommandChar::Starting("$"), Token::parse_word),
         (CommandChar::Starting("l$"), |s| {
             Token::parse_numeric(s, Width::Byte(Half::Lo))
         }),
         (CommandChar::Starting("h$"), |s| {
             Token::parse_numeric(s, Width::Byte(Half::Hi))
         }),
-        (CommandChar::Starting("\""), Token::parse_string),
     ];
 
     fn parse(raw: &str) -> Result<Self, Error> {
@@ -217,37 +224,84 @@ impl Token {
         Ok(Token::RegRef(RegRef::new(preg, width)))
     }
 
+    /// A `$` introduces either a numeric literal or, failing that, a constant expression (which
+    /// is parsed later, when we know what it will be used for).
+    fn parse_word(raw: &str) -> Result<Self, Error> {
+        match Token::parse_numeric(raw, Width::Word) {
+            Ok(tk) => Ok(tk),
+            Err(err) => {
+                let starts_numeric = raw.chars().next().map_or(true, |c| c.is_ascii_digit());
+                if starts_numeric
+                    && raw.chars().all(|c| c.is_ascii_alphanumeric())
+                    && scope::anonymous_ref(raw).is_none()
+                {
+                    Err(err)
+                } else {
+                    Ok(Token::Expr(raw.to_owned()))
+                }
+            }
+        }
+    }
+
+    /// Is the literal `digits` (in base `radix`), with value `val`, written with more digits than
+    /// a byte needs? This is so when `val` fits in a byte, but a) the largest number with as many
+    /// digits does not, and b) `val` would still be expressible without its leading digit.
+    fn is_over_qualified(digits: &str, radix: u32, val: Word) -> bool {
+        let max_with = |n: usize| {
+            u32::try_from(n)
+                .ok()
+                .and_then(|n| u64::from(radix).checked_pow(n))
+                .map(|p| p - 1)
+        };
+
+        let n = digits.len();
+        Byte::try_from(val).is_ok()
+            && !matches!(max_with(n), Some(max) if max <= u64::from(Byte::MAX))
+            && !matches!(max_with(n - 1), Some(max) if u64::from(val) > max)
+    }
+
     fn parse_numeric(raw: &str, width: Width) -> Result<Self, Error> {
-        let val = if raw.starts_with("0x") {
-            i64::from_str_radix(&raw[2..], 16)
-        } else if raw.starts_with("0o") {
-            i64::from_str_radix(&raw[2..], 8)
-        } else if raw.starts_with("0b") {
-            i64::from_str_radix(&raw[2..], 2)
-        } else {
-            i64::from_str_radix(raw, 10)
-        }?;
-
-        let val = word_from_i64_wrapping(val)?;
+        let (digits, radix) = [("0x", 16), ("0o", 8), ("0b", 2)]
+            .iter()
+            .find_map(|(prefix, radix)| raw.strip_prefix(prefix).map(|digits| (digits, *radix)))
+            .unwrap_or((raw, 10));
+
+        let val = word_from_i64_wrapping(i64::from_str_radix(digits, radix)?)?;
 
         match width {
             // RUSTFIX this would be a perfect place to add messages to the errors these give off using
             // the `anyhow` crate, since you can't distinguish between `Word` and `Byte` from the message...
+            Width::Word if Token::is_over_qualified(digits, radix, val) => {
+                Ok(Token::Const(Const::WideWord(val)))
+            }
             Width::Word => Ok(Token::Const(Const::Word(val))),
             Width::Byte(half) => Ok(Token::Const(Const::Byte(Byte::try_from(val)?, half))),
         }
     }
 
     fn parse_string(raw: &str) -> Result<Self, Error> {
-        if !raw.ends_with('"') {
-            return Err(Error::MalformedToken(
+        let inner = raw.strip_suffix('"').ok_or_else(|| {
+            Error::MalformedToken(
                 raw.to_owned(),
                 "no terminating '\"' while parsing string literal",
-            ));
-        }
+            )
+        })?;
 
-        // RUSTFIX this is unicode unsafe
-        Ok(Token::String(raw[0..raw.len() - 1].to_owned()))
+        let string = unescape(inner).map_err(|msg| Error::MalformedToken(raw.to_owned(), msg))?;
+        Ok(Token::String(string))
+    }
+
+    fn parse_char(raw: &str) -> Result<Self, Error> {
+        let inner = raw.strip_suffix('\'').ok_or_else(|| {
+            Error::MalformedToken(
+                raw.to_owned(),
+                "no terminating \"'\" while parsing character literal",
+            )
+        })?;
+
+        let val =
+            parse_char_literal(inner).map_err(|msg| Error::MalformedToken(raw.to_owned(), msg))?;
+        Ok(Token::Const(Const::Word(val)))
     }
 
     fn parse_special(raw: &str) -> Result<Self, Error> {
@@ -274,6 +328,53 @@ impl Token {
     }
 }
 
+/// Replace each escape sequence in `raw` (the text between the quotes of a string or character
+/// literal) by the character it stands for. The escape `\xNN` stands for the character with code
+/// `NN`, which is assembled as the single byte `NN`.
+fn unescape(raw: &str) -> Result<String, &'static str> {
+    let mut out = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('\'') => '\'',
+            Some('x') => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                if hex.len() != 2 {
+                    return Err("expected two hex digits in '\\x' escape");
+                }
+                char::from(
+                    u8::from_str_radix(&hex, 16)
+                        .map_err(|_| "expected two hex digits in '\\x' escape")?,
+                )
+            }
+            _ => return Err("unknown escape sequence"),
+        });
+    }
+    Ok(out)
+}
+
+/// The value of a character literal, given the text between its quotes.
+pub(super) fn parse_char_literal(raw: &str) -> Result<Word, &'static str> {
+    let unescaped = unescape(raw)?;
+    let mut chars = unescaped.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => u8::try_from(c)
+            .map(Word::from)
+            .map_err(|_| "character does not fit in a byte"),
+        _ => Err("character literals must contain exactly one character"),
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum RawToken<'a> {
     Value(Located<&'a str>),
@@ -310,7 +411,11 @@ impl TerminatorKind {
 enum SeekMode {
     Comment,
     Whitespace,
-    StringLiteral,
+    /// A string or character literal, ending at the next unescaped `delim`.
+    Literal {
+        delim: char,
+        escaped: bool,
+    },
     Name,
 }
 
@@ -318,7 +423,10 @@ impl From<char> for SeekMode {
     fn from(c: char) -> Self {
         match c {
             RawToken::COMMENT_CHAR => SeekMode::Comment,
-            RawToken::STRING_LITERAL_CHAR => SeekMode::StringLiteral,
+            RawToken::STRING_LITERAL_CHAR | RawToken::CHAR_LITERAL_CHAR => SeekMode::Literal {
+                delim: c,
+                escaped: false,
+            },
             c => {
                 if c.is_whitespace() {
                     SeekMode::Whitespace
@@ -332,14 +440,25 @@ impl From<char> for SeekMode {
 
 impl SeekMode {
     // RUSTFIX This is much better than it was: can it be simplified any further?
-    fn should_terminate(&self, cur: Option<char>) -> Result<Option<SeekEnd>, Error> {
+    fn should_terminate(&mut self, cur: Option<char>) -> Result<Option<SeekEnd>, Error> {
         match (self, TerminatorKind::from_char(cur)) {
             (SeekMode::Comment, _) => Ok(Some(SeekEnd::SkipEverything)),
             (SeekMode::Whitespace, Some(TerminatorKind::Whitespace)) => Ok(None),
             (SeekMode::Whitespace, _) => Ok(Some(SeekEnd::Skip)),
-            (SeekMode::StringLiteral, term_kind) => match (cur, term_kind) {
-                (Some(RawToken::STRING_LITERAL_CHAR), _) => Ok(Some(SeekEnd::AdvanceOne)),
-                (_, Some(TerminatorKind::Hard)) => Err(Error::UnterminatedStringLiteral),
+            (SeekMode::Literal { delim, .. }, Some(TerminatorKind::Hard)) => match *delim {
+                RawToken::STRING_LITERAL_CHAR => Err(Error::UnterminatedStringLiteral),
+                _ => Err(Error::UnterminatedCharLiteral),
+            },
+            (SeekMode::Literal { delim, escaped }, _) => match cur {
+                _ if *escaped => {
+                    *escaped = false;
+                    Ok(None)
+                }
+                Some('\\') => {
+                    *escaped = true;
+                    Ok(None)
+                }
+                Some(c) if c == *delim => Ok(Some(SeekEnd::AdvanceOne)),
                 _ => Ok(None),
             },
             (SeekMode::Name, Some(_)) => Ok(Some(SeekEnd::Current)),
@@ -390,6 +509,7 @@ impl<'a> RawToken<'a> {
     const COMMENT_CHAR: char = '#';
     const NEWLINE_CHAR: char = '\n';
     const STRING_LITERAL_CHAR: char = '"';
+    const CHAR_LITERAL_CHAR: char = '\'';
 
     fn consume_one<'b>(
         line_no: usize,
@@ -399,7 +519,7 @@ impl<'a> RawToken<'a> {
         match chars.next() {
             None => Ok(RawToken::EndOfStream),
             Some((col_start, c)) => {
-                let sm = SeekMode::from(c);
+                let mut sm = SeekMode::from(c);
                 loop {
                     let (idx, c) = match chars.peek().copied() {
                         Some((idx, c)) => (Some(idx), Some(c)),
@@ -453,14 +573,52 @@ pub(super) fn tokenize_to_iters(
     RawToken::source_to_iters(source).map(|line| line.map(|raw| raw?.try_map(Token::parse)))
 }
 
-pub fn tokenize(source: &str) -> Result<Vec<Vec<Located<Token>>>, Located<Error>> {
-    tokenize_to_iters(source).map(Iterator::collect).collect()
+pub fn tokenize(source: &str) -> Result<Vec<Vec<Located<Token>>>, Vec<Located<Error>>> {
+    common::collect_all(tokenize_to_iters(source).map(Iterator::collect), MAX_ERRORS)
+}
+
+/// A line of source as it was written: each of its tokens together with its text, and its
+/// trailing comment (starting with the `#`), if any.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RawLine<'a> {
+    pub tokens: Vec<(Token, &'a str)>,
+    pub comment: Option<&'a str>,
+}
+
+impl<'a> RawLine<'a> {
+    fn new(line_no: usize, line: &'a str) -> Result<Self, Located<Error>> {
+        let mut tokens = Vec::new();
+        let mut end = 0;
+        for raw in RawToken::line_to_iter(line_no, line) {
+            let raw = raw?;
+            let text = *raw.value_ref();
+            tokens.push((raw.locate(text).try_map_err(Token::parse)?, text));
+            end = raw.loc().unwrap().col() - 1 + text.len();
+        }
+
+        // NOTE: A line stops being tokenized either at its end or at the start of a comment.
+        let rest = line[end..].trim();
+        let comment = Some(rest).filter(|rest| rest.starts_with(RawToken::COMMENT_CHAR));
+        Ok(RawLine { tokens, comment })
+    }
+}
+
+/// Tokenize each line of `source`, keeping the text of each token and any comments (as needed
+/// to format the source).
+pub fn tokenize_lines(source: &str) -> Result<Vec<RawLine<'_>>, Vec<Located<Error>>> {
+    common::collect_all(
+        source
+            .lines()
+            .enumerate()
+            .map(|(line_no, line)| RawLine::new(line_no + 1, line)),
+        MAX_ERRORS,
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::types::{Loc, Located};
-    use super::RawToken;
+    use super::{parse_char_literal, unescape, RawToken, Token};
 
     #[test]
     fn consume_single_simple() {
@@ -811,4 +969,84 @@ mod tests {
         let mut line_it = &mut line.char_indices().peekable();
         drop(RawToken::consume_one(0, line, &mut line_it).unwrap());
     }
+
+    #[test]
+    fn consume_single_string_escaped_quote() {
+        let line = "\"say \\\"hi\\\"\" MOV";
+        let mut line_it = &mut line.char_indices().peekable();
+        assert_eq!(
+            RawToken::consume_one(0, line, &mut line_it).unwrap(),
+            RawToken::Value(Located::with_loc(Loc::new(0, 1), "\"say \\\"hi\\\"\""))
+        );
+        assert_eq!(
+            RawToken::consume_one(0, line, &mut line_it).unwrap(),
+            RawToken::Nothing
+        );
+        assert_eq!(
+            RawToken::consume_one(0, line, &mut line_it).unwrap(),
+            RawToken::Value(Located::with_loc(Loc::new(0, 14), "MOV"))
+        );
+    }
+
+    #[test]
+    fn consume_single_char() {
+        let line = "' ' '\\''";
+        let mut line_it = &mut line.char_indices().peekable();
+        assert_eq!(
+            RawToken::consume_one(0, line, &mut line_it).unwrap(),
+            RawToken::Value(Located::with_loc(Loc::new(0, 1), "' '"))
+        );
+        assert_eq!(
+            RawToken::consume_one(0, line, &mut line_it).unwrap(),
+            RawToken::Nothing
+        );
+        assert_eq!(
+            RawToken::consume_one(0, line, &mut line_it).unwrap(),
+            RawToken::Value(Located::with_loc(Loc::new(0, 5), "'\\''"))
+        );
+        assert_eq!(
+            RawToken::consume_one(0, line, &mut line_it).unwrap(),
+            RawToken::EndOfStream
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn consume_single_char_unterminated() {
+        let line = "'a";
+        let mut line_it = &mut line.char_indices().peekable();
+        drop(RawToken::consume_one(0, line, &mut line_it).unwrap());
+    }
+
+    #[test]
+    fn unescape_all() {
+        assert_eq!(
+            unescape("a\\n\\t\\0\\\\\\\"\\'\\x41\\xff").unwrap(),
+            "a\n\t\0\\\"'A\u{ff}"
+        );
+        assert!(unescape("\\q").is_err());
+        assert!(unescape("\\x4").is_err());
+        assert!(unescape("\\xzz").is_err());
+    }
+
+    #[test]
+    fn over_qualified_literals() {
+        assert!(!Token::is_over_qualified("05", 16, 0x05));
+        assert!(!Token::is_over_qualified("FF", 16, 0xFF));
+        assert!(Token::is_over_qualified("005", 16, 0x05));
+        assert!(!Token::is_over_qualified("200", 10, 200));
+        assert!(Token::is_over_qualified("010", 10, 10));
+        assert!(!Token::is_over_qualified("00000101", 2, 5));
+        assert!(!Token::is_over_qualified("0100", 16, 0x100));
+    }
+
+    #[test]
+    fn char_literals() {
+        assert_eq!(parse_char_literal("A"), Ok(0x41));
+        assert_eq!(parse_char_literal("\\n"), Ok(0x0A));
+        assert_eq!(parse_char_literal("\\xFF"), Ok(0xFF));
+        assert!(parse_char_literal("").is_err());
+        assert!(parse_char_literal("AB").is_err());
+        assert!(parse_char_literal("\u{100}").is_err());
+    }
 }
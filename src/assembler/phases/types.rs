@@ -1,8 +1,8 @@
-use super::{generate, parse, resolve, tokenize};
-use crate::assembler::model::{Arg, Blob};
-use crate::spec::types::hw::*;
-use derive_more::Constructor;
+use super::{expand, expr::Expr, generate, include, parse, resolve, scope, tokenize};
+use crate::assembler::model::{Arg, Blob, ConstBinding};
+use crate::spec::types::hw::Word;
 use std::fmt::Display;
+use std::sync::Arc;
 
 /*
     UPDATE:  FIX THE TEXT BELOW, THIS IS HOW WE DO IT NOW
@@ -16,19 +16,51 @@ use std::fmt::Display;
         2.  Parsing: Each statement stream is parsed into a `Statement`, either:
 
             `LabelDef`
+            `Equ`
+            `Export`/`Import`
+            `Org`/`Align`/`Fill`
             `BinaryData`
             `StringData`
             `Inst`
+            `MacroDef`/`MacroEnd`/`Rept`/`ReptEnd`/`If`/`IfDef`/`Else`/`EndIf` (block delimiters)
+            `AliasDef`/`AliasEnd` (block delimiters), `Family`
+            `StructDef`/`StructEnd` (block delimiters), `FieldDef`
+            `ProcDef`/`ProcEnd` (block delimiters)
+            `UserError`/`Assert`
 
             In this stage we check for things like the use of reserved instruction names in labels,
             but avoid trying to understand the semantic meaning of the statements.
 
-        3.  Expansion: Each `Statement` is expanded into a `Vec<BinaryElement>` (a local operation).
+        2b. Inclusion: Each `!include "path"` statement is replaced by the (recursively tokenized,
+            parsed and included) statements of the named file, which is found relative to the
+            directory of the including file by a `SourceLoader`.
 
-        4.  Resolution: The locations of the labels are read from the `BinaryElement` list, and givne
-            this data each `BinaryElement` is resolved into a `Vec<Word>` of binary data.
+        3.  Macro expansion: First the conditional blocks are evaluated, keeping only the
+            statements of the branches taken (and checking each `!assert` and `!error` in them).
+            Then `!macro` definitions are removed from the `Statement` list and recorded,
+            and then each macro call (an `Inst` naming a macro) and `!rept` block is replaced by the
+            statements of its body (recursively). The conditional blocks inside a macro body are
+            evaluated each time it is expanded. Each `!struct` block is laid out (alongside the
+            conditional blocks) into a single `Struct` statement. Finally each `!alias` block is
+            gathered (with the macros in its body expanded) into a single `Alias` statement, and
+            each `!proc` block is replaced by its body (with the macros in it expanded), wrapped in
+            a prologue and epilogue.
 
-        5.  Concatenation: Each of these lists of words are concatenated to give the final assembled binary.
+        3b. Scoping: Each local label (`.name`, belonging to the preceding global label) and
+            anonymous label (`1:`, referred to as `1f` or `1b`) is renamed to a unique global
+            name, as is each reference to one.
+
+        4.  Generation: First the `Alias` and `Family` statements are removed, and the aliases and
+            families they define are added to a language extending the built-in one. Then each
+            `Statement` is expanded into a `Vec<BinaryElement>` (a local operation), each of which
+            keeps the location of the statement which generated it.
+
+        5.  Resolution: The locations of the labels are read from the `BinaryElement` list, and givne
+            this data (together with the `!equ` definitions) each constant expression is evaluated and
+            each `BinaryElement` is resolved into a `Vec<Word>` of binary data.
+
+        6.  Concatenation: Each of these lists of words is written at its address to give the final
+            assembled binary (with any gaps between regions filled by zeros).
 
 
 
@@ -48,15 +80,109 @@ use std::fmt::Display;
         Given a fresh token stream, we expect that the first token (we split on spaces) is one of:
 
             * a label definition (as specified by the token ending in a ':'), or
-            * a macro definition (as specified by the token beginning with a '@'), or
-            * an instruction name.
+            * a special command (as specified by the token beginning with a '!'), or
+            * an instruction (or macro) name.
 
         If we encounter a label we remove it from the stream, record it, and consider the resulting
         stream again "fresh". Otherwise control is transfered to the macro or instruction stream
         parser.
 
             Macros:
-                Currently there are no macros.
+                A macro is defined by a block
+
+                    !macro NAME param1 param2 ...
+                        <body>
+                    !endm
+
+                and is called just like an instruction, `NAME arg1 arg2 ...`. When a macro is called
+                each parameter name appearing as an argument in the body is substituted with the
+                corresponding passed argument (which may be a register), and each label defined in the
+                body is renamed uniquely for that expansion, so that a macro may contain loops without
+                its labels colliding between calls. Similarly a block
+
+                    !rept COUNT
+                        <body>
+                    !endr
+
+                is replaced by COUNT copies of its body (with the same label hygiene applied to each).
+
+            Aliases and families:
+                Besides the built-in instructions, an assembly may define its own. A block
+
+                    !alias NAME param1 param2 ...
+                        <instructions>
+                    !enda
+
+                defines an alias whose body consists of the given (true, i.e. not alias) instructions,
+                where each argument is a register, a literal constant, or a parameter name, e.g.
+
+                    !alias PUSHAB
+                        PUSHx2 %ra %rb
+                    !enda
+
+                Unlike a macro, an alias takes its arguments' kinds from the slots each parameter fills,
+                and so can be made a variant of a family by `!family NAME alias1 alias2 ...`, which
+                defines the instruction NAME (so long as no two of its variants accept the same
+                arguments). Each alias is also a family (with itself as its only variant) of the same
+                name. Definitions apply to the whole assembly, wherever they appear in it.
+
+            Constant expressions:
+                Anywhere a constant is accepted (including in `!warray`/`!barray`) one may instead write
+                an expression over numerals and symbols, e.g. `$buf+4`, `(COLS*2)-1` or `hi(label)`,
+                using the usual C operators `+ - * / % << >> & | ^ ~`, and the functions `hi()`/`lo()`.
+                (Expressions cannot contain spaces, since they must form a single token.) A symbol is
+                either a label or a name defined by `!equ NAME expr`. Since symbols are only known once
+                all labels have been placed, expressions are only evaluated during resolution.
+
+            Records:
+                A block
+
+                    !struct NAME
+                    !word field1
+                    !byte field2 COUNT
+                    !ends
+
+                declares the layout of a record, defining the constant `NAME.field` to be the offset
+                of each field from the start of the record, and `NAME.size` to be its size in bytes
+                (so e.g. `LDWO %rbp $NAME.field %ra` loads a field, and `!fill $NAME.size/2 $0`
+                reserves a record). A field is a word (aligned to an even offset) or a byte, or COUNT
+                of them. The size is always even, so that records may be placed one after another.
+                Since a word load or store cannot access a byte field, a byte field may only be named
+                by the byte variants (`LDBL`, `STBH`, ...) of the `LD`, `LDZ` and `ST` families.
+
+            Procedures:
+                A block
+
+                    !proc NAME arg1 arg2 ... locals local1 local2 ...
+                        <body>
+                    !endp
+
+                defines a routine at the label NAME, which is called by pushing each argument (in
+                order) and then calling NAME, after which the caller pops the arguments again. The
+                body is preceded by `ENTERFR` (or `ENTER`, if there are no locals), reserving a word
+                for each local, and followed by `LEAVE` and `RET`. Inside the body each argument and
+                local name stands for the offset of its word from `%rbp` (so e.g. `LDWO %rbp $arg1 %ra`
+                loads an argument, and `STWO %rbp $local1 %ra` stores to a local), shadowing any
+                symbol of the same name. A `RET` in the body which is not directly preceded by a
+                `LEAVE` would return without releasing the frame, and so is warned about.
+
+            Placement:
+                Each element is placed directly after the previous one, starting at address 0, except
+                that `!org ADDR` moves the current address to ADDR (which must be even), and `!align N`
+                advances it to the next multiple of N (a power of two). `!fill COUNT VALUE` emits COUNT
+                copies of the word VALUE. The arguments of `!org`, `!align` and the COUNT of `!fill` are
+                evaluated during resolution (as placement proceeds), so may only refer to `!equ`
+                constants and labels defined before them. It is an error for any two regions of output
+                to overlap.
+
+            Objects and linking:
+                When assembling a relocatable object (`kasm -c`) the base address of the program is
+                unknown, so no expression involving a label can be evaluated. Instead each such
+                expression is recorded as a relocation against the word it occupies, to be evaluated
+                by the linker once every object has been placed. Symbols named by `!export` are made
+                visible to other objects, and symbols which are defined by other objects must be
+                declared with `!import`. (When assembling a flat binary these directives have no
+                effect.)
 
             Instructions:
                 We first check whether an instruction family by the specified name exists.
@@ -83,27 +209,155 @@ use std::fmt::Display;
 
 pub type LabelName = String;
 
+#[derive(Clone)]
 pub enum Statement {
     LabelDef(LabelName),
-    RawWords(Vec<Word>),
-    RawBytes(Vec<Byte>),
+    RawWords(Vec<ConstBinding<Expr>>),
+    RawBytes(Vec<ConstBinding<Expr>>),
     RawString(String),
-    Inst(String, Vec<Located<Arg<LabelName>>>),
+    /// A string emitted without the NUL terminator (and padding) added to a `RawString`.
+    RawBString(String),
+    Inst(String, Vec<Located<Arg<Expr>>>),
+    Equ(LabelName, Expr),
+    Export(Vec<LabelName>),
+    Import(Vec<LabelName>),
+    Org(Expr),
+    Align(Expr),
+    Fill(Expr, ConstBinding<Expr>),
+    Include(String),
+    MacroDef(String, Vec<String>),
+    MacroEnd,
+    AliasDef(String, Vec<String>),
+    AliasEnd,
+    /// A whole `!alias` block: the name, parameters and body of the alias.
+    Alias(String, Vec<String>, Vec<Located<Statement>>),
+    Family(String, Vec<String>),
+    StructDef(String),
+    StructEnd,
+    FieldDef(FieldKind, String, Word),
+    /// A whole `!struct` block: the name, the fields (under their full names, e.g. `NAME.field`)
+    /// and the size of the record.
+    Struct(String, Vec<Located<Field>>, Word),
+    /// The start of a `!proc` block: the name, the arguments and the locals of the procedure.
+    ProcDef(String, Vec<String>, Vec<String>),
+    ProcEnd,
+    Rept(Word),
+    ReptEnd,
+    If(Expr),
+    IfDef(String),
+    Else,
+    EndIf,
+    UserError(String),
+    Assert(Expr),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    Word,
+    Byte,
+}
+
+impl FieldKind {
+    /// The size of the field, in bytes.
+    pub fn size(self) -> Word {
+        match self {
+            FieldKind::Word => 2,
+            FieldKind::Byte => 1,
+        }
+    }
+}
+
+/// A field of a `!struct`, with its offset from the start of the record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub kind: FieldKind,
+    pub name: String,
+    pub offset: Word,
+}
+
+/// The name of the constant giving the size of the `!struct` called `name`.
+pub fn struct_size_name(name: &str) -> String {
+    format!("{}.size", name)
+}
+
+/// Records how an instruction was generated: the `Alias` variant chosen for the called family,
+/// and the arguments (with their locations) it was called with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstSource {
+    pub alias: String,
+    pub args: Vec<Located<Arg<Expr>>>,
+}
+
+#[derive(Clone)]
 pub enum BinaryElement {
     LabelDef(LabelName),
-    Inst(Blob<LabelName>),
-    Data(Vec<Word>),
+    Equ(LabelName, Expr),
+    Export(Vec<LabelName>),
+    Import(Vec<LabelName>),
+    Org(Expr),
+    Align(Expr),
+    /// A word count and the value to repeat, expanded into `Data` during resolution.
+    Fill(Expr, ConstBinding<Expr>),
+    Inst(Blob<Expr>, InstSource),
+    Data(Vec<ConstBinding<Expr>>),
+    /// Always of even length, each pair of bytes being packed into a word.
+    ByteData(Vec<ConstBinding<Expr>>),
 }
 
-#[derive(Debug, PartialEq, Clone, Eq, Constructor)]
+/// Identifies a source file by (a display name for) its path. The root source passed to
+/// `assembler::assemble` has no name, and its `Loc`s have no `FileId`.
+pub type FileId = Arc<str>;
+
+#[derive(Debug, PartialEq, Clone, Eq)]
 pub struct Loc {
+    file: Option<FileId>,
     line: usize,
     col: usize,
+    expanded_from: Option<Box<Loc>>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl Loc {
+    pub fn new(line: usize, col: usize) -> Self {
+        Loc {
+            file: None,
+            line,
+            col,
+            expanded_from: None,
+        }
+    }
+
+    pub fn file(&self) -> Option<&FileId> {
+        self.file.as_ref()
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn col(&self) -> usize {
+        self.col
+    }
+
+    /// The macro call whose expansion reached this location, if any.
+    pub fn call_site(&self) -> Option<&Loc> {
+        self.expanded_from.as_deref()
+    }
+
+    pub fn in_file(self, file: Option<FileId>) -> Self {
+        Loc { file, ..self }
+    }
+
+    /// Mark this location (somewhere inside the body of a macro definition)
+    /// as having been reached by expanding the macro call at `call_site`.
+    pub fn expanded_from(self, call_site: Loc) -> Self {
+        Loc {
+            expanded_from: Some(Box::new(call_site)),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Located<T: Sized> {
     loc: Option<Loc>,
     val: T,
@@ -111,7 +365,19 @@ pub struct Located<T: Sized> {
 
 impl Display for Loc {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "(line: {}, col: {})", self.line, self.col)
+        if let Some(file) = &self.file {
+            write!(
+                f,
+                "(file: {}, line: {}, col: {})",
+                file, self.line, self.col
+            )?;
+        } else {
+            write!(f, "(line: {}, col: {})", self.line, self.col)?;
+        }
+        if let Some(call_site) = &self.expanded_from {
+            write!(f, " in expansion of macro called at {}", call_site)?;
+        }
+        Ok(())
     }
 }
 
@@ -137,6 +403,21 @@ impl<T> Located<T> {
         self.val
     }
 
+    pub fn value_ref(&self) -> &T {
+        &self.val
+    }
+
+    pub fn loc(&self) -> Option<&Loc> {
+        self.loc.as_ref()
+    }
+
+    pub fn map_loc<F>(self, f: F) -> Self
+    where
+        F: FnOnce(Loc) -> Loc,
+    {
+        Located::new(self.loc.map(f), self.val)
+    }
+
     pub fn proximate_to_option_loc(self, loc: Option<Loc>) -> Self {
         match self.loc {
             None => Self { loc, ..self },
@@ -200,6 +481,11 @@ impl<T> Located<T> {
     pub fn transfer<S>(self, s: S) -> Located<S> {
         Located::new(self.loc, s)
     }
+
+    /// Give `s` a copy of the location of this value.
+    pub fn locate<S>(&self, s: S) -> Located<S> {
+        Located::new(self.loc.clone(), s)
+    }
 }
 
 impl<T> From<T> for Located<T> {
@@ -208,18 +494,88 @@ impl<T> From<T> for Located<T> {
     }
 }
 
+impl<T> Located<Vec<T>> {
+    /// Give each of the values the location of the whole.
+    pub fn distribute(self) -> Vec<Located<T>> {
+        let loc = self.loc;
+        self.val
+            .into_iter()
+            .map(|val| Located::new(loc.clone(), val))
+            .collect()
+    }
+}
+
 impl<T> Located<Located<T>> {
     pub fn flatten(self) -> Located<T> {
         self.val.proximate_to_option_loc(self.loc)
     }
 }
 
+/// The maximum number of errors which a phase will collect before giving up.
+pub const MAX_ERRORS: usize = 20;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Error {
     Tokenize(Located<tokenize::Error>),
     Parse(Located<parse::Error>),
+    Include(Located<include::Error>),
+    Expand(Located<expand::Error>),
+    Scope(Located<scope::Error>),
     Generate(Located<generate::Error>),
-    Resolve(resolve::Error), // RUSTFIX Does a `Loc` make sense for this?
+    Resolve(Located<resolve::Error>),
+    /// Several independent errors, each of which is not itself `Multiple`.
+    Multiple(Vec<Error>),
+}
+
+impl Error {
+    /// The individual errors which make up this one.
+    pub fn errors(&self) -> Vec<&Error> {
+        match self {
+            Error::Multiple(errs) => errs.iter().flat_map(Error::errors).collect(),
+            err => vec![err],
+        }
+    }
+
+    /// The name of the phase which raised the error, or `None` for `Multiple`.
+    pub fn phase(&self) -> Option<&'static str> {
+        match self {
+            Error::Tokenize(_) => Some("Tokenizer"),
+            Error::Parse(_) => Some("Parser"),
+            Error::Include(_) => Some("Includer"),
+            Error::Expand(_) => Some("Expander"),
+            Error::Scope(_) => Some("Scoper"),
+            Error::Generate(_) => Some("Generator"),
+            Error::Resolve(_) => Some("Resolver"),
+            Error::Multiple(_) => None,
+        }
+    }
+
+    pub fn loc(&self) -> Option<&Loc> {
+        match self {
+            Error::Tokenize(err) => err.loc(),
+            Error::Parse(err) => err.loc(),
+            Error::Include(err) => err.loc(),
+            Error::Expand(err) => err.loc(),
+            Error::Scope(err) => err.loc(),
+            Error::Generate(err) => err.loc(),
+            Error::Resolve(err) => err.loc(),
+            Error::Multiple(_) => None,
+        }
+    }
+
+    /// The message of the error, without its location.
+    pub fn message(&self) -> String {
+        match self {
+            Error::Tokenize(err) => err.value_ref().to_string(),
+            Error::Parse(err) => err.value_ref().to_string(),
+            Error::Include(err) => err.value_ref().to_string(),
+            Error::Expand(err) => err.value_ref().to_string(),
+            Error::Scope(err) => err.value_ref().to_string(),
+            Error::Generate(err) => err.value_ref().to_string(),
+            Error::Resolve(err) => err.value_ref().to_string(),
+            Error::Multiple(errs) => format!("{} errors", errs.len()),
+        }
+    }
 }
 
 impl From<Located<tokenize::Error>> for Error {
@@ -234,33 +590,69 @@ impl From<Located<parse::Error>> for Error {
     }
 }
 
+impl From<Located<include::Error>> for Error {
+    fn from(err: Located<include::Error>) -> Self {
+        Error::Include(err)
+    }
+}
+
+impl From<Located<expand::Error>> for Error {
+    fn from(err: Located<expand::Error>) -> Self {
+        Error::Expand(err)
+    }
+}
+
+impl From<Located<scope::Error>> for Error {
+    fn from(err: Located<scope::Error>) -> Self {
+        Error::Scope(err)
+    }
+}
+
 impl From<Located<generate::Error>> for Error {
     fn from(err: Located<generate::Error>) -> Self {
         Error::Generate(err)
     }
 }
 
-impl From<resolve::Error> for Error {
-    fn from(err: resolve::Error) -> Self {
+impl From<Located<resolve::Error>> for Error {
+    fn from(err: Located<resolve::Error>) -> Self {
         Error::Resolve(err)
     }
 }
 
+impl<E> From<Vec<E>> for Error
+where
+    Error: From<E>,
+{
+    fn from(errs: Vec<E>) -> Self {
+        let mut errs: Vec<Error> = errs.into_iter().map(Error::from).collect();
+        if errs.len() == 1 {
+            errs.remove(0)
+        } else {
+            Error::Multiple(errs)
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Assembly Error (in ")?;
-        match self {
-            Error::Tokenize(_) => write!(f, "Tokenizer"),
-            Error::Parse(_) => write!(f, "Parser"),
-            Error::Generate(_) => write!(f, "Generator"),
-            Error::Resolve(_) => write!(f, "Resolver"),
-        }?;
-        write!(f, "): ")?;
         match self {
-            Error::Tokenize(msg) => write!(f, "{}", msg),
-            Error::Parse(msg) => write!(f, "{}", msg),
-            Error::Generate(msg) => write!(f, "{}", msg),
-            Error::Resolve(msg) => write!(f, "{}", msg),
+            Error::Tokenize(msg) => write!(f, "Assembly Error (in Tokenizer): {}", msg),
+            Error::Parse(msg) => write!(f, "Assembly Error (in Parser): {}", msg),
+            Error::Include(msg) => write!(f, "Assembly Error (in Includer): {}", msg),
+            Error::Expand(msg) => write!(f, "Assembly Error (in Expander): {}", msg),
+            Error::Scope(msg) => write!(f, "Assembly Error (in Scoper): {}", msg),
+            Error::Generate(msg) => write!(f, "Assembly Error (in Generator): {}", msg),
+            Error::Resolve(msg) => write!(f, "Assembly Error (in Resolver): {}", msg),
+            Error::Multiple(errs) => {
+                for (idx, err) in errs.iter().enumerate() {
+                    if idx != 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", err)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -0,0 +1,243 @@
+use super::disasm::{self, DisassembledAlias, DisassembledBlob};
+use super::format;
+use super::model::{Arg, ConstBinding, Slot};
+use crate::spec::{
+    defs::usig,
+    types::{
+        hw::{Word, IU},
+        schema::InstDef,
+    },
+};
+use itertools::Itertools;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+
+/*
+    The standalone disassembler (`kcpu disasm`), which recovers a kasm source from a binary.
+
+    Code is told apart from data by following the flow of control from the entry point (the
+    start of the binary). Each instruction reached is decoded as an alias (see
+    `disasm::disassemble_alias`), and then its successors are visited in turn: the next
+    instruction (unless it always jumps away, or halts), and the target of each jump or call to
+    a constant address. Jumps whose target is not known statically (as for `RET`, `JMP %ra`, or
+    a far jump) are not followed, and neither are targets outside of the binary or inside of an
+    instruction which has already been decoded.
+
+    Each word which is never reached is data, and is written out by `!warray`. A label `L_XXXX`
+    (for the byte address `XXXX`) is invented for each target which starts an instruction or is
+    data, and is used in place of the constant in each instruction which jumps there.
+
+    Each instruction is written as the most general family which selects the alias it was decoded
+    as (see `disasm::family_reverse_lookup`), so for example `ADD %ra %rb %rc` rather than
+    `ADD3 %ra %rb %rc`, and assembling the output reproduces the binary exactly. The debugger
+    also uses the instructions found here, so that it knows which alias each instruction executed
+    is a part of, even after a jump into the middle of one.
+*/
+
+/// The most words written by a single `!warray`.
+const WARRAY_WIDTH: usize = 8;
+
+/// How control passes on from an instruction.
+struct Flow {
+    /// The argument holding the address which the instruction jumps (or calls) to, if any.
+    target: Option<IU>,
+    /// Whether control may continue to the next instruction.
+    next: bool,
+}
+
+impl Flow {
+    fn of(idef: &InstDef) -> Self {
+        let calls = idef.uis.iter().any(|ui| usig::does_save_rip(*ui));
+        let far = idef.uis.iter().any(|ui| usig::does_set_prefix(*ui));
+
+        let mut flow = Flow {
+            target: None,
+            next: true,
+        };
+        for ui in idef.uis.iter().copied() {
+            if usig::does_halt(ui) {
+                flow.next = false;
+            } else if usig::does_jump(ui) && !usig::does_save_rip(ui) {
+                flow.next &= calls || usig::does_jump_conditionally(ui);
+                if !far {
+                    flow.target = usig::rctrl_busb_output_iu(ui);
+                }
+            }
+        }
+        flow
+    }
+}
+
+struct Decoded {
+    alias: DisassembledAlias<'static>,
+    blobs: Vec<DisassembledBlob<'static>>,
+    /// The number of words occupied.
+    len: usize,
+    /// The address of each jump target, with the index of the argument of the alias which
+    /// gives it (if it is an argument, rather than fixed by the alias).
+    targets: Vec<(Word, Option<usize>)>,
+    next: bool,
+}
+
+impl Decoded {
+    /// Decode the instruction at the start of `words`, if it is one.
+    fn new(words: &[Word]) -> Option<Self> {
+        let blobs = disasm::disassemble_blob_iter(words.iter().copied());
+        let (alias, blobs) = disasm::disassemble_alias(blobs).ok()?;
+
+        let mut targets = Vec::new();
+        let mut next = true;
+        for (virt, blob) in alias.alias.vinsts.iter().zip(blobs.iter()) {
+            let flow = Flow::of(blob.idef);
+            next &= flow.next;
+
+            if let Some(iu) = flow.target {
+                if let Some(Arg::Const(ConstBinding::Resolved(c))) = &blob.args[iu] {
+                    let idx = match virt.slots[iu] {
+                        Some(Slot::Arg(idx)) => Some(idx),
+                        _ => None,
+                    };
+                    targets.push((c.encode(), idx));
+                }
+            }
+        }
+
+        Some(Decoded {
+            len: blobs.iter().map(|blob| blob.blob.to_words().len()).sum(),
+            alias,
+            blobs,
+            targets,
+            next,
+        })
+    }
+
+    fn render(&self, labels: &BTreeSet<usize>) -> String {
+        let args = self.alias.args.iter().enumerate().map(|(idx, arg)| {
+            self.targets
+                .iter()
+                .find(|(addr, from)| *from == Some(idx) && labels.contains(&usize::from(*addr)))
+                .map(|(addr, _)| label(usize::from(*addr)))
+                .unwrap_or_else(|| arg.to_string())
+        });
+
+        let family = disasm::family_reverse_lookup(&self.alias);
+        join_words(&family.name.to_uppercase(), args)
+    }
+}
+
+fn label(addr: usize) -> String {
+    format!("L_{:04X}", addr)
+}
+
+fn join_words(first: &str, rest: impl Iterator<Item = String>) -> String {
+    std::iter::once(first.to_owned()).chain(rest).join(" ")
+}
+
+/// The instructions of a program, as found by following its flow of control.
+pub struct Program {
+    /// The instructions, by the index of their first word.
+    insts: BTreeMap<usize, Decoded>,
+    /// Which words are covered by an instruction.
+    covered: Vec<bool>,
+}
+
+impl Program {
+    pub fn new(words: &[Word]) -> Self {
+        let mut insts = BTreeMap::new();
+        let mut covered = vec![false; words.len()];
+        let mut queue = VecDeque::from(vec![0]);
+        while let Some(at) = queue.pop_front() {
+            if at >= words.len() || covered[at] {
+                continue;
+            }
+
+            let inst = match Decoded::new(&words[at..]) {
+                Some(inst) => inst,
+                None => continue,
+            };
+            let span = at..at + inst.len;
+            if covered[span.clone()].iter().any(|c| *c) {
+                continue;
+            }
+            covered[span].iter_mut().for_each(|c| *c = true);
+
+            queue.extend(
+                inst.targets
+                    .iter()
+                    .filter(|(addr, _)| addr % 2 == 0)
+                    .map(|(addr, _)| usize::from(*addr) / 2),
+            );
+            if inst.next {
+                queue.push_back(at + inst.len);
+            }
+            insts.insert(at, inst);
+        }
+
+        Program { insts, covered }
+    }
+
+    /// The context (for a `disasm::SteppingDisassembler`) of the instruction at byte address
+    /// `addr`, if one starts there. It may lie in the middle of an alias.
+    pub fn context_at(&self, addr: Word) -> Option<disasm::Context<'static>> {
+        let idx = usize::from(addr) / 2;
+        let (start, inst) = self.insts.range(..=idx).next_back()?;
+
+        let mut offset = 2 * *start;
+        for (i, blob) in inst.blobs.iter().enumerate() {
+            if offset == usize::from(addr) {
+                return Some(disasm::Context::new(
+                    inst.alias.clone(),
+                    inst.blobs.clone(),
+                    i,
+                ));
+            }
+            offset += 2 * blob.blob.to_words().len();
+        }
+        None
+    }
+
+    /// The byte address of each jump target which starts an instruction or is data.
+    fn labels(&self) -> BTreeSet<usize> {
+        self.insts
+            .values()
+            .flat_map(|inst| inst.targets.iter())
+            .map(|(addr, _)| usize::from(*addr))
+            .filter(|addr| addr % 2 == 0 && addr / 2 < self.covered.len())
+            .filter(|addr| self.insts.contains_key(&(addr / 2)) || !self.covered[addr / 2])
+            .collect()
+    }
+
+    /// Write the program `words` (from which this was built) as a kasm source.
+    pub fn render(&self, words: &[Word]) -> String {
+        let labels = self.labels();
+
+        let mut out = String::new();
+        let mut at = 0;
+        while at < words.len() {
+            if labels.contains(&(2 * at)) {
+                writeln!(out, "{}:", label(2 * at)).unwrap();
+            }
+
+            if let Some(inst) = self.insts.get(&at) {
+                writeln!(out, "{}", inst.render(&labels)).unwrap();
+                at += inst.len;
+            } else {
+                let end = (at + 1..words.len())
+                    .find(|&i| self.covered[i] || labels.contains(&(2 * i)))
+                    .unwrap_or(words.len())
+                    .min(at + WARRAY_WIDTH);
+                let data = words[at..end].iter().map(|w| format!("${:#06X}", w));
+                writeln!(out, "{}", join_words("!warray", data)).unwrap();
+                at = end;
+            }
+        }
+
+        // NOTE: The source is made of tokens which were just written, so it always tokenizes.
+        format::format(&out).unwrap()
+    }
+}
+
+/// Disassemble the program `words` into a kasm source, as described above.
+pub fn disassemble_program(words: &[Word]) -> String {
+    Program::new(words).render(words)
+}
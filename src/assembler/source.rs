@@ -0,0 +1,120 @@
+use super::phases::types::{FileId, Loc};
+use crate::assets;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Provides the contents of source files to the assembler, both for the root file
+/// (if it is assembled by path) and for each `!include`.
+pub trait SourceLoader {
+    fn load(&self, path: &Path) -> io::Result<String>;
+}
+
+/// Loads sources from the real filesystem.
+pub struct FsLoader;
+
+impl SourceLoader for FsLoader {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+}
+
+/// Load the source at `path` with `loader`, unless it names a module of the standard library
+/// (see `assets::std_module`), which is always available.
+pub fn load(loader: &dyn SourceLoader, path: &Path) -> io::Result<String> {
+    let name = match path.to_str().and_then(assets::std_module_name) {
+        Some(name) => name,
+        None => return loader.load(path),
+    };
+
+    assets::std_module(name).map(str::to_owned).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such module in the standard library: {}", name),
+        )
+    })
+}
+
+/// An in-memory filesystem, for assembling sources which do not live on disk.
+/// Paths are compared after lexical normalization (so `a/../b.ks` is `b.ks`).
+#[derive(Default)]
+pub struct VirtualFs {
+    files: HashMap<PathBuf, String>,
+}
+
+impl VirtualFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<P: AsRef<Path>, S: Into<String>>(&mut self, path: P, src: S) {
+        self.files.insert(normalize(path.as_ref()), src.into());
+    }
+
+    pub fn with<P: AsRef<Path>, S: Into<String>>(mut self, path: P, src: S) -> Self {
+        self.insert(path, src);
+        self
+    }
+}
+
+impl SourceLoader for VirtualFs {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        self.files.get(&normalize(path)).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no such file in virtual filesystem: {}", path.display()),
+            )
+        })
+    }
+}
+
+/// The text of the lines named by `Loc`s, with each file loaded (at most once) on demand.
+pub struct SourceLines<'a> {
+    /// The text of the root source, if it was not loaded from a file.
+    root: Option<&'a str>,
+    loader: &'a dyn SourceLoader,
+    files: HashMap<Option<FileId>, Vec<String>>,
+}
+
+impl<'a> SourceLines<'a> {
+    pub fn new(root: Option<&'a str>, loader: &'a dyn SourceLoader) -> Self {
+        SourceLines {
+            root,
+            loader,
+            files: HashMap::new(),
+        }
+    }
+
+    /// The line at `loc`, or `None` if its file cannot be loaded or is too short.
+    pub fn line(&mut self, loc: &Loc) -> Option<&str> {
+        let (root, loader) = (self.root, self.loader);
+        let lines = self.files.entry(loc.file().cloned()).or_insert_with(|| {
+            let src = match loc.file() {
+                None => root.map(str::to_owned),
+                Some(file) => load(loader, Path::new(&**file)).ok(),
+            };
+            src.unwrap_or_default().lines().map(str::to_owned).collect()
+        });
+        lines.get(loc.line().checked_sub(1)?).map(String::as_str)
+    }
+}
+
+/// Remove each `.` from `path`, and each `..` which follows a directory name (together with the
+/// name), without consulting the filesystem.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => (),
+                _ => normalized.push(component),
+            },
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
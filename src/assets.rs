@@ -3,12 +3,48 @@ use once_cell::sync::Lazy;
 use std::path::PathBuf;
 
 pub const DEFAULT_BINARY_EXT: &str = "kb";
+pub const DEFAULT_OBJECT_EXT: &str = "ko";
+pub const DEFAULT_DEBUG_INFO_EXT: &str = "kdbg";
 
 // RUSTFIX make this const once `PathBuf` is.
 pub fn default_suite_dir() -> PathBuf {
     ["asm"].iter().collect()
 }
 
+/*
+    The kasm standard library, a set of routines (for what the ISA lacks, like multiplication)
+    which are bundled with the assembler. Each module is included by name, as in
+    `!include "std:math"`, and its routines follow the calling convention described in
+    `std/version.ks` (which every module includes, defining `STD_VERSION`).
+*/
+
+/// The prefix of the name of each module of the standard library, in an `!include`.
+pub const STD_PREFIX: &str = "std:";
+
+/// The version of the standard library, which is also its `STD_VERSION`.
+pub const STD_VERSION: u16 = 1;
+
+static STD_MODULES: &[(&str, &str)] = &[
+    ("version", include_str!("assets/std/version.ks")),
+    ("math", include_str!("assets/std/math.ks")),
+    ("mem", include_str!("assets/std/mem.ks")),
+    ("io", include_str!("assets/std/io.ks")),
+];
+
+/// The name of the module of the standard library which `target` (of an `!include`) refers
+/// to, if it does.
+pub fn std_module_name(target: &str) -> Option<&str> {
+    target.strip_prefix(STD_PREFIX)
+}
+
+/// The source of the module of the standard library called `name`, if there is one.
+pub fn std_module(name: &str) -> Option<&'static str> {
+    STD_MODULES
+        .iter()
+        .find(|(module, _)| *module == name)
+        .map(|(_, src)| *src)
+}
+
 // RUSTFIX remove duplication once `const fn`s become more powerful.
 static DEFAULT_BIOS_SRC: &str = include_str!("assets/default.bios.ks");
 static DEFAULT_PROG_SRC: &str = include_str!("assets/default.prog.ks");
@@ -0,0 +1,5 @@
+use kcpu::cli::lsp;
+
+fn main() -> anyhow::Result<()> {
+    lsp::run()
+}
@@ -1,7 +1,16 @@
 use kcpu::cli::command;
+use std::ffi::OsStr;
 use structopt::StructOpt;
 
 fn main() {
     command::terminal_init();
+
+    // NOTE: `kasm fmt` is the only subcommand, since otherwise `kasm` just takes a file to assemble.
+    if std::env::args_os().nth(1).as_deref() == Some(OsStr::new("fmt")) {
+        command::fmt(command::SubcommandFmt::from_iter(
+            std::env::args_os().skip(1),
+        ));
+    }
+
     command::asm(command::SubcommandAsm::from_args());
 }
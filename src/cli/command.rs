@@ -1,4 +1,5 @@
 use super::suite;
+use crate::assembler::{self, debuginfo::DebugInfo, diagnostic, listing::Listing, Define, Options};
 use crate::exec::{
     adaptor::{self, vram_access},
     event_loop::{headless, webgpu},
@@ -7,7 +8,7 @@ use crate::exec::{
     poller,
     types::{Backend, PipelineBuilder, PollerError, Runner, Snapshot},
 };
-use crate::{assembler, assets};
+use crate::{assets, spec::types::hw};
 use std::ffi::OsString;
 use std::{
     fmt::{Debug, Display},
@@ -22,9 +23,45 @@ pub fn terminal_init() {
 }
 
 pub fn assemble_path(path: &Path) -> Result<Vec<u8>, assembler::Error> {
-    // RUSTFIX proper IO error handling
-    let prog_src = std::fs::read_to_string(path).unwrap();
-    assembler::assemble_bytes(&prog_src)
+    assemble_path_with_options(path, &Options::default())
+}
+
+pub fn assemble_path_with_options(
+    path: &Path,
+    opts: &Options,
+) -> Result<Vec<u8>, assembler::Error> {
+    assembler::assemble_program_path(path, &assembler::source::FsLoader, opts)
+        .map(|assembly| assembly.bytes())
+}
+
+/// Unwrap the result of an assembly, or print its errors (with source snippets) and exit.
+fn or_report<T>(res: Result<T, assembler::Error>) -> T {
+    match res {
+        Ok(val) => val,
+        Err(err) => {
+            eprint!(
+                "{}",
+                diagnostic::render(&err, None, &assembler::source::FsLoader)
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Unwrap `res`, or print its error and exit.
+fn or_exit<T, E: Display>(res: Result<T, E>) -> T {
+    match res {
+        Ok(val) => val,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prefix an error with the path of the file it concerns.
+fn in_file<E: Display>(path: &Path) -> impl Fn(E) -> String + '_ {
+    move |err| format!("{}: {}", path.display(), err)
 }
 
 #[derive(StructOpt, Debug)]
@@ -34,11 +71,35 @@ pub enum CommandRoot {
     Asm(SubcommandAsm),
     Run(SubcommandRun),
     Suite(SubcommandSuite),
+    Link(SubcommandLink),
+    Fmt(SubcommandFmt),
+    Disasm(SubcommandDisasm),
 }
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kasm")]
 pub struct SubcommandAsm {
+    /// Write a relocatable object (for `kcpu link`) instead of a binary
+    #[structopt(short = "c")]
+    object: bool,
+
+    /// Also write a listing of the addresses and words emitted for each source line
+    #[structopt(long, name = "out.lst", parse(from_os_str), conflicts_with = "object")]
+    listing: Option<PathBuf>,
+
+    /// Also write debug info (label addresses and a source map) next to the binary
+    #[structopt(short = "g", long, conflicts_with = "object")]
+    debug_info: bool,
+
+    /// Define a constant, as if by `!equ NAME VALUE` at the top of the source (VALUE defaults to 1)
+    #[structopt(short = "D", name = "NAME=VALUE", number_of_values = 1)]
+    defines: Vec<Define>,
+
+    /// Optimise the generated code, selecting the no-flags variants of instructions and
+    /// rewriting short instruction sequences into cheaper ones
+    #[structopt(short = "O")]
+    optimize: bool,
+
     #[structopt(name = "in.ks", parse(from_os_str))]
     in_src: PathBuf,
 
@@ -46,6 +107,36 @@ pub struct SubcommandAsm {
     out_bin: Option<PathBuf>,
 }
 
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kasm fmt")]
+pub struct SubcommandFmt {
+    /// Do not rewrite any files, but list those which are not formatted (failing if there are any)
+    #[structopt(long)]
+    check: bool,
+
+    #[structopt(name = "in.ks", parse(from_os_str), required = true)]
+    in_srcs: Vec<PathBuf>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct SubcommandLink {
+    #[structopt(name = "in.ko", parse(from_os_str), required = true)]
+    in_objs: Vec<PathBuf>,
+
+    #[structopt(short, name = "out.kb", parse(from_os_str))]
+    out_bin: PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct SubcommandDisasm {
+    #[structopt(name = "in.kb", parse(from_os_str))]
+    in_bin: PathBuf,
+
+    /// Write the source to this file, instead of to stdout
+    #[structopt(short, name = "out.ks", parse(from_os_str))]
+    out_src: Option<PathBuf>,
+}
+
 #[derive(StructOpt, Debug)]
 struct VmOpts {
     #[structopt(short, long, name = "max-clocks")]
@@ -80,6 +171,10 @@ pub struct SubcommandRun {
     #[structopt(flatten)]
     vm_opts: VmOpts,
 
+    /// Define a constant in both sources, as if by `!equ NAME VALUE` (VALUE defaults to 1)
+    #[structopt(short = "D", name = "NAME=VALUE", number_of_values = 1)]
+    defines: Vec<Define>,
+
     #[structopt(name = "prog.ks", parse(from_os_str))]
     in_prog_src: PathBuf,
 
@@ -106,6 +201,10 @@ pub struct SuiteOpts {
 
     #[structopt(short, long, name = "max-clocks")]
     max_clocks: Option<ClockLimit>,
+
+    /// Assemble each unit with the optimiser enabled (as by `kasm -O`)
+    #[structopt(short = "O")]
+    optimize: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -147,20 +246,130 @@ pub fn root(cmd: CommandRoot) -> ! {
         CommandRoot::Vm(scmd) => vm(scmd),
         CommandRoot::Run(scmd) => run(scmd),
         CommandRoot::Suite(scmd) => suite(scmd),
+        CommandRoot::Link(scmd) => link(scmd),
+        CommandRoot::Fmt(scmd) => fmt(scmd),
+        CommandRoot::Disasm(scmd) => disasm(scmd),
     };
 }
 
 pub fn asm(cmd: SubcommandAsm) -> ! {
     // RUSTFIX proper error handling, instead of just calling `unwrap()`.
-    let out_bin = assemble_path(&cmd.in_src).unwrap();
+    let loader = assembler::source::FsLoader;
 
+    let ext = if cmd.object {
+        assets::DEFAULT_OBJECT_EXT
+    } else {
+        assets::DEFAULT_BINARY_EXT
+    };
     let out_name = match cmd.out_bin {
         Some(outfile) => outfile,
-        None => PathBuf::from(cmd.in_src.file_stem().unwrap())
-            .with_extension(assets::DEFAULT_BINARY_EXT),
+        None => PathBuf::from(cmd.in_src.file_stem().unwrap()).with_extension(ext),
+    };
+
+    let opts = Options {
+        defines: cmd.defines,
+        optimize: cmd.optimize,
     };
 
-    std::fs::write(out_name, out_bin).unwrap();
+    if cmd.object {
+        let (obj, warnings) =
+            or_report(assembler::assemble_object_path(&cmd.in_src, &loader, &opts));
+        eprint!("{}", diagnostic::render_warnings(&warnings, None, &loader));
+        std::fs::write(out_name, obj.write()).unwrap();
+    } else {
+        let assembly = or_report(assembler::assemble_program_path(
+            &cmd.in_src,
+            &loader,
+            &opts,
+        ));
+        eprint!(
+            "{}",
+            diagnostic::render_warnings(assembly.warnings(), None, &loader)
+        );
+
+        if let Some(listing_name) = cmd.listing {
+            let listing = Listing::new(None, assembly.placements());
+            std::fs::write(listing_name, listing.render(&loader)).unwrap();
+        }
+
+        if cmd.debug_info {
+            let debug_info = DebugInfo::new(assembly.placements());
+            std::fs::write(
+                out_name.with_extension(assets::DEFAULT_DEBUG_INFO_EXT),
+                debug_info.write(),
+            )
+            .unwrap();
+        }
+
+        std::fs::write(out_name, assembly.bytes()).unwrap();
+    }
+
+    std::process::exit(0);
+}
+
+pub fn fmt(cmd: SubcommandFmt) -> ! {
+    // RUSTFIX proper error handling, instead of just calling `unwrap()`.
+    let mut unformatted = false;
+    for path in cmd.in_srcs {
+        let source = std::fs::read_to_string(&path).unwrap();
+        let formatted = match assembler::format::format(&source) {
+            Ok(formatted) => formatted,
+            Err(err) => {
+                eprint!(
+                    "{}: {}",
+                    path.display(),
+                    diagnostic::render(&err, Some(&source), &assembler::source::FsLoader)
+                );
+                std::process::exit(1);
+            }
+        };
+
+        if formatted == source {
+            continue;
+        }
+
+        if cmd.check {
+            println!("{}", path.display());
+            unformatted = true;
+        } else {
+            std::fs::write(&path, formatted).unwrap();
+        }
+    }
+
+    std::process::exit(if unformatted { 1 } else { 0 });
+}
+
+pub fn link(cmd: SubcommandLink) -> ! {
+    let objs = cmd
+        .in_objs
+        .iter()
+        .map(|path| {
+            let text = or_exit(std::fs::read_to_string(path).map_err(in_file(path)));
+            or_exit(assembler::object::Object::read(&text).map_err(in_file(path)))
+        })
+        .collect::<Vec<_>>();
+    let out_bin = or_exit(assembler::object::link(&objs));
+
+    or_exit(
+        std::fs::write(&cmd.out_bin, hw::words_to_bytes(out_bin)).map_err(in_file(&cmd.out_bin)),
+    );
+
+    std::process::exit(0);
+}
+
+pub fn disasm(cmd: SubcommandDisasm) -> ! {
+    let bin = or_exit(std::fs::read(&cmd.in_bin).map_err(in_file(&cmd.in_bin)));
+    let words = or_exit(
+        hw::bytes_to_words(&bin)
+            .ok_or("The binary has an odd number of bytes")
+            .map_err(in_file(&cmd.in_bin)),
+    );
+    let src = assembler::recover::disassemble_program(&words);
+
+    match cmd.out_src {
+        Some(out_src) => or_exit(std::fs::write(&out_src, src).map_err(in_file(&out_src))),
+        None => print!("{}", src),
+    }
 
     std::process::exit(0);
 }
@@ -179,13 +388,15 @@ pub fn vm(cmd: SubcommandVm) -> ! {
 
 pub fn run(cmd: SubcommandRun) -> ! {
     // RUSTFIX proper error handling, instead of just calling `unwrap()`.
+    let opts = Options {
+        defines: cmd.defines,
+        ..Options::default()
+    };
     let bios_bin = cmd
         .in_bios_src
         .as_ref()
-        .map(|path| assemble_path(path))
-        .transpose()
-        .unwrap();
-    let prog_bin = assemble_path(&cmd.in_prog_src).unwrap();
+        .map(|path| or_report(assemble_path_with_options(path, &opts)));
+    let prog_bin = or_report(assemble_path_with_options(&cmd.in_prog_src, &opts));
 
     // RUSTFIX proper error handling in all of these, instead of just calling `unwrap()`.
     let snap = run_prog_with_opts(bios_bin.as_deref(), &prog_bin, cmd.vm_opts).unwrap();
@@ -201,7 +412,14 @@ pub fn suite(cmd: SubcommandSuite) -> ! {
             .suite_root_dir
             .unwrap_or_else(assets::default_suite_dir),
         cmd.opts.only.as_ref(),
-        cmd.opts.max_clocks.unwrap_or(ClockLimit(Some(50_000_000))).into_option(),
+        cmd.opts
+            .max_clocks
+            .unwrap_or(ClockLimit(Some(50_000_000)))
+            .into_option(),
+        &Options {
+            optimize: cmd.opts.optimize,
+            ..Options::default()
+        },
     )
     .unwrap();
 
@@ -0,0 +1,427 @@
+use crate::assembler::{
+    analysis::Analysis,
+    lang::Lang,
+    phases::{scope::Occurrence, types::Loc, types::Located},
+    source::{FsLoader, SourceLines, SourceLoader},
+    Options,
+};
+use lsp_server::{Connection, ExtractError, Message, Notification, Request, RequestId, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+        Notification as NotificationTrait, PublishDiagnostics,
+    },
+    request::{Completion, GotoDefinition, HoverRequest, References, Request as RequestTrait},
+    CompletionItem, CompletionItemKind, CompletionOptions, Diagnostic, DiagnosticSeverity,
+    GotoDefinitionResponse, Hover, HoverContents, HoverProviderCapability, Location, MarkupContent,
+    MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentPositionParams, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/*
+    The kasm language server (`kasm-lsp`), which speaks LSP over stdio. Each open document is
+    assembled (as the root file, with any `!include`s loaded from the open documents where
+    possible, and otherwise from the filesystem) whenever any open document changes, giving:
+
+        -   Diagnostics: the errors raised while assembling the document. Errors located in
+            other files are shown at the start of the document, together with their position.
+        -   Go-to-definition and find-references for labels and `!equ` constants (see
+            `assembler::analysis`).
+        -   Completion of the names of the instruction families of the built-in language.
+        -   Hover over an instruction name, showing the argument kinds accepted by each of the
+            variants of its family.
+
+    Positions are exchanged in UTF-16 code units, as LSP requires, and converted to and from the
+    (byte-based) columns of a `Loc` using the text of the line in question.
+*/
+
+/// The open documents, layered over the filesystem.
+#[derive(Default)]
+struct Documents {
+    texts: HashMap<PathBuf, String>,
+}
+
+impl SourceLoader for Documents {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        match self.texts.get(path) {
+            Some(text) => Ok(text.clone()),
+            None => FsLoader.load(path),
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// The number of UTF-16 code units in the first `col - 1` bytes of `line`.
+fn utf16_col(line: &str, col: usize) -> u32 {
+    let end = col.saturating_sub(1).min(line.len());
+    line.get(..end)
+        .unwrap_or(line)
+        .chars()
+        .map(char::len_utf16)
+        .sum::<usize>() as u32
+}
+
+/// The (1-based, byte) column of the `character`th UTF-16 code unit of `line`.
+fn byte_col(line: &str, character: u32) -> usize {
+    let mut units = 0;
+    for (idx, c) in line.char_indices() {
+        if units >= character as usize {
+            return idx + 1;
+        }
+        units += c.len_utf16();
+    }
+    line.len() + 1
+}
+
+/// The range spanning `len` bytes from `loc`, or if `len` is `None`, the rest of the token
+/// starting at `loc`.
+fn range(sources: &mut SourceLines, loc: &Loc, len: Option<usize>) -> Range {
+    let line = sources.line(loc).unwrap_or_default();
+    let len = len.unwrap_or_else(|| {
+        let rest = line.get(loc.col().saturating_sub(1)..).unwrap_or_default();
+        rest.find(char::is_whitespace).unwrap_or(rest.len())
+    });
+
+    let line_no = loc.line().saturating_sub(1) as u32;
+    Range::new(
+        Position::new(line_no, utf16_col(line, loc.col())),
+        Position::new(line_no, utf16_col(line, loc.col() + len)),
+    )
+}
+
+/// The location of the text of `occ`, which is in the file it names, or else in `root`.
+fn location(sources: &mut SourceLines, root: &Url, occ: &Located<Occurrence>) -> Location {
+    let loc = occ.loc().unwrap();
+    let url = loc
+        .file()
+        .and_then(|file| Url::from_file_path(&**file).ok())
+        .unwrap_or_else(|| root.clone());
+    Location::new(url, range(sources, loc, Some(occ.value_ref().name.len())))
+}
+
+/// The candidate argument lists of the family named `name`, as listed in the error raised when
+/// none of them accepts the arguments given.
+fn describe_family(lang: &Lang, name: &str) -> Option<String> {
+    let family = lang.lookup_family(name)?;
+
+    let mut out = format!(
+        "**{}**\n\ncandidate argument lists:\n",
+        family.name.to_uppercase()
+    );
+    for (alias, kinds) in lang.candidates(family) {
+        let kinds = kinds
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        out.push_str(&format!("- `{}`: {}\n", alias.to_uppercase(), kinds));
+    }
+    Some(out)
+}
+
+struct Server<'a> {
+    connection: &'a Connection,
+    docs: Documents,
+    analyses: HashMap<Url, Analysis>,
+}
+
+impl<'a> Server<'a> {
+    fn new(connection: &'a Connection) -> Self {
+        Server {
+            connection,
+            docs: Documents::default(),
+            analyses: HashMap::new(),
+        }
+    }
+
+    fn send(&self, msg: Message) -> anyhow::Result<()> {
+        self.connection.sender.send(msg)?;
+        Ok(())
+    }
+
+    fn diagnostics(&self, path: &Path, analysis: &Analysis) -> Vec<Diagnostic> {
+        let mut sources = SourceLines::new(None, &self.docs);
+        let here = path.display().to_string();
+
+        analysis
+            .errors()
+            .into_iter()
+            .map(|err| {
+                let mut message = err.message();
+                let range = match err.loc() {
+                    Some(loc) if loc.file().map(|f| &**f) == Some(here.as_str()) => {
+                        range(&mut sources, loc, None)
+                    }
+                    loc => {
+                        if let Some(loc) = loc {
+                            let file = loc.file().map(|f| f.to_string());
+                            message = format!(
+                                "{}:{}:{}: {}",
+                                file.as_deref().unwrap_or("<source>"),
+                                loc.line(),
+                                loc.col(),
+                                message
+                            );
+                        }
+                        Range::default()
+                    }
+                };
+
+                Diagnostic {
+                    range,
+                    severity: Some(DiagnosticSeverity::ERROR),
+                    source: Some("kasm".to_owned()),
+                    message: format!("[{}] {}", err.phase().unwrap_or("Assembler"), message),
+                    ..Diagnostic::default()
+                }
+            })
+            .collect()
+    }
+
+    /// Reassemble each of the open documents, and publish their diagnostics.
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        self.analyses.clear();
+        for path in self.docs.texts.keys() {
+            let url = match Url::from_file_path(path) {
+                Ok(url) => url,
+                Err(()) => continue,
+            };
+
+            let analysis = Analysis::of_path(path, &self.docs, &Options::default());
+            let params =
+                PublishDiagnosticsParams::new(url.clone(), self.diagnostics(path, &analysis), None);
+            self.send(Message::Notification(Notification::new(
+                PublishDiagnostics::METHOD.to_owned(),
+                params,
+            )))?;
+            self.analyses.insert(url, analysis);
+        }
+        Ok(())
+    }
+
+    fn handle_notification(&mut self, not: Notification) -> anyhow::Result<()> {
+        match not.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params = not
+                    .extract::<lsp_types::DidOpenTextDocumentParams>(DidOpenTextDocument::METHOD)?;
+                if let Ok(path) = params.text_document.uri.to_file_path() {
+                    self.docs.texts.insert(path, params.text_document.text);
+                }
+            }
+            DidChangeTextDocument::METHOD => {
+                let params = not.extract::<lsp_types::DidChangeTextDocumentParams>(
+                    DidChangeTextDocument::METHOD,
+                )?;
+                // NOTE: We only ask for full syncs, so the last change is the whole text.
+                if let (Ok(path), Some(change)) = (
+                    params.text_document.uri.to_file_path(),
+                    params.content_changes.into_iter().last(),
+                ) {
+                    self.docs.texts.insert(path, change.text);
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params = not.extract::<lsp_types::DidCloseTextDocumentParams>(
+                    DidCloseTextDocument::METHOD,
+                )?;
+                if let Ok(path) = params.text_document.uri.to_file_path() {
+                    self.docs.texts.remove(&path);
+                }
+                let params = PublishDiagnosticsParams::new(params.text_document.uri, vec![], None);
+                self.send(Message::Notification(Notification::new(
+                    PublishDiagnostics::METHOD.to_owned(),
+                    params,
+                )))?;
+            }
+            _ => return Ok(()),
+        }
+        self.refresh()
+    }
+
+    /// The file, line and column (as in a `Loc`) at `pos`, if the document is open.
+    fn locate(&self, pos: &TextDocumentPositionParams) -> Option<(String, usize, usize)> {
+        let path = pos.text_document.uri.to_file_path().ok()?;
+        let text = self.docs.texts.get(&path)?;
+        let line = text
+            .lines()
+            .nth(pos.position.line as usize)
+            .unwrap_or_default();
+        Some((
+            path.display().to_string(),
+            pos.position.line as usize + 1,
+            byte_col(line, pos.position.character),
+        ))
+    }
+
+    /// The name of the instruction or symbol at `pos`, if the document is open.
+    fn word_at(&self, pos: &TextDocumentPositionParams) -> Option<String> {
+        let path = pos.text_document.uri.to_file_path().ok()?;
+        let text = self.docs.texts.get(&path)?;
+        let line = text.lines().nth(pos.position.line as usize)?;
+        let at = byte_col(line, pos.position.character) - 1;
+
+        let start = line[..at]
+            .rfind(|c| !is_name_char(c))
+            .map_or(0, |idx| idx + 1);
+        let end = line[at..]
+            .find(|c| !is_name_char(c))
+            .map_or(line.len(), |idx| at + idx);
+        Some(line[start..end].to_owned()).filter(|word| !word.is_empty())
+    }
+
+    /// The appearances of the symbol at `pos` (only its definitions if `defs_only`).
+    fn symbol_locations(
+        &self,
+        pos: &TextDocumentPositionParams,
+        defs_only: bool,
+        include_defs: bool,
+    ) -> Vec<Location> {
+        let root = &pos.text_document.uri;
+        let (analysis, (file, line, col)) = match (self.analyses.get(root), self.locate(pos)) {
+            (Some(analysis), Some(at)) => (analysis, at),
+            _ => return vec![],
+        };
+
+        let occs = match defs_only {
+            true => analysis.definitions_at(Some(&file), line, col),
+            false => analysis.references_at(Some(&file), line, col, include_defs),
+        };
+
+        let mut sources = SourceLines::new(None, &self.docs);
+        occs.into_iter()
+            .map(|occ| location(&mut sources, root, occ))
+            .collect()
+    }
+
+    fn completions(&self) -> Vec<CompletionItem> {
+        let lang = Lang::get();
+        let mut names = lang
+            .family_iter()
+            .map(|family| family.name.to_uppercase())
+            .collect::<Vec<_>>();
+        names.sort();
+
+        names
+            .into_iter()
+            .map(|name| CompletionItem {
+                documentation: describe_family(lang, &name).map(|value| {
+                    lsp_types::Documentation::MarkupContent(MarkupContent {
+                        kind: MarkupKind::Markdown,
+                        value,
+                    })
+                }),
+                kind: Some(CompletionItemKind::KEYWORD),
+                label: name,
+                ..CompletionItem::default()
+            })
+            .collect()
+    }
+
+    fn handle_request(&mut self, req: Request) -> anyhow::Result<()> {
+        let req = match cast::<GotoDefinition>(req)? {
+            Ok((id, params)) => {
+                let locs = self.symbol_locations(&params.text_document_position_params, true, true);
+                return self.respond(id, Some(GotoDefinitionResponse::Array(locs)));
+            }
+            Err(req) => req,
+        };
+
+        let req = match cast::<References>(req)? {
+            Ok((id, params)) => {
+                let include_defs = params.context.include_declaration;
+                let locs =
+                    self.symbol_locations(&params.text_document_position, false, include_defs);
+                return self.respond(id, Some(locs));
+            }
+            Err(req) => req,
+        };
+
+        let req = match cast::<HoverRequest>(req)? {
+            Ok((id, params)) => {
+                let hover = self
+                    .word_at(&params.text_document_position_params)
+                    .and_then(|word| describe_family(Lang::get(), &word))
+                    .map(|value| Hover {
+                        contents: HoverContents::Markup(MarkupContent {
+                            kind: MarkupKind::Markdown,
+                            value,
+                        }),
+                        range: None,
+                    });
+                return self.respond(id, hover);
+            }
+            Err(req) => req,
+        };
+
+        match cast::<Completion>(req)? {
+            Ok((id, _)) => self.respond(id, Some(self.completions())),
+            Err(req) => self.send(Message::Response(Response::new_err(
+                req.id,
+                lsp_server::ErrorCode::MethodNotFound as i32,
+                format!("Unsupported request '{}'", req.method),
+            ))),
+        }
+    }
+
+    fn respond<R: serde::Serialize>(&self, id: RequestId, result: R) -> anyhow::Result<()> {
+        self.send(Message::Response(Response::new_ok(id, result)))
+    }
+}
+
+/// Interpret `req` as a request of kind `R`, or give it back if it is of another kind.
+fn cast<R>(req: Request) -> anyhow::Result<Result<(RequestId, R::Params), Request>>
+where
+    R: RequestTrait,
+{
+    match req.extract(R::METHOD) {
+        Ok(res) => Ok(Ok(res)),
+        Err(ExtractError::MethodMismatch(req)) => Ok(Err(req)),
+        Err(err @ ExtractError::JsonError { .. }) => Err(anyhow::anyhow!("{:?}", err)),
+    }
+}
+
+pub fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions::default()),
+        ..ServerCapabilities::default()
+    }
+}
+
+/// Serve a client over `connection`, from initialization until it asks us to exit.
+pub fn serve(connection: &Connection) -> anyhow::Result<()> {
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+
+    let mut server = Server::new(connection);
+    for msg in &connection.receiver {
+        match msg {
+            Message::Request(req) => {
+                if connection.handle_shutdown(&req)? {
+                    return Ok(());
+                }
+                server.handle_request(req)?;
+            }
+            Message::Notification(not) => server.handle_notification(not)?,
+            Message::Response(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Run the language server over stdio.
+pub fn run() -> anyhow::Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    serve(&connection)?;
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
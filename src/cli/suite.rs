@@ -1,4 +1,4 @@
-use crate::assembler;
+use crate::assembler::{self, Options};
 use crate::{
     exec::{
         event_loop,
@@ -28,16 +28,16 @@ struct UnitBin {
 }
 
 impl UnitSrc {
-    fn assemble(&self) -> Result<UnitBin, assembler::Error> {
+    fn assemble(&self, opts: &Options) -> Result<UnitBin, assembler::Error> {
         let bios_bin = self
             .bios_src
             .as_deref()
-            .map(super::command::assemble_path)
+            .map(|path| super::command::assemble_path_with_options(path, opts))
             .transpose()?;
         let prog_bin = self
             .prog_src
             .as_deref()
-            .map(super::command::assemble_path)
+            .map(|path| super::command::assemble_path_with_options(path, opts))
             .transpose()?;
 
         Ok(UnitBin::new(bios_bin, prog_bin))
@@ -61,6 +61,7 @@ pub fn run_suite(
     suite_root_dir: &PathBuf,
     only_this: Option<&OsString>,
     max_clocks: Option<u64>,
+    opts: &Options,
 ) -> Result<bool, assembler::Error> {
     let mut suite_dir = suite_root_dir.clone();
     suite_dir.push(suite_name);
@@ -82,6 +83,7 @@ pub fn run_suite(
     Ok(run_units(
         &suite_name.to_string_lossy(),
         max_clocks,
+        opts,
         &selected_units,
     ))
 }
@@ -143,7 +145,7 @@ fn find_units(suite_dir: &PathBuf) -> Vec<UnitSrc> {
 }
 
 // RUSTFIX run these in parallel using green threads, using `rayon`.
-fn run_units(name: &str, max_clocks: Option<u64>, units: &[UnitSrc]) -> bool {
+fn run_units(name: &str, max_clocks: Option<u64>, opts: &Options, units: &[UnitSrc]) -> bool {
     let name_pad = units.iter().map(|unit| unit.name.len()).max().unwrap_or(0);
 
     println!("Running suite: '{}' ({} units)", name, units.len());
@@ -155,7 +157,7 @@ fn run_units(name: &str, max_clocks: Option<u64>, units: &[UnitSrc]) -> bool {
     let passes = units
         .iter()
         .enumerate()
-        .filter(|(num, unit)| run_unit(unit, num + 1, name_pad, max_clocks))
+        .filter(|(num, unit)| run_unit(unit, num + 1, name_pad, max_clocks, opts))
         .count();
     let success = passes == units.len();
 
@@ -174,8 +176,14 @@ fn run_units(name: &str, max_clocks: Option<u64>, units: &[UnitSrc]) -> bool {
     success
 }
 
-fn run_unit(src: &UnitSrc, num: usize, name_pad: usize, max_clocks: Option<u64>) -> bool {
-    let summary = src.assemble().map(|bin| bin.execute(max_clocks));
+fn run_unit(
+    src: &UnitSrc,
+    num: usize,
+    name_pad: usize,
+    max_clocks: Option<u64>,
+    opts: &Options,
+) -> bool {
+    let summary = src.assemble(opts).map(|bin| bin.execute(max_clocks));
 
     let (success, msg) = match summary {
         Err(err) => (
@@ -1,11 +1,30 @@
 use itertools::iproduct;
 
-pub fn accumulate_vecs<T, E>(it: impl Iterator<Item = Result<Vec<T>, E>>) -> Result<Vec<T>, E> {
-    let mut result = Vec::new();
-    for ts in it {
-        result.append(&mut ts?)
+/// Collect the `Ok` values of `it`, or if there are any failures, every error (stopping once
+/// `limit` of them have been encountered).
+pub fn collect_all<T, E>(
+    it: impl Iterator<Item = Result<T, E>>,
+    limit: usize,
+) -> Result<Vec<T>, Vec<E>> {
+    let mut oks = Vec::new();
+    let mut errs = Vec::new();
+    for res in it {
+        match res {
+            Ok(t) => oks.push(t),
+            Err(e) => {
+                errs.push(e);
+                if errs.len() >= limit {
+                    break;
+                }
+            }
+        }
+    }
+
+    if errs.is_empty() {
+        Ok(oks)
+    } else {
+        Err(errs)
     }
-    Ok(result)
 }
 
 pub fn eq_ignore_case(a: &str, b: &str) -> bool {
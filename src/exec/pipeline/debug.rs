@@ -6,11 +6,15 @@ use super::super::{
     },
 };
 use crate::{
-    assembler::disasm::{self, SteppingDisassembler},
+    assembler::{
+        disasm::{self, SteppingDisassembler},
+        recover::Program,
+    },
     exec::interactive::InteractiveFrontend,
     spec::types::hw::Word,
-    vm::{self, debug},
+    vm::{self, debug, BankType},
 };
+use enum_map::{enum_map, EnumMap};
 
 pub struct Builder<I: Interactor<State = DebugReport, Action = Command>> {
     interactor: I,
@@ -93,14 +97,25 @@ pub enum Command {
 // RUSTFIX 'static everywhere!
 pub struct Backend {
     disasm: SteppingDisassembler<'static>,
+    /// The code in the binary loaded into each bank, found before execution begins.
+    code: EnumMap<BankType, Program>,
     vm: vm::Instance<'static>,
 }
 
 // RUSTFIX 'static everywhere!
 impl Backend {
     pub fn new(vm: vm::Instance<'static>) -> Result<Self, disasm::Error> {
-        let disasm = SteppingDisassembler::new(&mut vm.iter_at_ip())?;
-        Ok(Self { disasm, vm })
+        let code = enum_map! { typ => Program::new(vm.loaded(typ)) };
+        let known = Backend::known_context(&code, &vm);
+        let disasm = SteppingDisassembler::new(known, &mut vm.iter_at_ip())?;
+        Ok(Self { disasm, code, vm })
+    }
+
+    fn known_context(
+        code: &EnumMap<BankType, Program>,
+        vm: &vm::Instance,
+    ) -> Option<disasm::Context<'static>> {
+        code[vm.code_bank()].context_at(vm.ip())
     }
 }
 
@@ -122,7 +137,8 @@ impl TraitBackend for Backend {
                 let phase = self.vm.debug_exec_phase();
 
                 if let debug::ExecPhase::Load(0) = phase {
-                    self.disasm.step(self.vm.iter_at_ip())?;
+                    let known = Backend::known_context(&self.code, &self.vm);
+                    self.disasm.step(known, self.vm.iter_at_ip())?;
                 }
 
                 if break_on.should_break(phase) {
@@ -468,6 +468,59 @@ fn gen_jmp_loadables(builder: &mut Builder, ld: bool) {
         GCTRL_JCOND_N_OVFLW,
     ));
 
+    // The compound conditions, for after a `CMP a b`: unsigned (above/below) and signed
+    // (greater/less) comparisons of `a` with `b`.
+    builder.register(mk_loadable_instruction(
+        ld,
+        ITFLAG_JMP_LD,
+        "JA",
+        I_JA,
+        false,
+        GCTRL_JCOND_ABOVE,
+    ));
+    builder.register(mk_loadable_instruction(
+        ld,
+        ITFLAG_JMP_LD,
+        "JBE",
+        I_JBE,
+        false,
+        GCTRL_JM_INVERTCOND | GCTRL_JCOND_ABOVE,
+    ));
+
+    builder.register(mk_loadable_instruction(
+        ld,
+        ITFLAG_JMP_LD,
+        "JGE",
+        I_JGE,
+        false,
+        GCTRL_JCOND_N_LESS,
+    ));
+    builder.register(mk_loadable_instruction(
+        ld,
+        ITFLAG_JMP_LD,
+        "JL",
+        I_JL,
+        false,
+        GCTRL_JM_INVERTCOND | GCTRL_JCOND_N_LESS,
+    ));
+
+    builder.register(mk_loadable_instruction(
+        ld,
+        ITFLAG_JMP_LD,
+        "JG",
+        I_JG,
+        false,
+        GCTRL_JCOND_GREATER,
+    ));
+    builder.register(mk_loadable_instruction(
+        ld,
+        ITFLAG_JMP_LD,
+        "JLE",
+        I_JLE,
+        false,
+        GCTRL_JM_INVERTCOND | GCTRL_JCOND_GREATER,
+    ));
+
     builder.register(mk_loadable_instruction_with_preamble(
         ld,
         ITFLAG_JMP_LD,
@@ -744,6 +797,97 @@ fn gen_alu(builder: &mut Builder) {
         "",
         ALU_OUTMODE_FLAGSONLY,
     ));
+
+    // The instructions which take the carry in from the CARRY flag, for arithmetic across
+    // several words, and the rotates. These have no NOFLAGS (NF) variants, since their
+    // flags are usually consumed by the next instruction.
+    builder.register(mk_alu_inst(
+        "ADC2",
+        I_ADC2,
+        vec![
+            ArgKind::new_word(ConstPolicy::Allow),
+            ArgKind::new_word(ConstPolicy::Never),
+        ],
+        ACTRL_MODE_ADC,
+        false,
+        0,
+        false,
+        "",
+        ALU_OUTMODE_NORMAL,
+    ));
+    builder.register(mk_alu_inst(
+        "ADC3",
+        I_ADC3,
+        vec![
+            ArgKind::new_word(ConstPolicy::Allow),
+            ArgKind::new_word(ConstPolicy::Allow),
+            ArgKind::new_word(ConstPolicy::Never),
+        ],
+        ACTRL_MODE_ADC,
+        false,
+        0,
+        false,
+        "",
+        ALU_OUTMODE_NORMAL,
+    ));
+    builder.register(mk_alu_inst(
+        "SBB",
+        I_SBB,
+        vec![
+            ArgKind::new_word(ConstPolicy::Allow),
+            ArgKind::new_word(ConstPolicy::Never),
+        ],
+        ACTRL_MODE_SBB,
+        false,
+        0,
+        false,
+        "",
+        ALU_OUTMODE_NORMAL,
+    ));
+    builder.register(mk_alu_inst(
+        "RCL",
+        I_RCL,
+        vec![ArgKind::new_word(ConstPolicy::Never)],
+        ACTRL_MODE_RCL,
+        false,
+        0,
+        false,
+        "",
+        ALU_OUTMODE_NORMAL,
+    ));
+    builder.register(mk_alu_inst(
+        "RCR",
+        I_RCR,
+        vec![ArgKind::new_word(ConstPolicy::Never)],
+        ACTRL_MODE_RCR,
+        false,
+        0,
+        false,
+        "",
+        ALU_OUTMODE_NORMAL,
+    ));
+    builder.register(mk_alu_inst(
+        "ROL",
+        I_ROL,
+        vec![ArgKind::new_word(ConstPolicy::Never)],
+        ACTRL_MODE_ROL,
+        false,
+        0,
+        false,
+        "",
+        ALU_OUTMODE_NORMAL,
+    ));
+    builder.register(mk_alu_inst(
+        "ROR",
+        I_ROR,
+        vec![ArgKind::new_word(ConstPolicy::Never)],
+        ACTRL_MODE_ROR,
+        false,
+        0,
+        false,
+        "",
+        ALU_OUTMODE_NORMAL,
+    ));
 }
 
 fn gen_stk(builder: &mut Builder) {
@@ -49,6 +49,9 @@ pub const IT_ALU2   : Segment =           0b0111; // ALU (other ALU insts)
 pub const IT_IU3_ALL_GRP1: Segment =      0b1000;
 pub const IT_IU3_ALL_GRP2: Segment =      0b1001;
 pub const IT_IU3_ALL_GRP3: Segment =      0b1010;
+pub const IT_IU3_ALL_GRP4: Segment =      0b1011;
+pub const _IT_JCOND : Segment =           0b1100; // JCOND (compound jumps, don't use directly, use IT_JCOND and ITFLAG_JMP_LD instead)
+pub const _IT_JCONDLD: Segment =          0b1101; // JCOND (compound jumps, don't use directly, use IT_JCOND and ITFLAG_JMP_LD instead)
 // Don't forget that the `GCTRL_NRM_IU3_OVERRIDE_O_SELECT_RSP_I__UNUSED` mechanism exists largely
 // to prevent the creation of IU3_SINGLE opclassess.
 // pub const IT_IU3_SINGLE_GRP1 0b1000 // It is very wasteful to have a separate itype for IU3_SINGLEs, but lets just be lazy for now.
@@ -60,6 +63,7 @@ pub const IT_IU3_ALL_GRP3: Segment =      0b1010;
 // Fake ICs (to implement flags) and flags at the itype/icode level
 pub const IT_MEM : Segment = 0b0010;
 pub const IT_JMP : Segment = 0b0100;
+pub const IT_JCOND : Segment = 0b1100;
 pub const ITFLAG_MEM_FAR     : Segment = mk_itflag(0b0001);
 pub const ITFLAG_JMP_LD      : Segment = mk_itflag(0b0001);
 pub const ICFLAG_ALU1_NOFGS  : Segment = mk_icflag(0b1000);
@@ -110,9 +114,15 @@ pub const I_XOR       : OpClass = OpClass::new(IT_ALU1, 0b0101);
 pub const I_LSFT      : OpClass = OpClass::new(IT_ALU1, 0b0110);
 pub const I_RSFT      : OpClass = OpClass::new(IT_ALU1, 0b0111);
 
-// ALU2 (2/16)
+// ALU2 (8/16)
 pub const I_TST       : OpClass = OpClass::new(IT_ALU2, 0b0000);
 pub const I_CMP       : OpClass = OpClass::new(IT_ALU2, 0b0001);
+pub const I_ADC2      : OpClass = OpClass::new(IT_ALU2, 0b0010);
+pub const I_SBB       : OpClass = OpClass::new(IT_ALU2, 0b0011);
+pub const I_RCL       : OpClass = OpClass::new(IT_ALU2, 0b0100);
+pub const I_RCR       : OpClass = OpClass::new(IT_ALU2, 0b0101);
+pub const I_ROL       : OpClass = OpClass::new(IT_ALU2, 0b0110);
+pub const I_ROR       : OpClass = OpClass::new(IT_ALU2, 0b0111);
 
 // MEM (9/16)
 pub const I_STPFX     : OpClass = OpClass::new(IT_MEM, 0b0001);
@@ -141,6 +151,14 @@ pub const I_LJMP      : OpClass = OpClass::new(IT_JMP, 0b1001);
 pub const I_JMP_DI    : OpClass = OpClass::new(IT_JMP, 0b1110);
 pub const I_JMP_EI    : OpClass = OpClass::new(IT_JMP, 0b1111);
 
+// JCOND (6/16)
+pub const I_JA        : OpClass = OpClass::new(IT_JCOND, 0b0000);
+pub const I_JBE       : OpClass = OpClass::new(IT_JCOND, 0b0100);
+pub const I_JGE       : OpClass = OpClass::new(IT_JCOND, 0b0001);
+pub const I_JL        : OpClass = OpClass::new(IT_JCOND, 0b0101);
+pub const I_JG        : OpClass = OpClass::new(IT_JCOND, 0b0010);
+pub const I_JLE       : OpClass = OpClass::new(IT_JCOND, 0b0110);
+
 // IU3_ALL_GRP1 (2/2);
 pub const I_ADD3      : OpClass = OpClass::with_iu3_all(IT_IU3_ALL_GRP1, 0b0);
 pub const _I_ADD3NF    : OpClass = OpClass::with_iu3_all(IT_IU3_ALL_GRP1, 0b1); // REMINDER UNREFERENCED, use I_ADD3 and ICFLAG_ADD3_IU3_NF instead.
@@ -153,4 +171,7 @@ pub const _I_LDWO_FAR  : OpClass = OpClass::with_iu3_all(IT_IU3_ALL_GRP2, 0b1);
 pub const I_STWO      : OpClass = OpClass::with_iu3_all(IT_IU3_ALL_GRP3, 0b0);
 pub const _I_STWO_FAR  : OpClass = OpClass::with_iu3_all(IT_IU3_ALL_GRP3, 0b1); // REMINDER UNREFERENCED, use I_STWO and ICFLAG_MEM_IU3_FAR instead.
 
+// IU3_ALL_GRP4 (1/2);
+pub const I_ADC3      : OpClass = OpClass::with_iu3_all(IT_IU3_ALL_GRP4, 0b0);
+
 // END DECLS
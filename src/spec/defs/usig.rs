@@ -130,6 +130,13 @@ pub const GCTRL_JCOND_N_OVFLW: UInst = mk_val(GCTRL_BASE, 0, 0b1011);
 // NOT A REAL BIT, JUST A HELPER FOR THE 4 FLAG JMs
 pub const GCTRL_JM_INVERTCOND : UInst = mk_val(GCTRL_BASE, 0, 0b0100);
 
+// The compound JCONDs, which are the JCONDs above together with GCTRL_NRM_JCOND_COMPOUND
+// (see below), and test a combination of the flags left by a CMP (or SUB):
+pub const GCTRL_JCOND_ABOVE  : UInst = GCTRL_NRM_JCOND_COMPOUND | GCTRL_JCOND_CARRY;  // CARRY && N_ZERO
+pub const GCTRL_JCOND_N_LESS : UInst = GCTRL_NRM_JCOND_COMPOUND | GCTRL_JCOND_N_ZERO; // SIGN != N_OVFLW
+pub const GCTRL_JCOND_GREATER: UInst = GCTRL_NRM_JCOND_COMPOUND | GCTRL_JCOND_SIGN;   // N_ZERO && SIGN != N_OVFLW
+pub const _GCTRL_JCOND__UNUSED: UInst = GCTRL_NRM_JCOND_COMPOUND | GCTRL_JCOND_N_OVFLW;
+
 // The GCTRL modes
 
 /*
@@ -144,7 +151,13 @@ pub const GCTRL_NRM_IO_READWRITE: UInst = mk_val(GCTRL_BASE, 4, 0b01);
     GCTRL_CREG_O means to force IU3 to RSP. GCTRL_CREG_I is UNUSED.
 */
 pub const GCTRL_NRM_IU3_OVERRIDE_O_SELECT_RSP_I__UNUSED: UInst = mk_val(GCTRL_BASE, 4, 0b10);
-pub const _GCTRL_NRM__UNUSED     : UInst = mk_val(GCTRL_BASE, 4, 0b11);
+/*
+    HARDWARE NOTE: GCTRL_NRM_JCOND_COMPOUND means that the JCOND (which
+    must be present) selects one of the compound conditions instead,
+    which are decoded from the ALU bits of FG by a little extra logic.
+    GCTRL_CREG_I and GCTRL_CREG_O are UNUSED.
+*/
+pub const GCTRL_NRM_JCOND_COMPOUND: UInst = mk_val(GCTRL_BASE, 4, 0b11);
 
 /*
     These "alternate" modes occupy the same bits as the "normal" modes,
@@ -190,6 +203,10 @@ pub fn is_gctrl_nrm_io_readwrite(ui: UInst) -> bool {
     ((ui & MASK_CTRL_ACTION) != ACTION_GCTRL_USE_ALT) && ((ui & MASK_GCTRL_MODE) == GCTRL_NRM_IO_READWRITE)
 }
 
+pub fn is_gctrl_nrm_jcond_compound(ui: UInst) -> bool {
+    ((ui & MASK_CTRL_ACTION) != ACTION_GCTRL_USE_ALT) && ((ui & MASK_GCTRL_MODE) == GCTRL_NRM_JCOND_COMPOUND)
+}
+
 pub fn does_override_iu3_via_command(ui: UInst) -> bool {
     (ui & MASK_CTRL_COMMAND) == COMMAND_RCTRL_RSP_EARLY_DEC_IU3RSP
 }
@@ -200,6 +217,45 @@ pub fn does_override_iu3_via_gctrl_alt(ui: UInst) -> bool {
                 && ((ui & MASK_GCTRL_DIR) == GCTRL_CREG_O)
 }
 
+// NONBIT: How a uop uses the flags and RIP (for the assembler's optimiser).
+pub fn does_read_flags(ui: UInst) -> bool {
+    let outputs_fg = ((ui & MASK_CTRL_ACTION) == ACTION_GCTRL_USE_ALT)
+                && ((ui & MASK_GCTRL_MODE) == GCTRL_ALT_CREG_FG)
+                && gctrl_creg_is_output(ui);
+    outputs_fg || (ui & MASK_GCTRL_FTJM) >= GCTRL_JCOND_CARRY || actrl_reads_carry(ui)
+}
+
+pub fn does_write_flags(ui: UInst) -> bool {
+    ((ui & MASK_CTRL_ACTION) == ACTION_GCTRL_USE_ALT)
+                && (((ui & MASK_GCTRL_MODE) == GCTRL_ALT_CREG_FG)
+                    || ((ui & MASK_GCTRL_MODE) == GCTRL_ALT_P_O_CHNMI_OR_I_ALUFG))
+                && gctrl_creg_is_input(ui)
+}
+
+pub fn does_jump(ui: UInst) -> bool {
+    (ui & MASK_GCTRL_FTJM) >= GCTRL_JM_YES
+}
+
+// NONBIT: How a uop passes control on (for the disassembler).
+pub fn does_halt(ui: UInst) -> bool {
+    let jm = ui & MASK_GCTRL_FTJM;
+    jm == GCTRL_JM_HALT || jm == GCTRL_JM_ABRT
+}
+
+pub fn does_save_rip(ui: UInst) -> bool {
+    (ui & MASK_GCTRL_FTJM) == GCTRL_JM_P_RIP_BUSB_O
+}
+
+pub fn does_jump_conditionally(ui: UInst) -> bool {
+    (ui & MASK_GCTRL_FTJM) >= GCTRL_JCOND_CARRY
+}
+
+pub fn does_set_prefix(ui: UInst) -> bool {
+    let mode = ui & MASK_MCTRL_MODE;
+    ((ui & MASK_MCTRL_BUSMODE) != MCTRL_BUSMODE_DISABLE)
+                && (mode == MCTRL_MODE_STPFX || mode == MCTRL_MODE_STPFX_FAR)
+}
+
 // RCTRL
 pub const RCTRL_BASE : u32 = GCTRL_END;
 pub const RCTRL_END : u32 = RCTRL_BASE + 9;
@@ -259,6 +315,13 @@ pub const fn rctrl_iu_is_output(dec: u16) -> bool {
     0b001 & dec != 0
 }
 
+pub fn rctrl_busb_output_iu(val: UInst) -> Option<IU> {
+    [IU::ONE, IU::TWO, IU::THREE].iter().copied().find(|iu| {
+        let dec = rctrl_decode_iu(*iu, val);
+        rctrl_iu_is_en(dec) && matches!(rctrl_iu_to_bus(dec), Bus::B) && rctrl_iu_is_output(dec)
+    })
+}
+
 // MCTRL
 pub const MCTRL_BASE : u32 = RCTRL_END;
 pub const MCTRL_END : u32 = MCTRL_BASE + 6;
@@ -317,11 +380,36 @@ pub const ACTRL_MODE_LSFT: UInst = mk_val(ACTRL_BASE, 3, 5);
 pub const ACTRL_MODE_RSFT: UInst = mk_val(ACTRL_BASE, 3, 6);
 pub const ACTRL_MODE_TST: UInst =  mk_val(ACTRL_BASE, 3, 7);
 
+// HARDWARE NOTE: The ALU never outputs while it inputs, so alongside ACTRL_INPUT_EN
+// the bit of ACTRL_FLAGS_OUT instead selects the extended modes below.
+// ADC, SBB, RCL and RCR take their carry in from the CARRY bit of FG.
+pub const ACTRL_MODE_EXT: UInst = mk_val(ACTRL_BASE, 2, 1);
+
+pub const ACTRL_MODE_ADC: UInst = ACTRL_MODE_EXT | mk_val(ACTRL_BASE, 3, 0);
+pub const ACTRL_MODE_SBB: UInst = ACTRL_MODE_EXT | mk_val(ACTRL_BASE, 3, 1);
+pub const ACTRL_MODE_RCL: UInst = ACTRL_MODE_EXT | mk_val(ACTRL_BASE, 3, 2);
+pub const ACTRL_MODE_RCR: UInst = ACTRL_MODE_EXT | mk_val(ACTRL_BASE, 3, 3);
+pub const ACTRL_MODE_ROL: UInst = ACTRL_MODE_EXT | mk_val(ACTRL_BASE, 3, 4);
+pub const ACTRL_MODE_ROR: UInst = ACTRL_MODE_EXT | mk_val(ACTRL_BASE, 3, 5);
+pub const _ACTRL_MODE__UNUSED_1: UInst = ACTRL_MODE_EXT | mk_val(ACTRL_BASE, 3, 6);
+pub const _ACTRL_MODE__UNUSED_2: UInst = ACTRL_MODE_EXT | mk_val(ACTRL_BASE, 3, 7);
+
 // NONBIT: ACTRL decoding
 pub const MASK_ACTRL_MODE: UInst = mk_val(ACTRL_BASE, 3, 0b111);
 
+// NOTE: Only meaningful alongside ACTRL_INPUT_EN, giving the extended modes the values 8 to 15.
 pub const fn decode_actrl_mode(ui: UInst) -> u8 {
-    ((ui & MASK_ACTRL_MODE) >> (3 + ACTRL_BASE)) as u8 
+    let ext = if ui & ACTRL_MODE_EXT != 0 { 0b1000 } else { 0 };
+    ext | ((ui & MASK_ACTRL_MODE) >> (3 + ACTRL_BASE)) as u8
+}
+
+pub const fn actrl_is_flags_out(ui: UInst) -> bool {
+    (ui & ACTRL_FLAGS_OUT != 0) && (ui & ACTRL_INPUT_EN == 0)
+}
+
+pub const fn actrl_reads_carry(ui: UInst) -> bool {
+    (ui & ACTRL_INPUT_EN != 0) && matches!(ui & (ACTRL_MODE_EXT | MASK_ACTRL_MODE),
+                ACTRL_MODE_ADC | ACTRL_MODE_SBB | ACTRL_MODE_RCL | ACTRL_MODE_RCR)
 }
 
 #[allow(unused)]
@@ -1,7 +1,7 @@
 use bitflags::bitflags;
-use std::{fmt, num::Wrapping};
+use std::fmt;
 
-use super::types::*;
+use super::{interface, types::*};
 use crate::{spec::defs::usig, spec::types::hw::*};
 use fmt::Display;
 
@@ -53,9 +53,12 @@ impl Default for OpResult {
 }
 
 enum OpFunc {
-    Arithmetic(fn(i16, i16) -> (i16, u32)),
+    /// Gives the two addends (and the carry in) which the adder sums, given the operands and the
+    /// incoming carry.
+    Arithmetic(fn(u16, u16, bool) -> (u16, u16, bool)),
     Logic(fn(u16, u16) -> u16),
-    Shift(fn(u16, u16) -> (u16, bool)),
+    /// Gives the result and the bit shifted out, given the operands and the incoming carry.
+    Shift(fn(u16, u16, bool) -> (u16, bool)),
 }
 
 fn is_flag_n_zero(val: u16) -> bool {
@@ -86,25 +89,28 @@ impl OpFunc {
     // RUSTFIX proper docs?
     /// `eval` takes `Word`s and outputs a `Word` (plus `Flags`), and thus provides the bridge between the
     /// VM and the arithmetic implementation in Rust.
-    fn eval(&self, a: Word, b: Word) -> OpResult {
-        let (val, carry) = match self {
+    fn eval(&self, a: Word, b: Word, carry_in: bool) -> OpResult {
+        // The signed overflow is determined by the addends of the adder, which for a subtraction
+        // are not the operands themselves.
+        let (x, y, val, carry) = match self {
             Self::Arithmetic(f) => {
-                let (val, carry_val) = f(a as i16, b as i16);
-                (val as u16, carry_val > u16::MAX as u32)
+                let (x, y, carry_in) = f(a, b, carry_in);
+                let sum = x as u32 + y as u32 + carry_in as u32;
+                (x, y, sum as u16, sum > u16::MAX as u32)
             }
             Self::Logic(f) => {
                 let val = f(a as u16, b as u16);
-                (val, val & 0x0001 != 0)
+                (a, b, val, val & 0x0001 != 0)
             }
             Self::Shift(f) => {
-                let (val, dropped) = f(a as u16, b as u16);
-                (val, dropped)
+                let (val, dropped) = f(a as u16, b as u16, carry_in);
+                (a, b, val, dropped)
             }
         };
 
         let n_zero = is_flag_n_zero(val);
         let sign = is_flag_sign(val);
-        let n_overflow = is_flag_n_overflow(val as i16, a as i16, b as i16);
+        let n_overflow = is_flag_n_overflow(val as i16, x as i16, y as i16);
 
         OpResult {
             val,
@@ -138,23 +144,29 @@ impl<'a> Op<'a> {
 // RUSTFIX it was very easy to make an error here, write good tests for these and compare the Rust and C++ versions.
 
 #[rustfmt::skip]
-static OPS: [Op; 8] = [
+static OPS: [Op; 14] = [
     // RUSTFIX FIXME FIXME FIXME CHECK CHECK CHEK
-    // HARDWARE NOTE IN THE RUST IMPLEMENTATION WE added "(a as u16)" (used to be just "a") and similarly for "b" in the calculatiuon of the carry val below, due to a
-    // sign extension-caused overflow. CHECK THAT THE ARDUINO CODE VERIFIES THE HARDWARE OBEYS THIS, AND FIX THE C++ VERISON AS WELL.
-    Op::new(usig::ACTRL_MODE_ADD , "+" , OpFunc::Arithmetic(|a, b| ((Wrapping(a) + Wrapping(b)).0, ((a as u16) as u32) + ((b as u16) as u32)))),
-    // RUSTFIX FIXME FIXME FIXME CHECK CHECK CHEK
-    // HARDWARE NOTE IN THE RUST IMPLEMENTATION WE added "(b as u16)" (used to be just "b") in the calculatiuon of the carry val below, due to a
-    // sign extension overflow. CHECK THAT THE ARDUINO CODE VERIFIES THE HARDWARE OBEYS THIS, AND FIX THE C++ VERISON AS WELL.
-    Op::new(usig::ACTRL_MODE_SUB , "-" , OpFunc::Arithmetic(|a, b| ((Wrapping(b) - Wrapping(a)).0, (((!a as u16) as u32) + 1) + ((b as u16) as u32)))),
+    // HARDWARE NOTE IN THE RUST IMPLEMENTATION the carry val is calculated from the unsigned addends (it used to be calculated from the
+    // signed operands, which overflowed due to sign extension). CHECK THAT THE ARDUINO CODE VERIFIES THE HARDWARE OBEYS THIS, AND FIX THE C++ VERISON AS WELL.
+    Op::new(usig::ACTRL_MODE_ADD , "+" , OpFunc::Arithmetic(|a, b, _| (a, b, false))),
+    // HARDWARE NOTE: The result is `b + !a + 1`, so the overflow flag is calculated from `b` and `!a` (it used to be calculated
+    // from `a` and `b` as for an addition, which broke the signed comparisons).
+    Op::new(usig::ACTRL_MODE_SUB , "-" , OpFunc::Arithmetic(|a, b, _| (!a, b, true))),
     Op::new(usig::ACTRL_MODE_AND , "&" , OpFunc::Logic(|a, b| (a & b))),
     Op::new(usig::ACTRL_MODE_OR  , "|" , OpFunc::Logic(|a, b| (a | b))),
     Op::new(usig::ACTRL_MODE_XOR , "^" , OpFunc::Logic(|a, b| (a ^ b))),
-    Op::new(usig::ACTRL_MODE_LSFT, "<<", OpFunc::Shift(|a, _| (a << 1, a & 0x8000 != 0))),
-    Op::new(usig::ACTRL_MODE_RSFT, ">>", OpFunc::Shift(|a, _| (a >> 1, a & 0x0001 != 0))),
+    Op::new(usig::ACTRL_MODE_LSFT, "<<", OpFunc::Shift(|a, _, _| (a << 1, a & 0x8000 != 0))),
+    Op::new(usig::ACTRL_MODE_RSFT, ">>", OpFunc::Shift(|a, _, _| (a >> 1, a & 0x0001 != 0))),
     // Not implemented in hardware:
-    // Op::new(usig::ACTRL_MODE_ARSFT, "A>>", OpFunc::Shift(|a, _| (((a as i16) >> 1) as u16, a & 0x0001 != 0))),
+    // Op::new(usig::ACTRL_MODE_ARSFT, "A>>", OpFunc::Shift(|a, _, _| (((a as i16) >> 1) as u16, a & 0x0001 != 0))),
     Op::new(usig::ACTRL_MODE_TST, "TST", OpFunc::Logic(|a, _| a)),
+    // The extended modes. Since CARRY is set exactly when there is no borrow, SBB computes `b - a - !CARRY`.
+    Op::new(usig::ACTRL_MODE_ADC , "+C" , OpFunc::Arithmetic(|a, b, c| (a, b, c))),
+    Op::new(usig::ACTRL_MODE_SBB , "-C" , OpFunc::Arithmetic(|a, b, c| (!a, b, c))),
+    Op::new(usig::ACTRL_MODE_RCL , "<<C", OpFunc::Shift(|a, _, c| ((a << 1) | (c as u16), a & 0x8000 != 0))),
+    Op::new(usig::ACTRL_MODE_RCR , ">>C", OpFunc::Shift(|a, _, c| ((a >> 1) | ((c as u16) << 15), a & 0x0001 != 0))),
+    Op::new(usig::ACTRL_MODE_ROL , "<<<", OpFunc::Shift(|a, _, _| (a.rotate_left(1), a & 0x8000 != 0))),
+    Op::new(usig::ACTRL_MODE_ROR , ">>>", OpFunc::Shift(|a, _, _| (a.rotate_right(1), a & 0x0001 != 0))),
 ];
 
 pub struct Alu<'a> {
@@ -186,20 +198,19 @@ impl<'a> Alu<'a> {
             s.assign(Bus::A, self.result.val);
         }
 
-        if ui & usig::ACTRL_FLAGS_OUT != 0 {
-            assert!(ui & usig::ACTRL_INPUT_EN == 0);
+        if usig::actrl_is_flags_out(ui) {
             s.assign(Bus::B, Word::from(self.result.flags));
         }
     }
 
-    pub fn clock_inputs(&mut self, ui: UInst, s: &BusState) {
+    pub fn clock_inputs(&mut self, ui: UInst, s: &BusState, ctl: &dyn interface::Ctl) {
         if ui & usig::ACTRL_INPUT_EN != 0 {
             let mode = usig::decode_actrl_mode(ui);
             let op = &OPS[mode as usize];
             assert!(mode == usig::decode_actrl_mode(op.ui_mode));
 
             let (bus_a, bus_b) = (s.read(Bus::A), s.read(Bus::B));
-            self.result = op.func.eval(bus_a, bus_b);
+            self.result = op.func.eval(bus_a, bus_b, ctl.is_carry_set());
 
             if self.log_level.internals {
                 println!("{}({:#06X}, {:#06X}) -> {}", op, bus_a, bus_b, self.result);
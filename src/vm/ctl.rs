@@ -228,7 +228,9 @@ impl<'a> Ctl<'a> {
             }
         } else {
             match ui & usig::MASK_GCTRL_MODE {
-                usig::GCTRL_NRM_IU3_OVERRIDE_O_SELECT_RSP_I__UNUSED | usig::GCTRL_NRM_NONE => (),
+                usig::GCTRL_NRM_IU3_OVERRIDE_O_SELECT_RSP_I__UNUSED
+                | usig::GCTRL_NRM_JCOND_COMPOUND
+                | usig::GCTRL_NRM_NONE => (),
                 usig::GCTRL_NRM_IO_READWRITE => {
                     // The condition `is_gctrl_nrm_io_readwrite()` above actually exactly handles this case.
                 }
@@ -311,14 +313,30 @@ impl<'a> Ctl<'a> {
     }
 
     // RUSTFIX remove this from this file, unify the constants.
-    fn decode_jcond_mask(ui: UInst) -> Word {
-        match (ui & usig::MASK_GCTRL_FTJM) & !usig::GCTRL_JM_INVERTCOND {
-            usig::GCTRL_JCOND_CARRY => (1 << 0),
-            usig::GCTRL_JCOND_N_ZERO => (1 << 1),
-            usig::GCTRL_JCOND_SIGN => (1 << 2),
-            usig::GCTRL_JCOND_N_OVFLW => (1 << 3),
-            _ => panic!("unknown JCOND!"),
-        }
+    fn decode_jcond(ui: UInst, fg: Word) -> bool {
+        let carry = fg & (1 << 0) != 0;
+        let n_zero = fg & (1 << 1) != 0;
+        let sign = fg & (1 << 2) != 0;
+        let n_ovflw = fg & (1 << 3) != 0;
+
+        let jcond = (ui & usig::MASK_GCTRL_FTJM) & !usig::GCTRL_JM_INVERTCOND;
+        let cond = if usig::is_gctrl_nrm_jcond_compound(ui) {
+            match jcond | usig::GCTRL_NRM_JCOND_COMPOUND {
+                usig::GCTRL_JCOND_ABOVE => carry && n_zero,
+                usig::GCTRL_JCOND_N_LESS => sign != n_ovflw,
+                usig::GCTRL_JCOND_GREATER => n_zero && sign != n_ovflw,
+                _ => panic!("unknown compound JCOND!"),
+            }
+        } else {
+            match jcond {
+                usig::GCTRL_JCOND_CARRY => carry,
+                usig::GCTRL_JCOND_N_ZERO => n_zero,
+                usig::GCTRL_JCOND_SIGN => sign,
+                usig::GCTRL_JCOND_N_OVFLW => n_ovflw,
+                _ => panic!("unknown JCOND!"),
+            }
+        };
+        cond != (ui & usig::GCTRL_JM_INVERTCOND != 0)
     }
 
     pub fn clock_inputs(&mut self, ui: UInst, s: &BusState, pic: &dyn interface::Pic) {
@@ -370,7 +388,9 @@ impl<'a> Ctl<'a> {
             }
         } else {
             match ui & usig::MASK_GCTRL_MODE {
-                usig::GCTRL_NRM_IU3_OVERRIDE_O_SELECT_RSP_I__UNUSED | usig::GCTRL_NRM_NONE => (),
+                usig::GCTRL_NRM_IU3_OVERRIDE_O_SELECT_RSP_I__UNUSED
+                | usig::GCTRL_NRM_JCOND_COMPOUND
+                | usig::GCTRL_NRM_NONE => (),
                 usig::GCTRL_NRM_IO_READWRITE => {
                     // The condition `is_gctrl_nrm_io_readwrite()` above actually exactly handles this case.
                 }
@@ -407,11 +427,11 @@ impl<'a> Ctl<'a> {
                 self.cbits[CBit::Aborted] = true;
             }
             _ => {
-                // It was one of the 8 JCOND codes
-                if ((self.reg_fg() /* only care about the low "ALU" bits of FG */ & Ctl::decode_jcond_mask(ui))
-                    != 0)
-                    != (ui & usig::GCTRL_JM_INVERTCOND != 0)
-                {
+                // It was one of the 8 JCOND codes (or one of the compound JCOND codes)
+                if Ctl::decode_jcond(
+                    ui,
+                    self.reg_fg(), /* only care about the low "ALU" bits of FG */
+                ) {
                     self.regs[SReg::IP] = s.read(Bus::B);
                 }
                 self.set_instmask_enabled(ui, true, pint, nmi);
@@ -502,4 +522,8 @@ impl<'a> interface::Ctl for Ctl<'a> {
             self.regs[SReg::IR]
         }
     }
+
+    fn is_carry_set(&self) -> bool {
+        self.reg_fg() & (1 << 0) != 0
+    }
 }
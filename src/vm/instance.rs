@@ -127,6 +127,20 @@ impl<'a> Instance<'a> {
         debug::ExecPhase::TrueInst(uc)
     }
 
+    pub fn ip(&self) -> Word {
+        self.ctl.regs[SReg::IP]
+    }
+
+    /// The bank from which instructions are currently fetched.
+    pub fn code_bank(&self) -> mem::BankType {
+        self.mem.code_bank()
+    }
+
+    /// The part of the bank `typ` which was loaded from a binary when the instance was created.
+    pub fn loaded(&self, typ: mem::BankType) -> &[Word] {
+        self.mem.loaded(typ)
+    }
+
     pub fn iter_at_ip(&self) -> impl Iterator<Item = Word> + '_ {
         self.mem.iter_at(false, self.ctl.regs[SReg::IP])
     }
@@ -170,7 +184,7 @@ impl<'a> Instance<'a> {
             self.ioc.clock_inputs(ui, &state, &self.ctl);
             self.mem.clock_inputs(ui, &state);
             self.reg.clock_inputs(ui, &state, &self.ctl);
-            self.alu.clock_inputs(ui, &state);
+            self.alu.clock_inputs(ui, &state, &self.ctl);
             self.ctl.clock_inputs(ui, &state, self.ioc.pic());
 
             if !self.ctl.cbits[CBit::Halted] {
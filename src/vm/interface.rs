@@ -35,6 +35,11 @@ pub trait Ctl {
 
     // RUSTFIX Implement IU decoding (move this out of Reg), so that we can remove `inst()`,
     fn inst(&self) -> Word;
+
+    /*
+        The CARRY bit of FG, which is the carry in of the ALU.
+    */
+    fn is_carry_set(&self) -> bool;
 }
 
 pub trait Ioc {
@@ -34,6 +34,8 @@ impl BankType {
 pub struct Bank {
     typ: BankType,
     data: Vec<Word>,
+    /// The number of words loaded into the bank when it was created.
+    loaded: usize,
 }
 
 impl Bank {
@@ -45,7 +47,11 @@ impl Bank {
         let mut data = vec![0; typ.size()];
         hw::bytes_to_words_into_buff(&mut data, src).expect("parity error");
 
-        Self { typ, data }
+        Self {
+            typ,
+            data,
+            loaded: src.len() / 2,
+        }
     }
 
     fn load(&self, addr: Word) -> Word {
@@ -69,6 +75,10 @@ impl Bank {
         self.data[(addr >> 1) as usize] = val;
     }
 
+    fn loaded(&self) -> &[Word] {
+        &self.data[..self.loaded]
+    }
+
     fn iter_at(&self, mut addr: Word) -> impl Iterator<Item = Word> + '_ {
         std::iter::from_fn(move || {
             let cur = self.load(addr);
@@ -293,6 +303,16 @@ impl<'a> Mem<'a> {
         }
     }
 
+    /// The bank from which instructions are fetched.
+    pub fn code_bank(&self) -> BankType {
+        self.selected_bank_type(false)
+    }
+
+    /// The part of the bank `typ` which was loaded from a binary (as it is now).
+    pub fn loaded(&self, typ: BankType) -> &[Word] {
+        self.banks[typ].as_ref().unwrap().loaded()
+    }
+
     pub fn iter_at(&'a self, far: bool, addr: Word) -> impl Iterator<Item = Word> + 'a {
         self.selected_bank(far).iter_at(addr)
     }
@@ -10,6 +10,7 @@ mod reg;
 
 pub use instance::{Instance, State};
 pub use interface::{VIDEO_HEIGHT, VIDEO_WIDTH};
+pub use mem::BankType;
 pub use types::LogLevel;
 
 pub mod debug {
@@ -1,11 +1,20 @@
 use kcpu::assembler::{
-    self, model,
+    self,
+    analysis::Analysis,
+    debuginfo::DebugInfo,
+    diagnostic, format, lang,
+    listing::Listing,
+    model,
+    object::{self, Object},
     phases::{
-        generate,
+        expand, generate, include, resolve, scope,
         types::{Loc, Located},
     },
-    Error,
+    source::VirtualFs,
+    Define, Error, Options,
 };
+use kcpu::assets;
+use std::path::Path;
 
 #[test]
 fn at_most_one_const_in_inst() {
@@ -29,3 +38,913 @@ fn more_than_one_const_in_alias() {
     // RUSTFIX IMPLEMENT
     // todo!();
 }
+
+#[test]
+fn macro_arg_count_mismatch() {
+    assert_eq!(
+        assembler::assemble("!macro TWICE reg\nADD reg reg\n!endm\nTWICE %ra %rb"),
+        Err::<Vec<u16>, _>(Error::Expand(Located::with_loc(
+            Loc::new(4, 1),
+            expand::Error::MacroArgCount(
+                String::from("TWICE"),
+                1,
+                2,
+                Some(Box::new(Loc::new(1, 1)))
+            )
+        )))
+    );
+}
+
+#[test]
+fn macro_body_error_reports_call_site() {
+    let err = assembler::assemble("!macro BAD\nST $1 $2\n!endm\n\nBAD").unwrap_err();
+    match err {
+        Error::Generate(err) => assert_eq!(
+            err.loc(),
+            Some(&Loc::new(2, 1).expanded_from(Loc::new(5, 1)))
+        ),
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn barray_parity_and_range() {
+    assert_eq!(
+        assembler::assemble("!barray $1 $2 $3"),
+        Err::<Vec<u16>, _>(Error::Generate(Located::with_loc(
+            Loc::new(1, 1),
+            generate::Error::BadDataParity
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("!barray $0x100 $0"),
+        Err::<Vec<u16>, _>(Error::Generate(Located::with_loc(
+            Loc::new(1, 1),
+            generate::Error::DataByteOutOfRange(0x100)
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("!barray $0 far\n!rept $0x100\nNOP\n!endr\nfar:"),
+        Err::<Vec<u16>, _>(Error::Resolve(Located::with_loc(
+            Loc::new(1, 1),
+            resolve::Error::LabelNotByteAddressable(String::from("far"), 0x202)
+        )))
+    );
+}
+
+#[test]
+fn string_escapes_and_char_literals() {
+    assert_eq!(
+        assembler::assemble("!string \"a\\\"\\x80\\n\""),
+        assembler::assemble("!barray $0x61 $0x22 $0x80 $0x0A $0 $0")
+    );
+    assert_eq!(
+        assembler::assemble("!bstring \"a\\tb\\\\\""),
+        assembler::assemble("!barray $0x61 $0x09 $0x62 $0x5C")
+    );
+    assert_eq!(
+        assembler::assemble("MOV 'A' %ra\n!warray '\\0' $'a'+1 $('z'-'a')"),
+        assembler::assemble("MOV $0x41 %ra\n!warray $0 $0x62 $25")
+    );
+    assert_eq!(
+        assembler::assemble("!bstring \"abc\""),
+        Err::<Vec<u16>, _>(Error::Generate(Located::with_loc(
+            Loc::new(1, 1),
+            generate::Error::BadDataParity
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("!string \"\u{100}\""),
+        Err::<Vec<u16>, _>(Error::Generate(Located::with_loc(
+            Loc::new(1, 1),
+            generate::Error::StringCharOutOfRange('\u{100}')
+        )))
+    );
+    assert!(matches!(
+        assembler::assemble("MOV 'ab' %ra"),
+        Err(Error::Tokenize(_))
+    ));
+}
+
+#[test]
+fn word_consts_coerced_to_bytes() {
+    assert_eq!(
+        assembler::assemble("STBL %ra $5\nSTBH %ra $0x7F\nSTBL %ra 'A'\nSTBL %ra $200"),
+        assembler::assemble("STBL %ra l$5\nSTBH %ra h$0x7F\nSTBL %ra l$0x41\nSTBL %ra l$200")
+    );
+    // An exact match is always preferred.
+    assert_eq!(
+        assembler::assemble("ST %ra $5"),
+        assembler::assemble("STW %ra $5")
+    );
+
+    let rejected = |src: &str| match assembler::assemble(src) {
+        Err(Error::Generate(err)) => match err.value() {
+            generate::Error::InstConstNotByte(_, _, err) => err,
+            err => panic!("unexpected error: {}", err),
+        },
+        res => panic!("unexpected result: {:?}", res),
+    };
+    assert_eq!(
+        rejected("STBL %ra $0x100"),
+        model::ByteCoercionError::DoesNotFit(0x100)
+    );
+    assert_eq!(
+        rejected("STBL %ra $0x005"),
+        model::ByteCoercionError::WrittenAsWord(5)
+    );
+    assert_eq!(
+        rejected("STBL %ra $010"),
+        model::ByteCoercionError::WrittenAsWord(10)
+    );
+    assert_eq!(
+        rejected("STBL %ra lbl\nlbl:"),
+        model::ByteCoercionError::Unresolved
+    );
+}
+
+#[test]
+fn equ_circular_definition() {
+    assert_eq!(
+        assembler::assemble("!equ A $B+1\n!equ B $A-1\nMOV A %ra"),
+        Err::<Vec<u16>, _>(Error::Resolve(Located::with_loc(
+            Loc::new(3, 1),
+            resolve::Error::CircularDefinition(String::from("A"))
+        )))
+    );
+}
+
+#[test]
+fn include_relative_to_including_file() {
+    let fs = VirtualFs::new()
+        .with("src/main.ks", "!include \"lib/a.ks\"\nJMP a")
+        .with("src/lib/a.ks", "!include \"../b.ks\"\na:\nJMP b")
+        .with("src/b.ks", "b:\nHLT");
+    assert_eq!(
+        assembler::assemble_path(Path::new("src/main.ks"), &fs, &Options::default()),
+        assembler::assemble("b:\nHLT\na:\nJMP b\nJMP a")
+    );
+}
+
+#[test]
+fn include_errors_name_file() {
+    let fs = VirtualFs::new()
+        .with("main.ks", "NOP\n!include \"bad.ks\"")
+        .with("bad.ks", "\nST $1 $2");
+    match assembler::assemble_path(Path::new("main.ks"), &fs, &Options::default()).unwrap_err() {
+        Error::Generate(err) => {
+            let loc = err.loc().unwrap();
+            assert_eq!(loc.file().map(|f| &**f), Some("bad.ks"));
+            assert_eq!((loc.line(), loc.col()), (2, 1));
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn include_cycle() {
+    let fs = VirtualFs::new()
+        .with("a.ks", "!include \"b.ks\"")
+        .with("b.ks", "!include \"a.ks\"");
+    match assembler::assemble_path(Path::new("a.ks"), &fs, &Options::default()).unwrap_err() {
+        Error::Include(err) => {
+            assert_eq!(
+                err.value(),
+                include::Error::IncludeCycle(String::from("a.ks"))
+            )
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+
+    let fs = VirtualFs::new()
+        .with("src/a.ks", "!include \"./lib/b.ks\"")
+        .with("src/lib/b.ks", "!include \"../a.ks\"");
+    match assembler::assemble_path(Path::new("./src/a.ks"), &fs, &Options::default()).unwrap_err() {
+        Error::Include(err) => {
+            assert_eq!(
+                err.value(),
+                include::Error::IncludeCycle(String::from("src/a.ks"))
+            )
+        }
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+#[test]
+fn include_std_module() {
+    let fs = VirtualFs::new().with(
+        "src/main.ks",
+        "HLT\n!include \"std:math\"\n!include \"std:io\"",
+    );
+    let prog = assembler::assemble_program_path(Path::new("src/main.ks"), &fs, &Options::default())
+        .unwrap();
+    assert_eq!(prog.symbol("STD_VERSION"), Some(assets::STD_VERSION));
+    assert!(prog.symbol("std_mul").is_some());
+    assert!(prog.symbol("std_puthex").is_some());
+}
+
+#[test]
+fn include_std_unknown_module() {
+    match assembler::assemble("!include \"std:nope\"").unwrap_err() {
+        Error::Include(err) => assert_eq!(
+            err.value(),
+            include::Error::LoadFailed(
+                String::from("std:nope"),
+                String::from("no such module in the standard library: nope")
+            )
+        ),
+        err => panic!("unexpected error: {}", err),
+    }
+}
+
+const LINK_MAIN: &str = "!import double TABLE_LEN table\nMOV $TABLE_LEN %ra\nCALL double\nCMP $TABLE_LEN*2 %ra\nJNE fail\nMOV $table+2 %rb\nHLT\nfail:\nABRT";
+const LINK_LIB: &str = "!export double TABLE_LEN table\n!equ TABLE_LEN $(table_end-table)/2\ndouble:\nADD %ra %ra\nRET\nfail:\nABRT\ntable:\n!barray lo(table) hi(table) $3 $4\ntable_end:";
+
+fn assemble_object(src: &str) -> Object {
+    let (obj, _) = assembler::assemble_object_with(src, &VirtualFs::new()).unwrap();
+    Object::read(&obj.write()).unwrap()
+}
+
+#[test]
+fn link_matches_flat_assembly() {
+    let objs = [assemble_object(LINK_MAIN), assemble_object(LINK_LIB)];
+    assert_eq!(
+        object::link(&objs).map_err(|err| err.to_string()),
+        assembler::assemble(&format!(
+            "{}\n{}",
+            LINK_MAIN.replace("fail", "fail0"),
+            LINK_LIB
+        ))
+        .map_err(|err| err.to_string())
+    );
+}
+
+#[test]
+fn link_symbol_errors() {
+    let main = assemble_object(LINK_MAIN);
+    let lib = assemble_object(LINK_LIB);
+    assert_eq!(
+        object::link(std::slice::from_ref(&main)),
+        Err(object::Error::UnresolvedImport(String::from("double")))
+    );
+    assert_eq!(
+        object::link(&[main, lib.clone(), lib]),
+        Err(object::Error::DuplicateExport(String::from("double")))
+    );
+
+    assert_eq!(
+        assembler::assemble_object_with("!import x\nx:\nNOP", &VirtualFs::new()),
+        Err(Error::Resolve(Located::with_loc(
+            Loc::new(1, 1),
+            resolve::Error::DuplicateLabel(String::from("x"))
+        )))
+    );
+    assert_eq!(
+        assembler::assemble_object_with("MOV $y+1 %ra", &VirtualFs::new()),
+        Err(Error::Resolve(Located::with_loc(
+            Loc::new(1, 1),
+            resolve::Error::UnknownLabel(String::from("y"))
+        )))
+    );
+}
+
+#[test]
+fn org_align_fill_placement() {
+    let nop = assembler::assemble("NOP").unwrap();
+    let hlt = assembler::assemble("HLT").unwrap();
+
+    let mut expected = nop.clone();
+    expected.resize(4, 0);
+    expected.extend(hlt.iter());
+    expected.resize(8, 0);
+    expected.extend([0xAB, 0xAB, 0x14].iter());
+
+    assert_eq!(
+        assembler::assemble("NOP\n!org $0x8\nHLT\n!align $16\n!fill $2 $0xAB\nend:\n!warray end"),
+        Ok(expected)
+    );
+}
+
+#[test]
+fn org_errors() {
+    assert_eq!(
+        assembler::assemble("!org $0x4\nNOP\n!org $0x2\n!warray $1 $2"),
+        Err(Error::Resolve(Located::with_loc(
+            Loc::new(2, 1),
+            resolve::Error::OverlappingRegions(0x4, 0x2)
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("!org $0x3\nNOP"),
+        Err(Error::Resolve(Located::with_loc(
+            Loc::new(1, 1),
+            resolve::Error::MisalignedOrigin(0x3)
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("!align $6\nNOP"),
+        Err(Error::Resolve(Located::with_loc(
+            Loc::new(1, 1),
+            resolve::Error::BadAlignment(6)
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("!org $0xFFFE\n!warray $1 $2"),
+        Err(Error::Resolve(Located::with_loc(
+            Loc::new(2, 1),
+            resolve::Error::ImageTooLarge(0x10002)
+        )))
+    );
+    assert_eq!(
+        assembler::assemble_object_with("!org $0x10\nNOP", &VirtualFs::new()),
+        Err(Error::Resolve(Located::with_loc(
+            Loc::new(1, 1),
+            resolve::Error::NotRelocatable("org")
+        )))
+    );
+}
+
+#[test]
+fn listing_shows_addresses_and_expansions() {
+    let fs = VirtualFs::new().with("lib.ks", "double:\n    ADD %ra %ra\n    RET");
+    let src = "start: MOV $0x80 %ra\nCALL double\n!include \"lib.ks\"\n!warray $1 $2 $3 $4 $5";
+    let assembly = assembler::assemble_program_with(src, &fs).unwrap();
+    let listing = Listing::new(Some(src), assembly.placements()).render(&fs);
+    let lines = listing.lines().collect::<Vec<_>>();
+
+    assert_eq!(
+        Ok(assembly.into_words()),
+        assembler::assemble_with(src, &fs)
+    );
+    assert!(lines[0].starts_with("0000  ") && lines[0].ends_with("1  start: MOV $0x80 %ra"));
+    assert!(lines.contains(&"0008                           lib.ks:1  double:"));
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with("0008  ") && line.ends_with("lib.ks:2  ADD %ra %ra")));
+    assert!(lines
+        .iter()
+        .any(|line| line.ends_with("; add2: ADD2 %ra %ra")));
+    assert_eq!(lines.last(), Some(&"0014  0005"));
+}
+
+#[test]
+fn debug_info_maps_addresses_to_source() {
+    let fs = VirtualFs::new().with("lib/double.ks", "double:\n    ADD %ra %ra\n    RET");
+    let src = "MOV $0x80 %ra\nCALL double\nHLT\n!include \"lib/double.ks\"";
+    let assembly = assembler::assemble_program_with(src, &fs).unwrap();
+    let info = DebugInfo::new(assembly.placements());
+
+    assert_eq!(DebugInfo::read(&info.write()), Ok(info.clone()));
+
+    assert_eq!(info.labels, vec![(String::from("double"), 0x0A)]);
+    assert_eq!(info.symbolize(0x0C), Some(("double", 2)));
+    assert_eq!(info.symbolize(0x04), None);
+
+    let pos = info.source_at(0x04).unwrap();
+    assert_eq!((pos.file.as_deref(), pos.line, pos.col), (None, 2, 1));
+    assert_eq!(
+        info.source_at(0x0C).map(ToString::to_string),
+        Some(String::from("lib/double.ks:3:5"))
+    );
+    assert_eq!(info.source_at(0x02), None);
+}
+
+#[test]
+fn assembly_records_symbols_and_aliases() {
+    let src = "!equ LEN $(end-table)/2\nmain:\n.loop: ADD %ra %ra\nJMP .loop\ntable:\n!warray $1 $2 $3\nend:";
+    let assembly = assembler::assemble_program_with(src, &VirtualFs::new()).unwrap();
+
+    assert_eq!(Ok(assembly.words().to_vec()), assembler::assemble(src));
+    assert_eq!(assembly.symbol("main"), Some(0x00));
+    assert_eq!(assembly.symbol("main.loop"), Some(0x00));
+    assert_eq!(assembly.symbol("table"), Some(0x06));
+    assert_eq!(assembly.symbol("LEN"), Some(3));
+    assert_eq!(assembly.symbol(".loop"), None);
+    assert_eq!(
+        assembly.symbols().map(|(name, _)| name).collect::<Vec<_>>(),
+        vec!["LEN", "end", "main", "main.loop", "table"]
+    );
+
+    let aliases = assembly
+        .aliases()
+        .map(|(p, alias)| (p.loc().unwrap().line(), p.value_ref().addr, alias))
+        .collect::<Vec<_>>();
+    assert_eq!(aliases[0], (3, 0x00, "add2"));
+    assert_eq!(aliases[1].0, 4);
+}
+
+#[test]
+fn independent_errors_are_all_reported() {
+    let err = assembler::assemble("NOP\nST $1 $2\n!barray $0x100\nHLT\nST $3 $4").unwrap_err();
+    let locs = err
+        .errors()
+        .iter()
+        .map(|err| (err.phase(), err.loc().map(|loc| loc.line())))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        locs,
+        vec![
+            (Some("Generator"), Some(2)),
+            (Some("Generator"), Some(3)),
+            (Some("Generator"), Some(5)),
+        ]
+    );
+
+    let err = assembler::assemble("JMP nowhere\nJMP elsewhere\nHLT").unwrap_err();
+    assert_eq!(
+        err,
+        Error::Multiple(vec![
+            Error::Resolve(Located::with_loc(
+                Loc::new(1, 1),
+                resolve::Error::UnknownLabel(String::from("nowhere"))
+            )),
+            Error::Resolve(Located::with_loc(
+                Loc::new(2, 1),
+                resolve::Error::UnknownLabel(String::from("elsewhere"))
+            )),
+        ])
+    );
+}
+
+#[test]
+fn diagnostics_show_source_line_and_caret() {
+    let fs = VirtualFs::new().with("lib.ks", "!macro jump target\n\tJMP target\n!endm");
+    let src = "!include \"lib.ks\"\nloop:\n    jump lopo\n    ST $1 $2";
+    let err = assembler::assemble_with(src, &fs).unwrap_err();
+
+    assert_eq!(
+        diagnostic::render(&err, Some(src), &fs),
+        format!("error[Generator]: {}", err.message())
+            + "\n --> <source>:4:5\n  |\n4 |     ST $1 $2\n  |     ^\n\n"
+    );
+
+    let err = assembler::assemble_with("!include \"lib.ks\"\njump lopo", &fs).unwrap_err();
+    assert_eq!(
+        diagnostic::render(&err, None, &fs),
+        "error[Resolver]: Use of undefined label: 'lopo'\n --> lib.ks:2:2\n  |\n2 | \tJMP target\n  | \t^\n  = note: in expansion of macro called at <source>:2:1\n\n"
+    );
+}
+
+#[test]
+fn local_and_anonymous_labels() {
+    assert_eq!(
+        assembler::assemble("a:\n.x: JMP .x\nb:\n.x: JMP .x\nJMP a.x\n1: JMP 1f\n1: JMP 1b"),
+        assembler::assemble("a:\nax: JMP ax\nb:\nbx: JMP bx\nJMP ax\nc: JMP d\nd: JMP d")
+    );
+
+    assert_eq!(
+        assembler::assemble(".x: NOP"),
+        Err(Error::Scope(Located::with_loc(
+            Loc::new(1, 1),
+            scope::Error::LocalLabelOutsideScope(String::from(".x"))
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("1: JMP 1f"),
+        Err(Error::Scope(Located::with_loc(
+            Loc::new(1, 4),
+            scope::Error::NoSuchAnonymousLabel(String::from("1f"))
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("main:\n.add: NOP\nadd: NOP"),
+        Err(Error::Generate(Located::with_loc(
+            Loc::new(3, 1),
+            generate::Error::LabelNameCollidesWithInst(String::from("add"))
+        )))
+    );
+}
+
+#[test]
+fn conditional_assembly() {
+    let src = "!equ DEBUG $0\n!if DEBUG\nNOP\n!else\n!ifdef LEVEL\n!if $LEVEL>=2\nHLT\n!endif\n!else\nABRT\n!endif\n!endif";
+    let fs = VirtualFs::new().with("bios.ks", src);
+    let assemble = |defines: &[&str]| {
+        let opts = Options {
+            defines: defines
+                .iter()
+                .map(|def| def.parse::<Define>().unwrap())
+                .collect(),
+            ..Options::default()
+        };
+        assembler::assemble_path(Path::new("bios.ks"), &fs, &opts)
+    };
+
+    assert_eq!(assemble(&[]), assembler::assemble("ABRT"));
+    assert_eq!(assemble(&["LEVEL=$2"]), assembler::assemble("HLT"));
+    assert_eq!(assemble(&["LEVEL=1"]), assembler::assemble(""));
+
+    // Conditions inside a macro body are evaluated at each expansion.
+    assert_eq!(
+        assembler::assemble(
+            "!macro CHK n\n!if n==1\nNOP\n!else\nHLT\n!endif\n!endm\nCHK $1\nCHK $2"
+        ),
+        assembler::assemble("NOP\nHLT")
+    );
+}
+
+#[test]
+fn conditional_assembly_errors() {
+    assert_eq!(
+        assembler::assemble("!equ SIZE $0x20\n!assert $SIZE<=0x10"),
+        Err(Error::Expand(Located::with_loc(
+            Loc::new(2, 1),
+            expand::Error::AssertionFailed(String::from("(SIZE<=0x10)"))
+        )))
+    );
+    assert_eq!(
+        assembler::assemble(
+            "!ifdef RELEASE\nNOP\n!else\n!error \"RELEASE must be defined\"\n!endif"
+        ),
+        Err(Error::Expand(Located::with_loc(
+            Loc::new(4, 1),
+            expand::Error::UserError(String::from("RELEASE must be defined"))
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("!if end\nNOP\n!endif\nend:"),
+        Err(Error::Expand(Located::with_loc(
+            Loc::new(1, 1),
+            expand::Error::BadCondition(
+                String::from("end"),
+                resolve::Error::UnknownLabel(String::from("end"))
+            )
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("!if $1\nNOP"),
+        Err(Error::Expand(Located::with_loc(
+            Loc::new(1, 1),
+            expand::Error::UnterminatedBlock("if")
+        )))
+    );
+    assert_eq!(
+        assembler::assemble("NOP\n!else"),
+        Err(Error::Expand(Located::with_loc(
+            Loc::new(2, 1),
+            expand::Error::UnmatchedBlockEnd("else")
+        )))
+    );
+    assert!("=1".parse::<Define>().is_err());
+}
+
+const NODE: &str = "!struct NODE\n!byte tag\n!word next\n!byte flags $3\n!word val\n!ends\n";
+
+#[test]
+fn struct_layout() {
+    assert_eq!(
+        assembler::assemble(&format!(
+            "{}!warray $NODE.tag $NODE.next $NODE.flags $NODE.val $NODE.size",
+            NODE
+        )),
+        Ok(vec![0, 2, 4, 8, 10])
+    );
+
+    // The offsets are known to conditions, and can be used anywhere a constant can.
+    assert_eq!(
+        assembler::assemble(&format!(
+            "{}!if $NODE.size==10\nLDWO %rbp $NODE.val %ra\n!endif\nnodes:\n!fill $NODE.size/2 $0\nSTBL $nodes+NODE.flags+2 %la",
+            NODE
+        )),
+        assembler::assemble("LDWO %rbp $8 %ra\nnodes:\n!fill $5 $0\nSTBL $nodes+6 %la")
+    );
+}
+
+#[test]
+fn struct_errors() {
+    let generate_err = |src: &str, alias: &str| {
+        assert_eq!(
+            assembler::assemble(&format!("{}{}", NODE, src)),
+            Err(Error::Generate(Located::with_loc(
+                Loc::new(7, 1),
+                generate::Error::InstByteFieldNotByteAccess(
+                    String::from(alias),
+                    String::from("NODE.flags")
+                )
+            )))
+        );
+    };
+    generate_err("LDWO %rbp $NODE.flags %ra", "LDWO");
+    generate_err("LD $0x100+NODE.flags %ra", "LDW");
+    generate_err("ST $NODE.flags %ra", "STW");
+    assert!(assembler::assemble(&format!("{}LDBLZ $0x100+NODE.flags %la", NODE)).is_ok());
+    assert!(assembler::assemble(&format!("{}LDWO %rbp $NODE.next %ra", NODE)).is_ok());
+
+    let expand_err = |src: &str, line: usize, err: expand::Error| {
+        assert_eq!(
+            assembler::assemble(src),
+            Err(Error::Expand(Located::with_loc(Loc::new(line, 1), err)))
+        );
+    };
+    expand_err(
+        "!word x",
+        1,
+        expand::Error::FieldOutsideStruct(String::from("x")),
+    );
+    expand_err(
+        "!struct S\n!word x\nNOP\n!ends",
+        3,
+        expand::Error::StructBodyNotField(String::from("S")),
+    );
+    expand_err(
+        "!struct S\n!word x\n!byte x\n!ends",
+        3,
+        expand::Error::StructDuplicateField(String::from("S"), String::from("x")),
+    );
+    expand_err(
+        "!struct S\n!byte x $0xFFFF\n!word y\n!ends",
+        3,
+        expand::Error::StructTooLarge(String::from("S")),
+    );
+    expand_err(
+        "!struct S\n!word x",
+        1,
+        expand::Error::UnterminatedBlock("struct"),
+    );
+    expand_err("!ends", 1, expand::Error::UnmatchedBlockEnd("ends"));
+}
+
+#[test]
+fn proc_frame() {
+    // Arguments lie above the saved `%rbp` and return address, the last pushed lowest, and
+    // locals lie below `%rbp`.
+    assert_eq!(
+        assembler::assemble(
+            "!proc f a b locals x y\nLDWO %rbp $a %ra\nLDWO %rbp $b %rb\n\
+             STWO %rbp $x %ra\nSTWO %rbp $y+0 %rb\n!endp"
+        ),
+        assembler::assemble(
+            "f:\nENTERFR $4\nLDWO %rbp $6 %ra\nLDWO %rbp $4 %rb\n\
+             STWO %rbp $-2 %ra\nSTWO %rbp $-4 %rb\nLEAVE\nRET"
+        )
+    );
+    assert_eq!(
+        assembler::assemble("!proc f\nNOP\n!endp"),
+        assembler::assemble("f:\nENTER\nNOP\nLEAVE\nRET")
+    );
+
+    // Names shadow symbols only inside the body, and local labels belong to the procedure.
+    assert_eq!(
+        assembler::assemble(
+            "!equ n $100\n!proc f n\nLDWO %rbp $n %ra\nJMP .end\n.end:\n!endp\nMOV $n %ra"
+        ),
+        assembler::assemble(
+            "f:\nENTER\nLDWO %rbp $4 %ra\nJMP f.end\nf.end:\nLEAVE\nRET\nMOV $100 %ra"
+        )
+    );
+}
+
+#[test]
+fn proc_warnings() {
+    let warnings = |src: &str| {
+        let assembly = assembler::assemble_program_with(src, &VirtualFs::new()).unwrap();
+        assembly.warnings().to_vec()
+    };
+    let skips = |line: usize| {
+        Located::with_loc(
+            Loc::new(line, 1),
+            expand::Warning::RetSkipsLeave(String::from("f")),
+        )
+    };
+
+    assert_eq!(warnings("!proc f\nRET\n!endp"), vec![skips(2)]);
+    assert_eq!(
+        warnings("!proc f\nLEAVE\n.out:\nRET\n!endp"),
+        vec![skips(4)]
+    );
+    assert_eq!(warnings("!proc f\nLEAVE\n!equ X $1\nRET\n!endp"), vec![]);
+    assert_eq!(warnings("f:\nRET"), vec![]);
+
+    // An object keeps no warnings, so they are returned alongside it.
+    let (_, warnings) =
+        assembler::assemble_object_with("!proc f\nRET\n!endp", &VirtualFs::new()).unwrap();
+    assert_eq!(warnings, vec![skips(2)]);
+}
+
+#[test]
+fn proc_errors() {
+    let expand_err = |src: &str, line: usize, err: expand::Error| {
+        assert_eq!(
+            assembler::assemble(src),
+            Err(Error::Expand(Located::with_loc(Loc::new(line, 1), err)))
+        );
+    };
+    expand_err(
+        "!proc f a locals a\n!endp",
+        1,
+        expand::Error::ProcDuplicateName(String::from("f"), String::from("a")),
+    );
+    expand_err(
+        "!proc f\n!proc g\n!endp\n!endp",
+        2,
+        expand::Error::NestedProcDef(String::from("g")),
+    );
+    expand_err("!proc f\nNOP", 1, expand::Error::UnterminatedBlock("proc"));
+    expand_err("!endp", 1, expand::Error::UnmatchedBlockEnd("endp"));
+}
+
+#[test]
+fn optimizer_selects_no_flags_variants_and_rewrites() {
+    let optimized = |src: &str| {
+        let fs = VirtualFs::new().with("prog.ks", src);
+        let opts = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        assembler::assemble_path(Path::new("prog.ks"), &fs, &opts)
+    };
+
+    // Only the last flag-setting instruction before a flag-reading one keeps its flags.
+    assert_eq!(
+        optimized("ADD %ra %rb\nSUB %rc %rd\nJZ end\nADD %ra %rb\nend:\nHLT"),
+        assembler::assemble("ADDNF %ra %rb\nSUB %rc %rd\nJZ end\nADD %ra %rb\nend:\nHLT")
+    );
+    // The flags are live at a label (it may be jumped to) only if they are read after it.
+    assert_eq!(
+        optimized("loop:\nOR %ra %rb\nloop2:\nXOR %ra %ra\nCMP %rb %rc\nJNZ loop"),
+        assembler::assemble("loop:\nORNF %ra %rb\nloop2:\nXORNF %ra %ra\nCMP %rb %rc\nJNZ loop")
+    );
+    // An instruction which takes the carry in reads the flags.
+    assert_eq!(
+        optimized("ADD %ra %rb\nADC $0 %rc\nADD %ra %rb\nRCL %rd\nHLT"),
+        assembler::assemble("ADD %ra %rb\nADC $0 %rc\nADD %ra %rb\nRCL %rd\nHLT")
+    );
+
+    assert_eq!(
+        optimized("MOV $0 %ra\nMOV %rb %rb\nMOV %rc %rd\nMOV %rd %rc\nHLT"),
+        assembler::assemble("XORNF %ra %ra\nMOV %rc %rd\nHLT")
+    );
+    assert_eq!(
+        optimized("ADD $0 %ra\nADD3 $0 %rb %rc\nAND $0xFFFF %rd\nCMP %ra %rb\nHLT"),
+        assembler::assemble("MOV %rb %rc\nCMP %ra %rb\nHLT")
+    );
+    assert_eq!(
+        optimized("PUSH %ra\nPUSH %rb\nPOP %rc\nPOP %rd\nPUSH $1\nPUSH $2\nHLT"),
+        assembler::assemble("PUSHx2 %ra %rb\nPOPx2 %rc %rd\nPUSH $1\nPUSH $2\nHLT")
+    );
+
+    // Labels are placed after the code before them has shrunk.
+    assert_eq!(
+        optimized("MOV $0 %ra\nJMP end\nMOV %rb %rb\nend:\nHLT"),
+        assembler::assemble("XORNF %ra %ra\nJMP end\nend:\nHLT")
+    );
+}
+
+#[test]
+fn alias_and_family_definitions() {
+    // An alias behaves just like the instructions making it up, wherever it is defined.
+    assert_eq!(
+        assembler::assemble("CLR2 %rb\n!alias CLR2 reg\nXOR reg reg\nXOR reg reg\n!enda"),
+        assembler::assemble("XOR %rb %rb\nXOR %rb %rb")
+    );
+
+    let alias_err = |src: &str, line, err| {
+        assert_eq!(
+            assembler::assemble(src),
+            Err(Error::Generate(Located::with_loc(Loc::new(line, 1), err)))
+        );
+    };
+    alias_err(
+        "!alias X\nADD %ra %rb\n!enda",
+        2,
+        generate::Error::AliasBodyUnknownInst(String::from("ADD")),
+    );
+    alias_err(
+        "!alias X a\nADD2 a $1\n!enda",
+        2,
+        generate::Error::AliasBodyBadArgs(
+            String::from("ADD2"),
+            vec![
+                model::Arg::Const(model::ConstBinding::Unresolved(
+                    assembler::phases::expr::Expr::Name(String::from("a")),
+                )),
+                model::Arg::Const(model::ConstBinding::Resolved(model::Const::Word(1))),
+            ],
+        ),
+    );
+    alias_err(
+        "!alias X a b\nMOV a %ra\n!enda",
+        1,
+        generate::Error::AliasParamKind(String::from("X"), String::from("b")),
+    );
+    alias_err(
+        "!alias X a\nMOV a %ra\nSTBL $0 a\n!enda",
+        1,
+        generate::Error::AliasParamKind(String::from("X"), String::from("a")),
+    );
+    alias_err(
+        "!alias MOV\nNOP\n!enda",
+        1,
+        generate::Error::Lang(lang::Error::NameTaken(String::from("mov"))),
+    );
+    alias_err(
+        "!family F MOV ADD2",
+        1,
+        generate::Error::Lang(lang::Error::VariantsCollide(
+            String::from("mov"),
+            String::from("add2"),
+        )),
+    );
+    alias_err(
+        "!family F MOV NOPE",
+        1,
+        generate::Error::Lang(lang::Error::UnknownAlias(String::from("nope"))),
+    );
+
+    assert_eq!(
+        assembler::assemble("!alias X\nNOP"),
+        Err(Error::Expand(Located::with_loc(
+            Loc::new(1, 1),
+            expand::Error::UnterminatedBlock("alias")
+        )))
+    );
+}
+
+#[test]
+fn comparison_jumps() {
+    // `JL` and `JGE` are signed comparisons, and the unsigned ones are `JB` and `JAE`. The old
+    // unsigned `JNL` is gone, so that sources which used it fail to assemble.
+    assert_ne!(
+        assembler::assemble("x:\nJL x\nJGE x"),
+        assembler::assemble("x:\nJB x\nJAE x")
+    );
+    assert_eq!(
+        assembler::assemble("x:\nJAE x\nLDJAE x"),
+        assembler::assemble("x:\nJC x\nLDJC x")
+    );
+    assert!(assembler::assemble("x:\nJNL x").is_err());
+    assert!(assembler::assemble("x:\nLDJNL x").is_err());
+}
+
+#[test]
+fn analysis_finds_symbol_definitions_and_references() {
+    let fs = VirtualFs::new()
+        .with(
+            "main.ks",
+            "!include \"lib.ks\"\nmain:\n.loop:\nCALL putc\nJMP .loop\nCALL bad\n!macro WAIT n\nMOV $n %ra\n1:\nSUB $1 %ra\nJNZ 1b\n!endm\nWAIT COUNT",
+        )
+        .with("lib.ks", "!equ COUNT $3\nputc:\nRET");
+    let analysis = Analysis::of_path(Path::new("main.ks"), &fs, &Options::default());
+
+    let errs = analysis.errors();
+    assert_eq!(errs.len(), 1);
+    assert_eq!(errs[0].loc().map(|loc| loc.line()), Some(6));
+
+    let at = |occs: Vec<&Located<scope::Occurrence>>| {
+        occs.into_iter()
+            .map(|occ| {
+                let loc = occ.loc().unwrap();
+                (loc.file().unwrap().to_string(), loc.line(), loc.col())
+            })
+            .collect::<Vec<_>>()
+    };
+    let main = Some("main.ks");
+
+    // A label defined in another file, from anywhere in the name of a reference to it.
+    assert_eq!(
+        at(analysis.definitions_at(main, 4, 9)),
+        vec![(String::from("lib.ks"), 2, 1)]
+    );
+    assert_eq!(
+        at(analysis.definitions_at(main, 4, 6)),
+        at(analysis.definitions_at(main, 4, 10))
+    );
+    assert!(analysis.definitions_at(main, 4, 1).is_empty());
+
+    // A local label.
+    assert_eq!(
+        at(analysis.references_at(main, 3, 2, true)),
+        vec![
+            (String::from("main.ks"), 3, 1),
+            (String::from("main.ks"), 5, 5)
+        ]
+    );
+    assert_eq!(
+        at(analysis.references_at(main, 3, 2, false)),
+        vec![(String::from("main.ks"), 5, 5)]
+    );
+
+    // An anonymous label in a macro body, and a constant passed to the macro.
+    assert_eq!(
+        at(analysis.definitions_at(main, 11, 5)),
+        vec![(String::from("main.ks"), 9, 1)]
+    );
+    assert_eq!(
+        at(analysis.definitions_at(main, 13, 6)),
+        vec![(String::from("lib.ks"), 1, 6)]
+    );
+}
+
+#[test]
+fn format_source() {
+    let src = "# Header.\n!equ  N   $3\nstart:   MOV $N %ra\n  loop:\n SUB $1 %ra   # Count down.\nJNZ loop # Again.\n\n\n   # Indented.\nlongerlabel: HLT\n!string   \"a  b\" \n\n";
+    let formatted = "# Header.\n!equ N $3\nstart: mov $N %ra\nloop:\n    sub $1 %ra # Count down.\n    jnz loop   # Again.\n\n\n    # Indented.\nlongerlabel: hlt\n!string \"a  b\"\n";
+    assert_eq!(format::format(src).unwrap(), formatted);
+    assert_eq!(format::format(formatted).unwrap(), formatted);
+    assert_eq!(assembler::assemble(src), assembler::assemble(formatted));
+
+    assert!(matches!(
+        format::format("MOV \"oops"),
+        Err(Error::Tokenize(_))
+    ));
+}
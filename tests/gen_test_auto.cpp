@@ -49,10 +49,12 @@ int main() {
         "CALL", "RET", "IRET",
         "JMP", "LJMP", "JMP+DI", "JMP+EI",
         "LDJMP", "LDLJMP", "LDJMP+DI", "LDJMP+EI",
-        "JC", "JO", "JS", "JZ", "JE", "JL", "JGE",
-        "JNC", "JNO", "JNS", "JNZ", "JNE", "JNL",
-        "LDJC", "LDJO", "LDJS", "LDJZ", "LDJE", "LDJL", "LDJGE",
-        "LDJNC", "LDJNO", "LDJNS", "LDJNZ", "LDJNE", "LDJNL",
+        "JC", "JO", "JS", "JZ", "JE", "JAE",
+        "JNC", "JNO", "JNS", "JNZ", "JNE", "JB",
+        "JA", "JBE", "JG", "JLE", "JGE", "JL",
+        "LDJC", "LDJO", "LDJS", "LDJZ", "LDJE", "LDJAE",
+        "LDJNC", "LDJNO", "LDJNS", "LDJNZ", "LDJNE", "LDJB",
+        "LDJA", "LDJBE", "LDJG", "LDJLE", "LDJGE", "LDJL",
     };
 
     std::set<std::string> blacklist_found;
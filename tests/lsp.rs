@@ -0,0 +1,123 @@
+use kcpu::cli::lsp;
+use lsp_server::{Connection, Message, Notification, Request, RequestId};
+use lsp_types::{
+    notification::{DidOpenTextDocument, Exit, Initialized, Notification as _, PublishDiagnostics},
+    request::{Completion, GotoDefinition, HoverRequest, Initialize, Request as _, Shutdown},
+    CompletionResponse, GotoDefinitionResponse, Hover, HoverContents, Location, Position,
+    PublishDiagnosticsParams, Range, TextDocumentItem, Url,
+};
+use serde_json::json;
+use std::thread;
+
+struct Client {
+    conn: Connection,
+    next_id: i32,
+}
+
+impl Client {
+    fn request<T: serde::de::DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> T {
+        self.next_id += 1;
+        let id = RequestId::from(self.next_id);
+        self.conn
+            .sender
+            .send(Message::Request(Request::new(
+                id.clone(),
+                method.to_owned(),
+                params,
+            )))
+            .unwrap();
+
+        loop {
+            match self.conn.receiver.recv().unwrap() {
+                Message::Response(resp) if resp.id == id => {
+                    return serde_json::from_value(resp.result.unwrap()).unwrap()
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn notify(&self, method: &str, params: serde_json::Value) {
+        self.conn
+            .sender
+            .send(Message::Notification(Notification::new(
+                method.to_owned(),
+                params,
+            )))
+            .unwrap();
+    }
+
+    fn diagnostics(&self) -> PublishDiagnosticsParams {
+        loop {
+            if let Message::Notification(not) = self.conn.receiver.recv().unwrap() {
+                if not.method == PublishDiagnostics::METHOD {
+                    return serde_json::from_value(not.params).unwrap();
+                }
+            }
+        }
+    }
+}
+
+fn position(uri: &Url, line: u32, character: u32) -> serde_json::Value {
+    json!({ "textDocument": { "uri": uri }, "position": { "line": line, "character": character } })
+}
+
+#[test]
+fn lsp_session() {
+    let (server, conn) = Connection::memory();
+    let server = thread::spawn(move || lsp::serve(&server).unwrap());
+    let mut client = Client { conn, next_id: 0 };
+
+    let _: serde_json::Value = client.request(Initialize::METHOD, json!({ "capabilities": {} }));
+    client.notify(Initialized::METHOD, json!({}));
+
+    let uri = Url::from_file_path(std::env::temp_dir().join("kasm-lsp-test/main.ks")).unwrap();
+    let text = "start:\nMOV $1 %ra\nJMP start\nJMP nowhere\n";
+    client.notify(
+        DidOpenTextDocument::METHOD,
+        json!({ "textDocument": TextDocumentItem::new(uri.clone(), "kasm".to_owned(), 1, text.to_owned()) }),
+    );
+
+    let diags = client.diagnostics();
+    assert_eq!(diags.uri, uri);
+    assert_eq!(diags.diagnostics.len(), 1);
+    assert_eq!(
+        diags.diagnostics[0].range,
+        Range::new(Position::new(3, 0), Position::new(3, 3))
+    );
+    assert!(diags.diagnostics[0].message.contains("nowhere"));
+
+    let def: GotoDefinitionResponse = client.request(GotoDefinition::METHOD, position(&uri, 2, 6));
+    assert_eq!(
+        def,
+        GotoDefinitionResponse::Array(vec![Location::new(
+            uri.clone(),
+            Range::new(Position::new(0, 0), Position::new(0, 5))
+        )])
+    );
+
+    let hover: Hover = client.request(HoverRequest::METHOD, position(&uri, 1, 1));
+    match hover.contents {
+        HoverContents::Markup(markup) => {
+            assert!(markup.value.starts_with("**MOV**"));
+            assert!(markup.value.contains("candidate argument lists"));
+        }
+        contents => panic!("unexpected hover contents: {:?}", contents),
+    }
+
+    let completions: CompletionResponse = client.request(Completion::METHOD, position(&uri, 1, 0));
+    match completions {
+        CompletionResponse::Array(items) => {
+            assert!(items.iter().any(|item| item.label == "JMP"));
+        }
+        resp => panic!("unexpected completions: {:?}", resp),
+    }
+
+    let _: serde_json::Value = client.request(Shutdown::METHOD, json!(null));
+    client.notify(Exit::METHOD, json!(null));
+    server.join().unwrap();
+}
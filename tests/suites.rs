@@ -1,7 +1,9 @@
 use kcpu::{
+    assembler::{self, format, recover, source::VirtualFs, Options},
     assets,
     cli::suite,
 };
+use std::{ffi::OsStr, path::Path};
 
 #[test]
 fn run_suite_test() -> Result<(), kcpu::assembler::Error> {
@@ -9,11 +11,84 @@ fn run_suite_test() -> Result<(), kcpu::assembler::Error> {
         &std::ffi::OsString::from("test"),
         &assets::default_suite_dir(),
         None,
-        Some(50_000_000)
+        Some(50_000_000),
+        &Options::default()
     )?);
     Ok(())
 }
 
+#[test]
+fn run_suite_test_optimized() -> Result<(), kcpu::assembler::Error> {
+    // These units depend on the exact addresses of their instructions (`ljmp`) or on the
+    // number of instructions executed (`flag_tui2nmi`), both of which the optimiser changes.
+    const LAYOUT_DEPENDENT: [&str; 2] = ["flag_tui2nmi", "ljmp"];
+
+    let suite_dir = assets::default_suite_dir();
+    let opts = Options {
+        optimize: true,
+        ..Options::default()
+    };
+    for entry in suite_dir.join("test").read_dir().unwrap() {
+        let path = entry.unwrap().path();
+        let unit = path.file_stem().unwrap().to_owned();
+        if LAYOUT_DEPENDENT.iter().any(|name| unit == *name) {
+            continue;
+        }
+
+        assert!(suite::run_suite(
+            &std::ffi::OsString::from("test"),
+            &suite_dir,
+            Some(&unit),
+            Some(50_000_000),
+            &opts
+        )?);
+    }
+    Ok(())
+}
+
+fn assert_formatted(dir: &Path) {
+    for entry in dir.read_dir().unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            assert_formatted(&path);
+        } else if path.extension() == Some(OsStr::new("ks")) {
+            let source = std::fs::read_to_string(&path).unwrap();
+            assert!(
+                format::format(&source).unwrap() == source,
+                "{} is not formatted, run `kasm fmt` on it",
+                path.display()
+            );
+        }
+    }
+}
+
+#[test]
+fn suite_test_is_formatted() {
+    assert_formatted(&assets::default_suite_dir().join("test"));
+}
+
+#[test]
+fn suite_test_disassembles_exactly() {
+    for entry in assets::default_suite_dir().join("test").read_dir().unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() != Some(OsStr::new("ks")) {
+            continue;
+        }
+
+        let bin =
+            assembler::assemble_path(&path, &assembler::source::FsLoader, &Options::default())
+                .unwrap();
+        let source = recover::disassemble_program(&bin);
+        assert_eq!(
+            assembler::assemble_with(&source, &VirtualFs::new()).as_ref(),
+            Ok(&bin),
+            "the disassembly of {} does not reassemble exactly:\n{}",
+            path.display(),
+            source
+        );
+    }
+}
+
 #[test]
 #[cfg_attr(not(feature = "big_tests"), ignore)]
 fn run_suite_bench() -> Result<(), kcpu::assembler::Error> {
@@ -21,7 +96,8 @@ fn run_suite_bench() -> Result<(), kcpu::assembler::Error> {
         &std::ffi::OsString::from("bench"),
         &assets::default_suite_dir(),
         None,
-        Some(50_000_000)
+        Some(50_000_000),
+        &Options::default()
     )?);
     Ok(())
 }